@@ -33,6 +33,11 @@ fn main() {
     add_def(&mut defines, "XD3_WIN32", "1");
     add_def(&mut defines, "SHELL_TESTS", "0");
 
+    println!(
+        "cargo:rustc-env=XDELTA3_VERSION={}",
+        xdelta3_version().unwrap_or_else(|| "unknown".to_owned())
+    );
+
     #[cfg(feature = "lzma")]
     {
         add_def(&mut defines, "SECONDARY_LZMA", "1");
@@ -75,6 +80,19 @@ fn main() {
     }
 }
 
+/// Read the release version from `AC_INIT([Xdelta3], [<version>], ...)` in configure.ac.
+fn xdelta3_version() -> Option<String> {
+    let configure = std::fs::read_to_string("xdelta3/xdelta3/configure.ac").ok()?;
+    let args = configure.split("AC_INIT(").nth(1)?;
+    let version = args.split(',').nth(1)?;
+    Some(
+        version
+            .trim()
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_owned(),
+    )
+}
+
 fn check_native_size(name: &str) -> String {
     let builder = cc::Build::new();
     let out_dir = env::var("OUT_DIR").unwrap();
@@ -0,0 +1,71 @@
+//! Compare fixed window sizes with `adaptive_winsize` on mixed-entropy data.
+//!
+//! Run with: cargo run --release --example adaptive_winsize
+
+use xdelta3::stream::{process, ProcessMode, Xd3Config};
+
+fn pseudo_random(len: usize, mut seed: u32) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect()
+}
+
+// a different generator from `pseudo_random`, whose streams for different seeds are
+// shifted copies of each other and so would be found in the source
+fn xorshift(len: usize, mut state: u32) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+fn main() {
+    // alternate 4 MiB stretches copied from the source with 4 MiB of fresh random data
+    let source = pseudo_random(32 << 20, 1);
+    let mut input = Vec::new();
+    for i in 0..8u32 {
+        let at = (i as usize) * (4 << 20);
+        if i % 2 == 0 {
+            input.extend_from_slice(&source[at..at + (4 << 20)]);
+        } else {
+            input.extend_from_slice(&xorshift(4 << 20, i + 2));
+        }
+    }
+
+    let configs = vec![
+        ("fixed 1 MiB", Xd3Config::new().window_size(1 << 20)),
+        ("fixed 8 MiB", Xd3Config::new().window_size(1 << 23)),
+        (
+            "adaptive 256 KiB - 8 MiB",
+            Xd3Config::new().adaptive_winsize(1 << 18, 1 << 23),
+        ),
+    ];
+
+    println!("{:>26} {:>12} {:>10}", "config", "delta bytes", "ms");
+    for (name, cfg) in configs {
+        let cfg = cfg.source_window_size(source.len() as u64);
+        let mut patch = Vec::new();
+        let start = std::time::Instant::now();
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+        println!(
+            "{:>26} {:>12} {:>10}",
+            name,
+            patch.len(),
+            start.elapsed().as_millis()
+        );
+    }
+}
@@ -0,0 +1,26 @@
+//! Encode a delta asynchronously on the Tokio runtime.
+//!
+//! The stream API works with `futures` I/O traits, so Tokio files are wrapped with
+//! `tokio_util::compat`.
+//!
+//! Run with: cargo run --example async_diff -- <old file> <new file> <delta file>
+
+use tokio::fs::File;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 {
+        eprintln!("usage: {} <old file> <new file> <delta file>", args[0]);
+        std::process::exit(1);
+    }
+
+    let source = File::open(&args[1]).await.expect("File::open");
+    let input = File::open(&args[2]).await.expect("File::open");
+    let out = File::create(&args[3]).await.expect("File::create");
+
+    xdelta3::stream::encode_async(input.compat(), source.compat(), out.compat_write())
+        .await
+        .expect("failed to encode");
+}
@@ -0,0 +1,41 @@
+//! Print the delta size for a range of `iopt_size` values.
+//!
+//! Run with: cargo run --release --example iopt_size -- <old file> <new file>
+
+use xdelta3::stream::{process, ProcessMode, Xd3Config};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <old file> <new file>", args[0]);
+        std::process::exit(1);
+    }
+    let source = std::fs::read(&args[1]).expect("failed to read old file");
+    let input = std::fs::read(&args[2]).expect("failed to read new file");
+
+    println!("{:>10} {:>12} {:>10}", "iopt_size", "delta bytes", "ms");
+    for &iopt_size in &[16, 64, 256, 1024, 4096, 1 << 15, 0] {
+        let cfg = Xd3Config::new().iopt_size(iopt_size);
+        let mut patch = Vec::new();
+        let start = std::time::Instant::now();
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+        let label = if iopt_size == 0 {
+            "unlimited".to_owned()
+        } else {
+            iopt_size.to_string()
+        };
+        println!(
+            "{:>10} {:>12} {:>10}",
+            label,
+            patch.len(),
+            start.elapsed().as_millis()
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! Delta size, total encode time and per-window latency for each `MatchEffort` preset.
+//!
+//! Run with: cargo run --release --example match_effort -- <old file> <new file>
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use xdelta3::stream::{MatchEffort, ProcessState, Xd3Config};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <old file> <new file>", args[0]);
+        std::process::exit(1);
+    }
+    let source = std::fs::read(&args[1]).expect("failed to read old file");
+    let input = std::fs::read(&args[2]).expect("failed to read new file");
+
+    println!(
+        "{:>10} {:>12} {:>10} {:>8} {:>10} {:>10} {:>10}",
+        "effort", "delta bytes", "total ms", "windows", "mean ms", "p99 ms", "max ms"
+    );
+    for &effort in &[
+        MatchEffort::Exhaustive,
+        MatchEffort::Balanced,
+        MatchEffort::Fast,
+    ] {
+        let cfg = Xd3Config::new().window_size(1 << 20).match_effort(effort);
+        let mut state = ProcessState::new(cfg, &source[..]).expect("failed to set up");
+
+        let windows = Arc::new(Mutex::new(Vec::new()));
+        let last = Arc::new(Mutex::new(Instant::now()));
+        {
+            let windows = windows.clone();
+            state.set_progress_callback(move |_| {
+                let mut last = last.lock().unwrap();
+                windows.lock().unwrap().push(last.elapsed());
+                *last = Instant::now();
+            });
+        }
+
+        let mut patch = Vec::new();
+        let start = Instant::now();
+        futures::executor::block_on(async {
+            state.encode_segment(&input[..], &mut patch).await?;
+            state.finish_encode(&mut patch).await
+        })
+        .expect("failed to encode");
+        let elapsed = start.elapsed();
+
+        let mut windows = windows.lock().unwrap().clone();
+        windows.sort();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let total: Duration = windows.iter().sum();
+        let mean = ms(total) / windows.len().max(1) as f64;
+        let p99 = windows
+            .get(windows.len() * 99 / 100)
+            .or_else(|| windows.last())
+            .map_or(0.0, |d| ms(*d));
+        let max = windows.last().map_or(0.0, |d| ms(*d));
+        println!(
+            "{:>10} {:>12} {:>10.0} {:>8} {:>10.2} {:>10.2} {:>10.2}",
+            format!("{:?}", effort),
+            patch.len(),
+            ms(elapsed),
+            windows.len(),
+            mean,
+            p99,
+            max
+        );
+    }
+}
@@ -0,0 +1,13 @@
+//! Apply a delta to an old file, writing the new file.
+//!
+//! Run with: cargo run --example patch_files -- <old file> <delta file> <new file>
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 {
+        eprintln!("usage: {} <old file> <delta file> <new file>", args[0]);
+        std::process::exit(1);
+    }
+
+    xdelta3::stream::patch_files(&args[1], &args[2], &args[3]).expect("failed to patch");
+}
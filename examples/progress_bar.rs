@@ -0,0 +1,31 @@
+//! Encode a delta between two files with a progress bar.
+//!
+//! Run with: cargo run --example progress_bar -- <old file> <new file> <delta file>
+
+use indicatif::{ProgressBar, ProgressStyle};
+use xdelta3::stream::{encode_files_with_progress, Xd3Config};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 {
+        eprintln!("usage: {} <old file> <new file> <delta file>", args[0]);
+        std::process::exit(1);
+    }
+
+    let new_len = std::fs::metadata(&args[2]).expect("metadata").len();
+    let bar = ProgressBar::new(new_len);
+    bar.set_style(
+        ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} {msg}")
+            .expect("invalid template"),
+    );
+
+    let stats =
+        encode_files_with_progress(Xd3Config::new(), &args[2], &args[1], &args[3], |progress| {
+            bar.set_position(progress.new_bytes_read);
+            bar.set_message(format!("delta {} bytes", progress.patch_bytes_written));
+        })
+        .expect("failed to encode");
+    bar.finish();
+
+    println!("{} byte delta written", stats.output_bytes);
+}
@@ -0,0 +1,17 @@
+//! Write a delta turning one file into another.
+//!
+//! Run with: cargo run --example simple_diff -- <old file> <new file> <delta file>
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 {
+        eprintln!("usage: {} <old file> <new file> <delta file>", args[0]);
+        std::process::exit(1);
+    }
+
+    let stats = xdelta3::stream::diff_files(&args[1], &args[2], &args[3]).expect("failed to diff");
+    println!(
+        "{} bytes of new file, {} bytes of old file read, {} byte delta written",
+        stats.input_bytes, stats.source_bytes, stats.output_bytes
+    );
+}
@@ -0,0 +1,69 @@
+//! Serve a delta over HTTP with Axum, encoding it while the response is being sent.
+//!
+//! `GET /patch` streams the delta from `<old file>` to `<new file>`. The encoder writes
+//! into one end of an in-memory pipe and the response body reads from the other, so the
+//! delta is never held in memory as a whole. If encoding fails halfway, the body ends
+//! with an error and the client sees a broken response rather than a short patch.
+//!
+//! Run with: cargo run --example streaming_server -- <old file> <new file>
+//! and fetch the patch with: curl -o patch.vcdiff http://127.0.0.1:3000/patch
+
+use std::io;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use futures::stream::StreamExt;
+use tokio::fs::File;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tokio_util::io::ReaderStream;
+
+struct Files {
+    old: String,
+    new: String,
+}
+
+async fn patch(State(files): State<Arc<Files>>) -> Result<Body, (StatusCode, String)> {
+    let not_found = |e: io::Error| (StatusCode::NOT_FOUND, e.to_string());
+    let source = File::open(&files.old).await.map_err(not_found)?;
+    let input = File::open(&files.new).await.map_err(not_found)?;
+
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    let encoder = tokio::spawn(async move {
+        xdelta3::stream::encode_async(input.compat(), source.compat(), writer.compat_write()).await
+    });
+
+    // after the last chunk, turn a failed encode into an error item
+    let outcome = futures::stream::once(async move {
+        match encoder.await {
+            Ok(res) => res,
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    })
+    .filter_map(|res| async move { res.err().map(Err) });
+    Ok(Body::from_stream(ReaderStream::new(reader).chain(outcome)))
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <old file> <new file>", args[0]);
+        std::process::exit(1);
+    }
+
+    let files = Arc::new(Files {
+        old: args[1].clone(),
+        new: args[2].clone(),
+    });
+    let app = Router::new().route("/patch", get(patch)).with_state(files);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+        .await
+        .expect("failed to bind");
+    println!("serving on http://{}/patch", listener.local_addr().unwrap());
+    axum::serve(listener, app).await.expect("server failed");
+}
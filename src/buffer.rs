@@ -0,0 +1,89 @@
+//! Write-side buffering in front of the encoder.
+
+use std::io;
+
+use futures_util::future::FutureExt;
+use futures_util::io::AllowStdIo;
+
+use crate::stream::{EncodeStats, ProcessState, Xd3Config};
+
+/// Collects many small writes into whole windows before handing them to the encoder.
+///
+/// Encoding every small write on its own (e.g. one `process` call per log line) starts a
+/// new delta each time, and even with a long-lived `ProcessState` each write costs a trip
+/// through xdelta3's state machine. `WindowBuffer` holds writes until a full window of
+/// `window_size` bytes is ready and encodes it in one go. Call `flush` to encode the
+/// remainder and finish the delta; dropping the buffer without it loses buffered data.
+pub struct WindowBuffer<R, W> {
+    state: ProcessState<AllowStdIo<R>>,
+    out: W,
+    buf: Vec<u8>,
+    winsize: usize,
+    finished: bool,
+}
+
+impl<R, W> WindowBuffer<R, W>
+where
+    R: io::Read,
+    W: io::Write,
+{
+    /// Encode against `src`, writing the delta to `out`.
+    pub fn new(cfg: Xd3Config, src: R, out: W) -> io::Result<Self> {
+        let state = ProcessState::new(cfg, AllowStdIo::new(src))?;
+        let winsize = state.output_buffer_capacity();
+        Ok(Self {
+            state,
+            out,
+            buf: Vec::with_capacity(winsize),
+            winsize,
+            finished: false,
+        })
+    }
+
+    /// Append `data` to the input, encoding every window that fills up.
+    pub fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "WindowBuffer: delta already finished",
+            ));
+        }
+        while !data.is_empty() {
+            let len = std::cmp::min(data.len(), self.winsize - self.buf.len());
+            self.buf.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.buf.len() == self.winsize {
+                self.encode_buffered()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode whatever is buffered, finish the delta and flush `out`.
+    ///
+    /// The delta is complete afterwards; further writes fail.
+    pub fn flush(&mut self) -> io::Result<EncodeStats> {
+        self.encode_buffered()?;
+        self.finished = true;
+        self.state.flush_and_finish(&mut self.out)
+    }
+
+    /// Bytes written but not yet handed to the encoder.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn encode_buffered(&mut self) -> io::Result<()> {
+        let result = self
+            .state
+            .encode_segment(&self.buf[..], AllowStdIo::new(&mut self.out))
+            .now_or_never()
+            .expect("synchronous I/O future returned Pending");
+        self.buf.clear();
+        result
+    }
+}
@@ -0,0 +1,43 @@
+//! Pluggable checksums for the verification helpers, such as
+//! `stream::decode_with_manifest`.
+//!
+//! With the `crc32fast` feature, `Crc32` provides CRC-32 through the `crc32fast` crate.
+//! Implement `Hasher` to use a different algorithm.
+
+/// Incremental checksum over a byte stream.
+pub trait Hasher {
+    /// Feed more data into the checksum.
+    fn update(&mut self, data: &[u8]);
+    /// The checksum of everything fed so far. Does not reset the state.
+    fn finish(&self) -> u64;
+}
+
+/// CRC-32 (IEEE), hardware accelerated where the CPU supports it.
+#[cfg(feature = "crc32fast")]
+#[derive(Clone, Default)]
+pub struct Crc32(crc32fast::Hasher);
+
+#[cfg(feature = "crc32fast")]
+impl Crc32 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "crc32fast")]
+impl Hasher for Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(&self) -> u64 {
+        u64::from(self.0.clone().finalize())
+    }
+}
+
+/// Checksum `data` in one go with `H`.
+pub fn checksum<H: Hasher + Default>(data: &[u8]) -> u64 {
+    let mut hasher = H::default();
+    hasher.update(data);
+    hasher.finish()
+}
@@ -0,0 +1,272 @@
+//! Error types returned by this crate.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+
+use super::binding;
+
+/// Error returned by the xdelta3 operations.
+#[derive(Debug)]
+pub enum Xd3Error {
+    /// The data handed to `decode` does not start with the VCDIFF magic bytes. This is
+    /// usually a sign that the arguments of `encode` and `decode` were swapped.
+    NotAVcdiff,
+    /// The delta is structurally invalid.
+    MalformedDelta(String),
+    /// xdelta3 failed with the given return code.
+    Code(i32),
+    /// The xdelta3 state machine failed while encoding or decoding.
+    Stream {
+        /// `"encode"` or `"decode"`.
+        operation: &'static str,
+        /// The `xd3_rvalues` code returned by xdelta3.
+        code: i32,
+        /// The stream's own error message, if xdelta3 set one.
+        msg: Option<String>,
+    },
+    /// xdelta3 asked for a source block that was already evicted from the block cache, and
+    /// the source reader cannot go back to it, or (with `strict_source_window`) already had
+    /// to go back for too many.
+    SourceWindowTooSmall {
+        /// The requested block.
+        blkno: u64,
+        /// The source window size in effect.
+        source_window_size: u64,
+    },
+    /// The delta is valid but uses a feature the pure-Rust VCDIFF helpers don't interpret,
+    /// such as a custom code table or secondary compression.
+    Unsupported(String),
+    /// The deadline set with `Xd3Config::deadline` passed before processing finished.
+    DeadlineExceeded,
+    /// `process_verify` decoded the new delta to something other than its input.
+    VerificationFailed {
+        /// Offset of the first byte that differs.
+        at_byte: u64,
+    },
+    /// A region of the decoded target is missing or does not match its manifest checksum.
+    ManifestMismatch {
+        /// Start of the region in the target.
+        offset: u64,
+        /// Length of the region.
+        len: u64,
+    },
+    /// A `pad_windows_to` stream was written in a framing version this build can't read,
+    /// most likely by a newer release of this crate.
+    UnsupportedFormatVersion {
+        /// Version byte from the stream header.
+        found: u8,
+        /// Newest version this build reads.
+        max_supported: u8,
+    },
+}
+
+/// Symbolic name or description of an xdelta3 return code.
+fn describe_code(code: i32) -> String {
+    let msg = unsafe { binding::xd3_strerror(code) };
+    if msg.is_null() {
+        // xdelta3 passes errno values through unchanged
+        io::Error::from_raw_os_error(code).to_string()
+    } else {
+        let msg = unsafe { CStr::from_ptr(msg) };
+        msg.to_string_lossy().into_owned()
+    }
+}
+
+impl fmt::Display for Xd3Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Xd3Error::NotAVcdiff => write!(f, "input is not a VCDIFF delta"),
+            Xd3Error::MalformedDelta(detail) => write!(f, "malformed VCDIFF delta: {}", detail),
+            Xd3Error::Code(code) => write!(f, "{}", describe_code(*code)),
+            Xd3Error::Stream {
+                operation,
+                code,
+                msg,
+            } => {
+                write!(f, "xdelta3 {} failed: {}", operation, describe_code(*code))?;
+                if let Some(msg) = msg {
+                    write!(f, ": {}", msg)?;
+                }
+                Ok(())
+            }
+            Xd3Error::SourceWindowTooSmall {
+                blkno,
+                source_window_size,
+            } => write!(
+                f,
+                "source block {} is no longer cached, use a source window larger than {} bytes",
+                blkno, source_window_size
+            ),
+            Xd3Error::Unsupported(feature) => write!(f, "unsupported VCDIFF feature: {}", feature),
+            Xd3Error::DeadlineExceeded => write!(f, "deadline exceeded"),
+            Xd3Error::VerificationFailed { at_byte } => write!(
+                f,
+                "decoded delta differs from the input at byte {}",
+                at_byte
+            ),
+            Xd3Error::ManifestMismatch { offset, len } => write!(
+                f,
+                "decoded target does not match the manifest region of {} bytes at {}",
+                len, offset
+            ),
+            Xd3Error::UnsupportedFormatVersion {
+                found,
+                max_supported,
+            } => write!(
+                f,
+                "padded frame stream has format version {}, this build reads up to version {}",
+                found, max_supported
+            ),
+        }
+    }
+}
+
+impl Xd3Error {
+    /// Whether the operation might succeed if repeated. Only true for `DeadlineExceeded`:
+    /// every other variant describes the delta, the source or the configuration, which a
+    /// retry doesn't change.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Xd3Error::DeadlineExceeded => true,
+            Xd3Error::NotAVcdiff
+            | Xd3Error::MalformedDelta(_)
+            | Xd3Error::Code(_)
+            | Xd3Error::Stream { .. }
+            | Xd3Error::SourceWindowTooSmall { .. }
+            | Xd3Error::Unsupported(_)
+            | Xd3Error::VerificationFailed { .. }
+            | Xd3Error::ManifestMismatch { .. }
+            | Xd3Error::UnsupportedFormatVersion { .. } => false,
+        }
+    }
+}
+
+impl std::error::Error for Xd3Error {}
+
+/// A configuration that xdelta3 would accept but that is likely a mistake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Xd3ConfigError {
+    /// The small-match history is shorter than the input window, so the small string
+    /// matcher cannot see copies that span the whole window.
+    SprevSzTooSmall {
+        sprevsz: u32,
+        winsize: u32,
+        recommended_min: u32,
+    },
+    /// A compression level outside 0 to 9.
+    LevelOutOfRange { level: i32 },
+    /// A TOML config that doesn't parse or has a bad value.
+    InvalidToml(String),
+}
+
+impl fmt::Display for Xd3ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Xd3ConfigError::SprevSzTooSmall {
+                sprevsz,
+                winsize,
+                recommended_min,
+            } => write!(
+                f,
+                "sprevsz {} is smaller than winsize {}, use at least {}",
+                sprevsz, winsize, recommended_min
+            ),
+            Xd3ConfigError::LevelOutOfRange { level } => {
+                write!(f, "compression level {} is out of range 0..=9", level)
+            }
+            Xd3ConfigError::InvalidToml(msg) => write!(f, "invalid TOML config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Xd3ConfigError {}
+
+/// An `io::Error` with a note on what was being done when it happened, made by
+/// `Xd3IoErrorExt::with_xd3_context`. Its `source()` is the original error.
+#[derive(Debug)]
+pub struct Xd3Context {
+    context: String,
+    source: io::Error,
+}
+
+impl Xd3Context {
+    /// What was being done, e.g. `"fetching source block"`.
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+
+    /// The error this context was added to.
+    pub fn inner(&self) -> &io::Error {
+        &self.source
+    }
+}
+
+impl fmt::Display for Xd3Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.source)
+    }
+}
+
+impl std::error::Error for Xd3Context {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Adds context to the `io::Error`s returned by the streaming API.
+pub trait Xd3IoErrorExt {
+    /// Prefix the error message with `context`, keeping the error kind. The original error
+    /// is kept as the `source()` of an `Xd3Context`, so `xd3_error` still finds an
+    /// `Xd3Error` inside it.
+    fn with_xd3_context(self, context: &str) -> io::Error;
+
+    /// The `Xd3Error` this error carries, looking through any context added with
+    /// `with_xd3_context`.
+    fn xd3_error(&self) -> Option<&Xd3Error>;
+
+    /// Whether the failure is transient, so that repeating the operation, e.g. after
+    /// reconnecting a network reader or writer, may succeed.
+    ///
+    /// An error carrying an `Xd3Error` is decided by `Xd3Error::is_retryable`, whatever its
+    /// kind: a delta that ends early fails with `UnexpectedEof` and `MalformedDelta`, and
+    /// reading the same bytes again won't help. Otherwise it is true for the kinds
+    /// `Interrupted`, `TimedOut`, `WouldBlock`, `ConnectionReset`, `ConnectionAborted` and
+    /// `UnexpectedEof`, the last one being a stream dropped by the transport.
+    fn is_retryable(&self) -> bool;
+}
+
+impl Xd3IoErrorExt for io::Error {
+    fn with_xd3_context(self, context: &str) -> io::Error {
+        io::Error::new(
+            self.kind(),
+            Xd3Context {
+                context: context.to_owned(),
+                source: self,
+            },
+        )
+    }
+
+    fn xd3_error(&self) -> Option<&Xd3Error> {
+        let inner = self.get_ref()?;
+        match inner.downcast_ref::<Xd3Context>() {
+            Some(context) => context.source.xd3_error(),
+            None => inner.downcast_ref::<Xd3Error>(),
+        }
+    }
+
+    fn is_retryable(&self) -> bool {
+        if let Some(err) = self.xd3_error() {
+            return err.is_retryable();
+        }
+        matches!(
+            self.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        )
+    }
+}
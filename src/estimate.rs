@@ -0,0 +1,54 @@
+//! Cheap estimates for judging how good a delta is.
+
+use std::collections::HashSet;
+
+/// Matches shorter than this are not looked for.
+const BLOCK_SIZE: usize = 16;
+
+/// Estimate the smallest delta that could turn `src` into `input`, in bytes.
+///
+/// This is a heuristic, not a proven bound. Bytes of `input` that can't be found in `src`
+/// or earlier in `input` (in runs of at least 16 bytes, checked against 16-byte aligned
+/// blocks) are counted as novel, and the estimate is what those bytes would take at
+/// their order-0 entropy. Instruction overhead is ignored. A real delta several times
+/// larger than this points at the configuration (e.g. a source window too small to see
+/// the matches); one close to it means the data just doesn't compress further.
+pub fn estimate_min_delta_size(input: &[u8], src: &[u8]) -> u64 {
+    let mut blocks: HashSet<u128> = src.chunks_exact(BLOCK_SIZE).map(block_key).collect();
+
+    let mut counts = [0u64; 256];
+    let mut novel = 0u64;
+    let mut pos = 0;
+    while pos < input.len() {
+        let block = input.get(pos..pos + BLOCK_SIZE).map(block_key);
+        if let Some(block) = block {
+            if blocks.contains(&block) {
+                pos += BLOCK_SIZE;
+                continue;
+            }
+            // later parts of the target may copy from here
+            if pos % BLOCK_SIZE == 0 {
+                blocks.insert(block);
+            }
+        }
+        counts[input[pos] as usize] += 1;
+        novel += 1;
+        pos += 1;
+    }
+
+    let bits: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / novel as f64;
+            -(count as f64) * p.log2()
+        })
+        .sum();
+    (bits / 8.0).ceil() as u64
+}
+
+fn block_key(block: &[u8]) -> u128 {
+    let mut key = [0u8; BLOCK_SIZE];
+    key.copy_from_slice(block);
+    u128::from_le_bytes(key)
+}
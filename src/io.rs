@@ -0,0 +1,492 @@
+//! `std::io` adapters for feeding sources and collecting output.
+
+use std::io::{self, Read, Seek, SeekFrom};
+#[cfg(feature = "stream")]
+use std::pin::Pin;
+#[cfg(feature = "stream")]
+use std::task::{Context, Poll};
+
+#[cfg(feature = "stream")]
+use futures_io::AsyncRead;
+#[cfg(all(feature = "stream", feature = "digest"))]
+use futures_io::AsyncWrite;
+
+/// Reader over the `len` bytes of `inner` starting at `start`.
+///
+/// Useful as a source when only one section of a large file, such as a member of an
+/// archive, is the reference data for a delta.
+pub struct OffsetReader<R> {
+    inner: R,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> OffsetReader<R> {
+    /// Seek `inner` to `start` and limit reads to the following `len` bytes.
+    pub fn new(mut inner: R, start: u64, len: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            inner,
+            start,
+            len,
+            pos: 0,
+        })
+    }
+
+    /// Offset of the range within the inner reader.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Length of the range.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for OffsetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len - self.pos;
+        let max = std::cmp::min(buf.len() as u64, remaining) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.inner.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reader that stops after `limit` bytes of `inner`, like `Read::take` but also usable
+/// where a named type is needed.
+///
+/// Handy for encoding only the first part of a large input, or for feeding a source whose
+/// size is known up front.
+pub struct LimitReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R> LimitReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+        }
+    }
+
+    /// Bytes left before EOF is reported.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for LimitReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.inner.read(&mut buf[..max])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// `AsyncRead` counterpart of `LimitReader`.
+#[cfg(feature = "stream")]
+pub struct AsyncLimitReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+#[cfg(feature = "stream")]
+impl<R> AsyncLimitReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+        }
+    }
+
+    /// Bytes left before EOF is reported.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+#[cfg(feature = "stream")]
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncLimitReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let max = std::cmp::min(buf.len() as u64, this.remaining) as usize;
+        if max == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        let poll = Pin::new(&mut this.inner).poll_read(cx, &mut buf[..max]);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.remaining -= n as u64;
+        }
+        poll
+    }
+}
+
+/// Magic bytes opening a stream written with `Xd3Config::pad_windows_to`, so it can't be
+/// mistaken for a plain VCDIFF delta.
+pub const PADDED_MAGIC: [u8; 4] = *b"XD3P";
+
+/// Framing version written after `PADDED_MAGIC`. `AsyncUnpadReader` rejects other versions
+/// with `Xd3Error::UnsupportedFormatVersion`.
+pub const PADDED_FORMAT_VERSION: u8 = 1;
+
+/// Length of the stream header: `PADDED_MAGIC` and the version byte.
+#[cfg(feature = "stream")]
+pub(crate) const PADDED_HEADER_LEN: u64 = 5;
+
+/// Size of the frame holding `len` bytes of payload, as written with
+/// `Xd3Config::pad_windows_to(frame_size)`: `prefix_len` bytes of stream header (only in
+/// the first frame), a 4 byte length prefix and the payload, rounded up to a multiple of
+/// `frame_size`.
+#[cfg(feature = "stream")]
+pub(crate) fn padded_frame_len(prefix_len: u64, len: u64, frame_size: u32) -> u64 {
+    let frame_size = u64::from(frame_size);
+    (prefix_len + 4 + len + frame_size - 1) / frame_size * frame_size
+}
+
+/// Reader stripping the framing added by `Xd3Config::pad_windows_to`, yielding the plain
+/// VCDIFF delta.
+///
+/// The stream has to start with `PADDED_MAGIC` and a version byte: anything else fails with
+/// `InvalidData`, and versions other than `PADDED_FORMAT_VERSION` with
+/// `Xd3Error::UnsupportedFormatVersion`. `frame_size` has to be the one the delta was
+/// encoded with. Decoding with a config that has the same `pad_windows_to` uses this reader
+/// already.
+#[cfg(feature = "stream")]
+pub struct AsyncUnpadReader<R> {
+    inner: R,
+    frame_size: u32,
+    // stream header, as far as read
+    magic: [u8; 5],
+    magic_len: usize,
+    // stream header bytes in the current frame, which only the first one has
+    prefix_len: u64,
+    // length prefix of the next frame, as far as read
+    header: [u8; 4],
+    header_len: usize,
+    payload_left: u64,
+    padding_left: u64,
+}
+
+#[cfg(feature = "stream")]
+impl<R> AsyncUnpadReader<R> {
+    /// # Panics
+    ///
+    /// Panics if `frame_size` is 0.
+    pub fn new(inner: R, frame_size: u32) -> Self {
+        assert!(frame_size > 0, "frame size must not be 0");
+        Self {
+            inner,
+            frame_size,
+            magic: [0; 5],
+            magic_len: 0,
+            prefix_len: PADDED_HEADER_LEN,
+            header: [0; 4],
+            header_len: 0,
+            payload_left: 0,
+            padding_left: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn check_magic(&self) -> io::Result<()> {
+        if self.magic[..4] != PADDED_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a padded frame stream",
+            ));
+        }
+        let version = self.magic[4];
+        if version != PADDED_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                crate::Xd3Error::UnsupportedFormatVersion {
+                    found: version,
+                    max_supported: PADDED_FORMAT_VERSION,
+                },
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "stream")]
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncUnpadReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let cut_short = || io::Error::new(io::ErrorKind::UnexpectedEof, "padded frame cut short");
+
+        while this.magic_len < this.magic.len() {
+            let magic_len = this.magic_len;
+            match Pin::new(&mut this.inner).poll_read(cx, &mut this.magic[magic_len..]) {
+                Poll::Ready(Ok(0)) if magic_len == 0 => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(cut_short())),
+                Poll::Ready(Ok(n)) => this.magic_len += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            if this.magic_len == this.magic.len() {
+                if let Err(e) = this.check_magic() {
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+
+        loop {
+            if this.payload_left > 0 {
+                let max = std::cmp::min(buf.len() as u64, this.payload_left) as usize;
+                return match Pin::new(&mut this.inner).poll_read(cx, &mut buf[..max]) {
+                    Poll::Ready(Ok(0)) => Poll::Ready(Err(cut_short())),
+                    Poll::Ready(Ok(n)) => {
+                        this.payload_left -= n as u64;
+                        Poll::Ready(Ok(n))
+                    }
+                    poll => poll,
+                };
+            }
+
+            if this.padding_left > 0 {
+                let mut scratch = [0u8; 512];
+                let max = std::cmp::min(scratch.len() as u64, this.padding_left) as usize;
+                match Pin::new(&mut this.inner).poll_read(cx, &mut scratch[..max]) {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(cut_short())),
+                    Poll::Ready(Ok(n)) => this.padding_left -= n as u64,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+                continue;
+            }
+
+            let header_len = this.header_len;
+            match Pin::new(&mut this.inner).poll_read(cx, &mut this.header[header_len..]) {
+                Poll::Ready(Ok(0)) if header_len == 0 => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(cut_short())),
+                Poll::Ready(Ok(n)) => this.header_len += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            if this.header_len == this.header.len() {
+                let len = u64::from(u32::from_be_bytes(this.header));
+                let frame_len = padded_frame_len(this.prefix_len, len, this.frame_size);
+                this.padding_left = frame_len - this.prefix_len - 4 - len;
+                this.header_len = 0;
+                this.payload_left = len;
+                this.prefix_len = 0;
+            }
+        }
+    }
+}
+
+/// Reader over a sparse source made of `(offset, data)` fragments.
+///
+/// Bytes not covered by any fragment read as zeros, so a sparse disk image can be used as
+/// the source of a delta without materializing its holes.
+pub struct SparseReader {
+    // sorted by offset, non-overlapping
+    fragments: Vec<(u64, Vec<u8>)>,
+    len: u64,
+    pos: u64,
+}
+
+impl SparseReader {
+    /// Build a source of `len` bytes from `fragments`, which may be given in any order.
+    ///
+    /// Fails with `InvalidInput` if fragments overlap or extend past `len`.
+    pub fn new(mut fragments: Vec<(u64, Vec<u8>)>, len: u64) -> io::Result<Self> {
+        fragments.sort_by_key(|(offset, _)| *offset);
+        let mut end = 0;
+        for (offset, data) in &fragments {
+            if *offset < end {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("fragment at offset {} overlaps the previous one", offset),
+                ));
+            }
+            end = offset + data.len() as u64;
+        }
+        if end > len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fragments end at {}, past the source length {}", end, len),
+            ));
+        }
+        Ok(Self {
+            fragments,
+            len,
+            pos: 0,
+        })
+    }
+
+    /// Logical length of the source, holes included.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len - self.pos;
+        let max = std::cmp::min(buf.len() as u64, remaining) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+
+        // first fragment ending past pos
+        let idx = self
+            .fragments
+            .iter()
+            .position(|(offset, data)| offset + data.len() as u64 > self.pos);
+        let n = match idx.map(|i| &self.fragments[i]) {
+            Some((offset, data)) if *offset <= self.pos => {
+                let start = (self.pos - offset) as usize;
+                let n = std::cmp::min(max, data.len() - start);
+                buf[..n].copy_from_slice(&data[start..start + n]);
+                n
+            }
+            next => {
+                // in a hole, up to the next fragment or the end
+                let hole = next.map_or(remaining, |(offset, _)| offset - self.pos);
+                let n = std::cmp::min(max as u64, hole) as usize;
+                for b in &mut buf[..n] {
+                    *b = 0;
+                }
+                n
+            }
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writer that feeds everything written to `inner` into the digest `H`, e.g.
+/// `sha2::Sha256`.
+///
+/// Wrapping the output of an encode gives the hash of the delta without reading it back.
+/// Only the bytes `inner` accepted are hashed, so short writes are accounted for.
+#[cfg(feature = "digest")]
+pub struct HashingWriter<W, H> {
+    inner: W,
+    hasher: H,
+}
+
+#[cfg(feature = "digest")]
+impl<W: io::Write, H: digest::Digest> HashingWriter<W, H> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: H::new(),
+        }
+    }
+
+    /// Return the inner writer and the digest of everything written to it.
+    pub fn finalize(self) -> (W, digest::Output<H>) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+#[cfg(feature = "digest")]
+impl<W: io::Write, H: digest::Digest> io::Write for HashingWriter<W, H> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Async counterpart of `HashingWriter`.
+#[cfg(all(feature = "stream", feature = "digest"))]
+pub struct AsyncHashingWriter<W, H> {
+    inner: W,
+    hasher: H,
+}
+
+#[cfg(all(feature = "stream", feature = "digest"))]
+impl<W, H: digest::Digest> AsyncHashingWriter<W, H> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: H::new(),
+        }
+    }
+
+    /// Return the inner writer and the digest of everything written to it.
+    pub fn finalize(self) -> (W, digest::Output<H>) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+#[cfg(all(feature = "stream", feature = "digest"))]
+impl<W, H> AsyncWrite for AsyncHashingWriter<W, H>
+where
+    W: AsyncWrite + Unpin,
+    H: digest::Digest + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.hasher.update(&buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
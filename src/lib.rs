@@ -10,13 +10,30 @@
 //!
 //! You can find out how to use this crate in this documentation and you can also consult the tests in the `test/` folder
 //! to see it in action (how to generate and patch two files!)
+//!
+//! `use xdelta3::prelude::*` re-exports the most commonly used items.
 
 extern crate libc;
 
 use libc::c_uint;
+use log::warn;
 
+#[cfg(feature = "stream")]
+pub mod buffer;
+pub mod checksum;
+pub mod error;
+pub mod estimate;
+pub mod io;
+pub mod prelude;
+#[cfg(any(feature = "http-source", feature = "s3"))]
+pub mod sources;
 #[cfg(feature = "stream")]
 pub mod stream;
+pub mod vcdiff;
+
+pub use error::{Xd3ConfigError, Xd3Error};
+pub use estimate::estimate_min_delta_size;
+pub use vcdiff::{disassemble, validate_delta, DeltaInfo, Instruction, VcdiffHeader, VCDIFF_MAGIC};
 
 #[allow(dead_code)]
 mod binding {
@@ -54,30 +71,17 @@ mod binding {
 /// You might notice the generated patch data is larger than both orginal data and the updated data.
 /// But don't worry, if your data is large enough and kind of similar between each other (usually the case
 /// for software updates or ROM patches), the patch data should be only a fraction of your updated file.
-pub fn encode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
-    unsafe {
-        let input_len = input.len() as c_uint;
-        let src_len = src.len() as c_uint;
-        let estimated_out_len = (input_len + src_len) * 2;
-        let mut avail_output = 0 as c_uint;
-        let mut output = Vec::with_capacity(estimated_out_len as usize);
-        let result = binding::xd3_encode_memory(
-            input.as_ptr(),
-            input_len,
-            src.as_ptr(),
-            src_len,
-            output.as_mut_ptr(),
-            &mut avail_output,
-            estimated_out_len,
-            0,
-        );
-        if result == 0 {
-            output.set_len(avail_output as usize);
-            Some(output)
-        } else {
-            None
-        }
+///
+/// A warning is logged if `input` already looks like a VCDIFF delta, since that usually means
+/// `decode` was intended.
+pub fn encode(input: &[u8], src: &[u8]) -> Result<Vec<u8>, Xd3Error> {
+    if input.starts_with(&VCDIFF_MAGIC) {
+        warn!("encode: input starts with the VCDIFF magic bytes, did you mean to decode?");
     }
+
+    let estimated_out_len = 2 * (input.len() as u64 + src.len() as u64);
+    process_memory(binding::xd3_encode_memory, input, src, estimated_out_len)
+        .map_err(Xd3Error::Code)
 }
 
 /// Function to decode the difference data
@@ -103,28 +107,99 @@ pub fn encode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
 ///     assert_eq!(result.unwrap().as_slice(), &[1, 2, 3, 4, 5, 6, 7]);
 /// }
 /// ```
-pub fn decode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
-    unsafe {
-        let input_len = input.len() as c_uint;
-        let src_len = src.len() as c_uint;
-        let estimated_out_len = (input_len + src_len) * 2;
+///
+/// If `input` does not start with the VCDIFF magic bytes, `Xd3Error::NotAVcdiff` is
+/// returned without running the decoder. A delta that xdelta3 rejects because it is
+/// structurally impossible, e.g. copies from target data that was not produced yet, fails
+/// with `Xd3Error::MalformedDelta` naming the offending window.
+pub fn decode(input: &[u8], src: &[u8]) -> Result<Vec<u8>, Xd3Error> {
+    if !input.starts_with(&VCDIFF_MAGIC) {
+        return Err(Xd3Error::NotAVcdiff);
+    }
+
+    let data_len = 2 * (input.len() as u64 + src.len() as u64);
+    // the window headers declare the target length, but they are untrusted: believe them up
+    // to a bounded multiple of the data at hand, and grow the buffer from there if needed
+    let declared = validate_delta(input).map_or(0, |info| info.total_target_size);
+    let estimated_out_len = std::cmp::max(data_len, std::cmp::min(declared, data_len * 16));
+    process_memory(binding::xd3_decode_memory, input, src, estimated_out_len).map_err(|code| {
+        // xdelta3 only reports a code, so look for the offending window ourselves
+        match disassemble(input) {
+            Err(err @ Xd3Error::MalformedDelta(_)) => err,
+            _ => Xd3Error::Code(code),
+        }
+    })
+}
+
+type MemoryFn = unsafe extern "C" fn(
+    *const u8,
+    c_uint,
+    *const u8,
+    c_uint,
+    *mut u8,
+    *mut c_uint,
+    c_uint,
+    libc::c_int,
+) -> libc::c_int;
+
+/// Run `xd3_encode_memory` or `xd3_decode_memory` with an output buffer of
+/// `estimated_out_len` bytes, doubling it for as long as xdelta3 runs out of space.
+fn process_memory(
+    process: MemoryFn,
+    input: &[u8],
+    src: &[u8],
+    estimated_out_len: u64,
+) -> Result<Vec<u8>, libc::c_int> {
+    let mut out_len = std::cmp::min(estimated_out_len, u64::from(c_uint::MAX)) as c_uint;
+    loop {
         let mut avail_output = 0 as c_uint;
-        let mut output = Vec::with_capacity(estimated_out_len as usize);
-        let result = binding::xd3_decode_memory(
-            input.as_ptr(),
-            input_len,
-            src.as_ptr(),
-            src_len,
-            output.as_mut_ptr(),
-            &mut avail_output,
-            estimated_out_len,
-            0,
-        );
-        if result == 0 {
-            output.set_len(avail_output as usize);
-            Some(output)
-        } else {
-            None
+        let mut output = Vec::with_capacity(out_len as usize);
+        let result = unsafe {
+            process(
+                input.as_ptr(),
+                input.len() as c_uint,
+                src.as_ptr(),
+                src.len() as c_uint,
+                output.as_mut_ptr(),
+                &mut avail_output,
+                out_len,
+                0,
+            )
+        };
+        match result {
+            0 => {
+                unsafe { output.set_len(avail_output as usize) };
+                return Ok(output);
+            }
+            libc::ENOSPC if out_len < c_uint::MAX => {
+                out_len = std::cmp::max(out_len, 1).saturating_mul(2);
+            }
+            code => return Err(code),
         }
     }
 }
+
+#[cfg(feature = "lzma")]
+const VERSION: &str = concat!(
+    "xdelta3 ",
+    env!("XDELTA3_VERSION"),
+    ", features: djw, fgk, lzma"
+);
+#[cfg(not(feature = "lzma"))]
+const VERSION: &str = concat!("xdelta3 ", env!("XDELTA3_VERSION"), ", features: djw, fgk");
+
+/// Version of the bundled xdelta3 library and the secondary compressors it was built with,
+/// e.g. `"xdelta3 3.0.11, features: djw, fgk"`.
+pub fn version() -> &'static str {
+    VERSION
+}
+
+/// Whether the LZMA secondary compressor is available (the `lzma` feature).
+pub fn supports_lzma() -> bool {
+    cfg!(feature = "lzma")
+}
+
+/// Whether the DJW secondary compressor is available. It is always built in.
+pub fn supports_djw() -> bool {
+    true
+}
@@ -0,0 +1,11 @@
+//! Re-exports the most commonly used items.
+//!
+//! `use xdelta3::prelude::*` is enough to encode and decode in memory, and with the
+//! `stream` feature, through readers and writers.
+
+pub use crate::{decode, encode, Xd3Error};
+
+#[cfg(feature = "stream")]
+pub use crate::stream::{
+    decode_async, encode_async, process, process_async, EncodeStats, ProcessMode, Xd3Config,
+};
@@ -0,0 +1,224 @@
+//! Source reader for files served over HTTP.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_io::{AsyncRead, AsyncSeek};
+use log::trace;
+use reqwest::header::{CONTENT_LENGTH, RANGE};
+use reqwest::StatusCode;
+
+type BlockFuture = Pin<Box<dyn Future<Output = io::Result<Bytes>> + Send>>;
+
+/// Reads a remote file over HTTP, one range request per block.
+///
+/// Blocks are downloaded lazily as the encoder or decoder reads the source, so only the
+/// parts of the reference file that are actually read get transferred. The server has to
+/// support range requests. The `max_cache_blocks` most recently used blocks are kept in
+/// memory, so seeking back to one, as `ProcessState::with_source_refetch` does for source
+/// blocks xdelta3 asks for again, doesn't download it again.
+///
+/// Blocks are fetched strictly one at a time, as xdelta3 asks for them: there is no
+/// readahead, so at most one request per source is ever in flight.
+pub struct HttpSource {
+    url: String,
+    client: reqwest::Client,
+    block_size: u32,
+    total_size: Option<u64>,
+    max_cache_blocks: usize,
+
+    pos: u64,
+    // block number -> (last use, data)
+    cache: HashMap<u64, (u64, Bytes)>,
+    uses: u64,
+    // block number and download in flight
+    pending: Option<(u64, BlockFuture)>,
+}
+
+impl HttpSource {
+    /// Default block size, 1 MiB.
+    pub const DEFAULT_BLOCK_SIZE: u32 = 1 << 20;
+    /// Default number of cached blocks.
+    pub const DEFAULT_MAX_CACHE_BLOCKS: usize = 16;
+
+    /// Prepare to read `url`, issuing a `HEAD` request to learn its size.
+    ///
+    /// A missing `Content-Length` is not an error; the end of the file is then found by a
+    /// range request coming back empty.
+    pub async fn new(url: String, client: reqwest::Client) -> io::Result<Self> {
+        let resp = client.head(&url).send().await.map_err(to_io_error)?;
+        let resp = resp.error_for_status().map_err(to_io_error)?;
+        let total_size = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        trace!("HttpSource: url={}, total_size={:?}", url, total_size);
+
+        Ok(Self {
+            url,
+            client,
+            block_size: Self::DEFAULT_BLOCK_SIZE,
+            total_size,
+            max_cache_blocks: Self::DEFAULT_MAX_CACHE_BLOCKS,
+            pos: 0,
+            cache: HashMap::new(),
+            uses: 0,
+            pending: None,
+        })
+    }
+
+    /// Bytes fetched per range request.
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        assert!(block_size > 0, "block size must not be zero");
+        self.block_size = block_size;
+        self
+    }
+
+    /// How many downloaded blocks to keep in memory.
+    pub fn max_cache_blocks(mut self, max_cache_blocks: usize) -> Self {
+        self.max_cache_blocks = std::cmp::max(max_cache_blocks, 1);
+        self
+    }
+
+    /// Size of the remote file, if the server reported it.
+    pub fn total_size(&self) -> Option<u64> {
+        self.total_size
+    }
+
+    fn fetch(&self, blkno: u64) -> BlockFuture {
+        let start = blkno * u64::from(self.block_size);
+        let mut end = start + u64::from(self.block_size) - 1;
+        if let Some(total) = self.total_size {
+            end = std::cmp::min(end, total.saturating_sub(1));
+        }
+        trace!("HttpSource: fetching bytes={}-{}", start, end);
+
+        let request = self
+            .client
+            .get(&self.url)
+            .header(RANGE, format!("bytes={}-{}", start, end));
+        Box::pin(async move {
+            let resp = request.send().await.map_err(to_io_error)?;
+            match resp.status() {
+                StatusCode::PARTIAL_CONTENT => resp.bytes().await.map_err(to_io_error),
+                StatusCode::RANGE_NOT_SATISFIABLE => Ok(Bytes::new()),
+                status if status.is_success() => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "server does not support range requests",
+                )),
+                status => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("range request failed: {}", status),
+                )),
+            }
+        })
+    }
+}
+
+impl AsyncRead for HttpSource {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if buf.is_empty() || this.total_size.map_or(false, |total| this.pos >= total) {
+            return Poll::Ready(Ok(0));
+        }
+
+        let block_size = u64::from(this.block_size);
+        let blkno = this.pos / block_size;
+        loop {
+            if let Some((last_use, block)) = this.cache.get_mut(&blkno) {
+                this.uses += 1;
+                *last_use = this.uses;
+
+                let offset = (this.pos - blkno * block_size) as usize;
+                if offset >= block.len() {
+                    // short block: end of file
+                    return Poll::Ready(Ok(0));
+                }
+                let len = std::cmp::min(buf.len(), block.len() - offset);
+                buf[..len].copy_from_slice(&block[offset..offset + len]);
+                this.pos += len as u64;
+                return Poll::Ready(Ok(len));
+            }
+
+            let stale = this.pending.as_ref().map_or(true, |(b, _)| *b != blkno);
+            if stale {
+                this.pending = Some((blkno, this.fetch(blkno)));
+            }
+            let fetch = &mut this.pending.as_mut().unwrap().1;
+            let block = match fetch.as_mut().poll(cx) {
+                Poll::Ready(Ok(block)) => block,
+                Poll::Ready(Err(e)) => {
+                    this.pending = None;
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            this.pending = None;
+
+            if this.cache.len() >= this.max_cache_blocks {
+                let lru = this
+                    .cache
+                    .iter()
+                    .min_by_key(|(_, (last_use, _))| *last_use)
+                    .map(|(blkno, _)| *blkno)
+                    .unwrap();
+                this.cache.remove(&lru);
+            }
+            this.cache.insert(blkno, (this.uses, block));
+        }
+    }
+}
+
+impl AsyncSeek for HttpSource {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        let this = &mut *self;
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                this.pos = pos;
+                return Poll::Ready(Ok(pos));
+            }
+            SeekFrom::Current(offset) => (this.pos, offset),
+            SeekFrom::End(offset) => match this.total_size {
+                Some(size) => (size, offset),
+                None => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "can't seek from the end of a file of unknown size",
+                    )))
+                }
+            },
+        };
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+        match pos {
+            Some(pos) => {
+                this.pos = pos;
+                Poll::Ready(Ok(pos))
+            }
+            None => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ))),
+        }
+    }
+}
+
+fn to_io_error(e: reqwest::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
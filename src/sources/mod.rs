@@ -0,0 +1,11 @@
+//! Source readers for reference data that does not live in a local file.
+
+#[cfg(feature = "http-source")]
+mod http;
+#[cfg(feature = "s3")]
+mod s3;
+
+#[cfg(feature = "http-source")]
+pub use http::HttpSource;
+#[cfg(feature = "s3")]
+pub use s3::S3Source;
@@ -0,0 +1,217 @@
+//! Source reader for objects stored in Amazon S3.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::Client;
+use bytes::Bytes;
+use futures_io::{AsyncRead, AsyncSeek};
+use log::trace;
+
+type BlockFuture = Pin<Box<dyn Future<Output = io::Result<Bytes>> + Send>>;
+
+/// Reads an S3 object, one ranged `GetObject` request per block.
+///
+/// Like `HttpSource`, only the blocks the encoder or decoder actually reads are
+/// downloaded. The `max_cache_blocks` most recently used blocks are kept in memory, so
+/// seeking back to one, e.g. through `ProcessState::with_source_refetch`, doesn't hit S3
+/// again.
+///
+/// Blocks are fetched strictly one at a time, as xdelta3 asks for them: there is no
+/// readahead, so at most one request per source is ever in flight.
+pub struct S3Source {
+    client: Client,
+    bucket: String,
+    key: String,
+    block_size: u32,
+    object_size: u64,
+    max_cache_blocks: usize,
+
+    pos: u64,
+    // block number -> (last use, data)
+    cache: HashMap<u64, (u64, Bytes)>,
+    uses: u64,
+    // block number and download in flight
+    pending: Option<(u64, BlockFuture)>,
+}
+
+impl S3Source {
+    /// Default block size, 1 MiB.
+    pub const DEFAULT_BLOCK_SIZE: u32 = 1 << 20;
+    /// Default number of cached blocks.
+    pub const DEFAULT_MAX_CACHE_BLOCKS: usize = 16;
+
+    /// Prepare to read `bucket/key`, issuing a `HeadObject` request to learn its size.
+    pub async fn new(client: Client, bucket: String, key: String) -> io::Result<Self> {
+        let head = client
+            .head_object()
+            .bucket(&bucket)
+            .key(&key)
+            .send()
+            .await
+            .map_err(|e| to_io_error(DisplayErrorContext(e)))?;
+        let object_size = head.content_length().unwrap_or(0).max(0) as u64;
+        trace!(
+            "S3Source: bucket={}, key={}, object_size={}",
+            bucket,
+            key,
+            object_size
+        );
+
+        Ok(Self {
+            client,
+            bucket,
+            key,
+            block_size: Self::DEFAULT_BLOCK_SIZE,
+            object_size,
+            max_cache_blocks: Self::DEFAULT_MAX_CACHE_BLOCKS,
+            pos: 0,
+            cache: HashMap::new(),
+            uses: 0,
+            pending: None,
+        })
+    }
+
+    /// Bytes fetched per range request.
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        assert!(block_size > 0, "block size must not be zero");
+        self.block_size = block_size;
+        self
+    }
+
+    /// How many downloaded blocks to keep in memory.
+    pub fn max_cache_blocks(mut self, max_cache_blocks: usize) -> Self {
+        self.max_cache_blocks = std::cmp::max(max_cache_blocks, 1);
+        self
+    }
+
+    /// Size of the object.
+    pub fn object_size(&self) -> u64 {
+        self.object_size
+    }
+
+    fn fetch(&self, blkno: u64) -> BlockFuture {
+        let start = blkno * u64::from(self.block_size);
+        let end = std::cmp::min(start + u64::from(self.block_size), self.object_size) - 1;
+        trace!("S3Source: fetching bytes={}-{}", start, end);
+
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .range(format!("bytes={}-{}", start, end));
+        Box::pin(async move {
+            let resp = request
+                .send()
+                .await
+                .map_err(|e| to_io_error(DisplayErrorContext(e)))?;
+            let body = resp.body.collect().await.map_err(to_io_error)?;
+            Ok(body.into_bytes())
+        })
+    }
+}
+
+impl AsyncRead for S3Source {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if buf.is_empty() || this.pos >= this.object_size {
+            return Poll::Ready(Ok(0));
+        }
+
+        let block_size = u64::from(this.block_size);
+        let blkno = this.pos / block_size;
+        loop {
+            if let Some((last_use, block)) = this.cache.get_mut(&blkno) {
+                this.uses += 1;
+                *last_use = this.uses;
+
+                let offset = (this.pos - blkno * block_size) as usize;
+                if offset >= block.len() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "S3 object is shorter than its reported size",
+                    )));
+                }
+                let len = std::cmp::min(buf.len(), block.len() - offset);
+                buf[..len].copy_from_slice(&block[offset..offset + len]);
+                this.pos += len as u64;
+                return Poll::Ready(Ok(len));
+            }
+
+            let stale = this.pending.as_ref().map_or(true, |(b, _)| *b != blkno);
+            if stale {
+                this.pending = Some((blkno, this.fetch(blkno)));
+            }
+            let fetch = &mut this.pending.as_mut().unwrap().1;
+            let block = match fetch.as_mut().poll(cx) {
+                Poll::Ready(Ok(block)) => block,
+                Poll::Ready(Err(e)) => {
+                    this.pending = None;
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            this.pending = None;
+
+            if this.cache.len() >= this.max_cache_blocks {
+                let lru = this
+                    .cache
+                    .iter()
+                    .min_by_key(|(_, (last_use, _))| *last_use)
+                    .map(|(blkno, _)| *blkno)
+                    .unwrap();
+                this.cache.remove(&lru);
+            }
+            this.cache.insert(blkno, (this.uses, block));
+        }
+    }
+}
+
+impl AsyncSeek for S3Source {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        let this = &mut *self;
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                this.pos = pos;
+                return Poll::Ready(Ok(pos));
+            }
+            SeekFrom::Current(offset) => (this.pos, offset),
+            SeekFrom::End(offset) => (this.object_size, offset),
+        };
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+        match pos {
+            Some(pos) => {
+                this.pos = pos;
+                Poll::Ready(Ok(pos))
+            }
+            None => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ))),
+        }
+    }
+}
+
+fn to_io_error<E>(e: E) -> io::Error
+where
+    E: std::fmt::Display,
+{
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
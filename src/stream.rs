@@ -1,24 +1,86 @@
 use futures_io::*;
+use futures_util::future::Either;
 use futures_util::io::*;
-use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
 use std::io;
+use std::ops::ControlFlow;
+use std::path::Path;
+#[cfg(feature = "rayon")]
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
 
 use super::binding;
-use log::{debug, trace};
+use super::checksum;
+use super::error::{Xd3ConfigError, Xd3IoErrorExt};
+use super::io::{
+    padded_frame_len, AsyncUnpadReader, PADDED_FORMAT_VERSION, PADDED_HEADER_LEN, PADDED_MAGIC,
+};
+use super::vcdiff;
+use super::Xd3Error;
+use log::{debug, trace, warn};
 
-pub use binding::xd3_smatch_cfg;
+pub use binding::{
+    xd3_alloc_func, xd3_decode_state, xd3_encode_state, xd3_free_func, xd3_rvalues, xd3_smatch_cfg,
+};
 
-#[allow(unused)]
-const XD3_DEFAULT_WINSIZE: usize = 1 << 23;
-const XD3_DEFAULT_SRCWINSZ: u64 = 1 << 26;
-#[allow(unused)]
-const XD3_DEFAULT_ALLOCSIZE: usize = 1 << 14;
-#[allow(unused)]
-const XD3_DEFAULT_SPREVSZ: usize = 1 << 18;
+/// Default input window size (8 MiB), matching xdelta3's `XD3_DEFAULT_WINSIZE`.
+///
+/// The target is encoded in windows of this size; larger windows find more matches at the
+/// cost of a bigger input buffer.
+pub const XD3_DEFAULT_WINSIZE: usize = 1 << 23;
+/// Default source window size (64 MiB), matching xdelta3's `XD3_DEFAULT_SRCWINSZ`.
+///
+/// This bounds how much of the source is visible to the matcher at once.
+pub const XD3_DEFAULT_SRCWINSZ: u64 = 1 << 26;
+/// Default allocation granularity (16 KiB), matching xdelta3's `XD3_DEFAULT_ALLOCSIZE`.
+///
+/// xdelta3 grows its internal output buffers in steps of this size.
+pub const XD3_DEFAULT_ALLOCSIZE: usize = 1 << 14;
+/// Default small-match history size (256 KiB), matching xdelta3's `XD3_DEFAULT_SPREVSZ`.
+///
+/// This is how far back within the target the small string matcher looks for copies.
+pub const XD3_DEFAULT_SPREVSZ: usize = 1 << 18;
+/// Largest window xdelta3 decodes (16 MiB), matching xdelta3's `XD3_HARDMAXWINSIZE`.
+pub const XD3_HARDMAXWINSIZE: usize = 1 << 24;
+/// Default instruction optimization buffer size (32768 instructions), matching xdelta3's
+/// `XD3_DEFAULT_IOPT_SIZE`.
+pub const XD3_DEFAULT_IOPT_SIZE: usize = 1 << 15;
+
+// same as std's default `BufReader`/`BufWriter` capacity
+const DEFAULT_IO_BUFFER_SIZE: usize = 8 * 1024;
+
+/// A source block cache operation, reported to the observer set with
+/// `ProcessState::with_block_observer`. Only meant for tests and cache tuning.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockEvent {
+    /// A block was read from the source reader. `cache_miss` is true for the block xdelta3
+    /// asked for and false for blocks before it that had to be read on the way.
+    BlockFetch { blkno: u64, cache_miss: bool },
+    /// xdelta3 asked for a block that was already cached.
+    BlockHit { blkno: u64 },
+    /// A block was dropped from the cache to make room for a new one.
+    BlockEvict { blkno: u64 },
+}
+
+// `<R as AsyncSeek>::poll_seek`, as a plain function pointer so that only the code enabling
+// re-fetches needs `R: AsyncSeek`
+type SeekFn<R> = fn(
+    std::pin::Pin<&mut R>,
+    &mut std::task::Context<'_>,
+    io::SeekFrom,
+) -> std::task::Poll<io::Result<u64>>;
 
 struct CacheEntry {
     len: usize,
-    buf: Box<[u8]>,
+    buf: Arc<[u8]>,
+    // content hash, only computed when dedup is enabled
+    hash: u64,
 }
 
 struct SrcBuffer<R> {
@@ -26,68 +88,310 @@ struct SrcBuffer<R> {
     read: R,
     read_len: usize,
     eof_known: bool,
+    // total source length, when known up front
+    size: Option<u64>,
 
     block_offset: usize,
     block_len: usize,
     cache: BTreeMap<usize, CacheEntry>,
+    // most blocks kept in `cache` at once
+    capacity: usize,
+    // seeks back to blocks that were already evicted, if the reader can
+    seek: Option<SeekFn<R>>,
+    // fail instead of warning once too many blocks had to be read again
+    strict: bool,
+    churn_warned: bool,
+    // content hash -> cached block with that content
+    dedup: Option<HashMap<u64, usize>>,
+    // whole source in memory: blocks point straight into it and `read` is unused
+    shared: Option<Arc<[u8]>>,
+    stats: SrcStats,
+    #[cfg(any(test, feature = "testing"))]
+    observer: Option<Box<dyn FnMut(BlockEvent) + Send>>,
 }
-unsafe impl<R> Send for SrcBuffer<R> {}
+// the raw pointers in `src` point into `cache` or `shared`, which move along with it
+unsafe impl<R: Send> Send for SrcBuffer<R> {}
 
 impl<R> SrcBuffer<R> {
-    fn new(cfg: &Xd3Config, read: R) -> io::Result<Self> {
+    /// With a known `size` the source geometry is final from the start, so xdelta3 does not
+    /// have to discover EOF through a short read.
+    fn new(cfg: &Xd3Config, read: R, size: Option<u64>) -> io::Result<Self> {
         let block_count = 32;
-        let max_winsize = cfg.source_window_size;
-        let blksize = max_winsize / block_count;
+        // at least one byte, for source windows smaller than `block_count`
+        let mut blksize = std::cmp::max(cfg.source_window_size / block_count, 1);
+        if blksize % cfg.src_block_alignment != 0 {
+            blksize = (blksize / cfg.src_block_alignment + 1) * cfg.src_block_alignment;
+        }
+        let max_winsize = std::cmp::max(cfg.source_window_size, blksize);
+        // xdelta3 copies from up to a source window behind the point it has indexed to, and
+        // matches extend past both ends of that, so keep twice the window
+        let capacity = 2 * (max_winsize / blksize) as usize + 2;
 
         let cache = BTreeMap::new();
-
-        let mut src: Box<binding::xd3_source> = Box::new(unsafe { std::mem::zeroed() });
-        src.blksize = blksize as u32;
-        src.max_winsize = max_winsize;
+        let src = Self::fresh_source(blksize, max_winsize, size);
 
         Ok(Self {
             src,
             read,
             read_len: 0,
             eof_known: false,
+            size,
 
             block_offset: 0,
             block_len: blksize as usize,
             cache,
+            capacity,
+            seek: None,
+            strict: cfg.strict_source_window,
+            churn_warned: false,
+            dedup: None,
+            shared: None,
+            stats: SrcStats::default(),
+            #[cfg(any(test, feature = "testing"))]
+            observer: None,
         })
     }
+
+    fn fresh_source(blksize: u64, max_winsize: u64, size: Option<u64>) -> Box<binding::xd3_source> {
+        let mut src: Box<binding::xd3_source> = Box::new(unsafe { std::mem::zeroed() });
+        src.blksize = blksize as u32;
+        src.max_winsize = max_winsize;
+        if let Some(size) = size {
+            // same split as xd3_set_source_and_size: a source ending on a block boundary
+            // has an empty last block
+            src.eof_known = 1;
+            src.max_blkno = size / blksize;
+            src.onlastblk = (size % blksize) as u32;
+        }
+        src
+    }
+
+    /// Fix the source length after construction, as `new` does for a known `size`. Only
+    /// valid before the first block was requested.
+    fn set_size(&mut self, size: u64) {
+        let blksize = self.src.blksize as u64;
+        self.size = Some(size);
+        self.src.eof_known = 1;
+        self.src.max_blkno = size / blksize;
+        self.src.onlastblk = (size % blksize) as u32;
+    }
+
+    /// Share the storage of blocks with identical content, so that highly redundant
+    /// sources (e.g. sparse files full of zero blocks) only keep one copy of each block.
+    fn with_dedup(mut self) -> Self {
+        self.dedup = Some(HashMap::new());
+        self
+    }
+
+    /// Number of re-fetched blocks above which the source window is considered too small:
+    /// as many as the cache holds.
+    fn churn_threshold(&self) -> u64 {
+        self.capacity as u64
+    }
+
+    /// Call `observer` on every block fetch, cache hit and eviction.
+    #[cfg(any(test, feature = "testing"))]
+    fn with_observer<F>(mut self, observer: F) -> Self
+    where
+        F: FnMut(BlockEvent) + Send + 'static,
+    {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    fn notify(&mut self, event: BlockEvent) {
+        if let Some(observer) = self.observer.as_mut() {
+            observer(event);
+        }
+    }
+
+    /// Source length given up front, if any.
+    fn expected_source_size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Number of blocks in a source of the length given up front, if any.
+    fn expected_blocks(&self) -> Option<u64> {
+        let blksize = self.src.blksize as u64;
+        self.size.map(|size| (size + blksize - 1) / blksize)
+    }
+
+    /// Total number of source blocks, or `None` while the source length is unknown.
+    fn total_blocks_in_source(&self) -> Option<u64> {
+        match self.expected_blocks() {
+            Some(blocks) => Some(blocks),
+            None if self.eof_known => Some(self.block_offset as u64),
+            None => None,
+        }
+    }
+
+    /// Cached source blocks as `(block number, data)`, in block order.
+    #[cfg(any(test, feature = "testing"))]
+    fn iter_cached_blocks(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.cache.iter().map(|(k, v)| (*k as u64, &v.buf[..v.len]))
+    }
+
+    /// Give back the source reader. Only call this once no stream refers to `src` anymore.
+    fn into_reader(self) -> R {
+        self.read
+    }
+
+    /// Number of blocks left after the current one, or `None` while the source length is
+    /// unknown. Together with `total_blocks_in_source` this gives source-read progress.
+    fn remaining_blocks(&self) -> Option<u64> {
+        let total = self.total_blocks_in_source()?;
+        if self.src.curblk.is_null() {
+            // no block loaded yet
+            return Some(total);
+        }
+        Some(total.saturating_sub(self.src.curblkno + 1))
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> SrcBuffer<R> {
+    /// Read evicted blocks again when xdelta3 asks for them, instead of failing.
+    fn with_refetch(mut self) -> Self {
+        self.seek = Some(<R as AsyncSeek>::poll_seek);
+        self
+    }
+
+    /// Drop all cached blocks and rewind the reader, so the source can be read again from
+    /// the start.
+    ///
+    /// `src` is replaced with a fresh `xd3_source`, which has to be handed to the stream
+    /// with `xd3_set_source` again.
+    async fn clear_and_reset_reader(&mut self) -> io::Result<()> {
+        self.read.seek(io::SeekFrom::Start(0)).await?;
+
+        self.cache.clear();
+        if let Some(dedup) = self.dedup.as_mut() {
+            dedup.clear();
+        }
+        self.block_offset = 0;
+        self.eof_known = false;
+        self.read_len = 0;
+        self.stats = SrcStats::default();
+        self.churn_warned = false;
+        self.src = Self::fresh_source(self.src.blksize as u64, self.src.max_winsize, self.size);
+        Ok(())
+    }
 }
 
 impl<R: AsyncRead + Unpin> SrcBuffer<R> {
+    /// Read the next block from the source reader.
     async fn fetch(&mut self) -> Result<()> {
-        let mut buf = if self.cache.len() == self.block_offset + 1 {
-            let mut key = 0usize;
-            for (k, _v) in &self.cache {
-                key = *k;
-                break;
+        let blkno = self.block_offset;
+        let read_len = self.read_block(blkno).await?;
+        if read_len < self.block_len {
+            self.eof_known = true;
+        }
+        self.read_len += read_len;
+        self.block_offset += 1;
+        Ok(())
+    }
+
+    /// Read an already evicted block again, leaving the reader where it was.
+    async fn refetch(&mut self, seek: SeekFn<R>, blkno: usize) -> Result<()> {
+        self.seek_to(seek, (blkno * self.block_len) as u64).await?;
+        self.read_block(blkno).await?;
+        self.seek_to(seek, self.read_len as u64).await?;
+        self.stats.blocks_refetched += 1;
+        Ok(())
+    }
+
+    async fn seek_to(&mut self, seek: SeekFn<R>, offset: u64) -> Result<u64> {
+        let read = &mut self.read;
+        futures_util::future::poll_fn(|cx| {
+            seek(
+                std::pin::Pin::new(&mut *read),
+                cx,
+                io::SeekFrom::Start(offset),
+            )
+        })
+        .await
+    }
+
+    /// Read block `blkno` from the current reader position into the cache, evicting the
+    /// cached block farthest from it if the cache is full. Returns the number of bytes read.
+    async fn read_block(&mut self, blkno: usize) -> Result<usize> {
+        let evicted = if self.cache.len() >= self.capacity {
+            // the lowest block when reading forward
+            let first = *self.cache.keys().next().unwrap();
+            let last = *self.cache.keys().next_back().unwrap();
+            let key = if blkno.saturating_sub(first) >= last.saturating_sub(blkno) {
+                first
+            } else {
+                last
+            };
+            let entry = self.cache.remove(&key).unwrap();
+            self.stats.blocks_evicted += 1;
+            #[cfg(any(test, feature = "testing"))]
+            self.notify(BlockEvent::BlockEvict { blkno: key as u64 });
+            if let Some(dedup) = self.dedup.as_mut() {
+                if dedup.get(&entry.hash) == Some(&key) {
+                    dedup.remove(&entry.hash);
+                }
             }
-            self.cache.remove(&key).unwrap().buf
+            Some(entry.buf)
         } else {
-            let v = vec![0u8; self.block_len];
-            v.into_boxed_slice()
+            None
+        };
+
+        // reuse the evicted buffer unless it is still shared with a deduplicated block
+        let mut buf = match evicted {
+            Some(buf) if Arc::strong_count(&buf) == 1 => buf,
+            _ => vec![0u8; self.block_len].into(),
         };
 
         let mut read_len = 0;
+        {
+            let data = Arc::get_mut(&mut buf).unwrap();
+            while read_len != data.len() {
+                let len = self.read.read(&mut data[read_len..]).await?;
+                if len == 0 {
+                    break;
+                } else {
+                    read_len += len;
+                }
+            }
+        }
 
-        while read_len != buf.len() {
-            let len = self.read.read(&mut buf[read_len..]).await?;
-            if len == 0 {
-                self.eof_known = true;
-                break;
-            } else {
-                read_len += len;
+        let mut hash = 0;
+        if let Some(dedup) = self.dedup.as_mut() {
+            let mut hasher = DefaultHasher::new();
+            buf[..read_len].hash(&mut hasher);
+            hash = hasher.finish();
+
+            let existing = match dedup.get(&hash) {
+                Some(blkno) => self.cache.get(blkno),
+                None => None,
+            };
+            match existing {
+                Some(entry) if entry.buf[..entry.len] == buf[..read_len] => {
+                    trace!("fetch: blkno={} shares storage", blkno);
+                    buf = entry.buf.clone();
+                    self.stats.blocks_shared += 1;
+                }
+                _ => {
+                    dedup.insert(hash, blkno);
+                }
             }
         }
 
-        let entry = CacheEntry { len: read_len, buf };
-        self.cache.insert(self.block_offset, entry);
-        self.block_offset += 1;
-        Ok(())
+        let entry = CacheEntry {
+            len: read_len,
+            buf,
+            hash,
+        };
+        self.stats.blocks_read += 1;
+        #[cfg(any(test, feature = "testing"))]
+        self.notify(BlockEvent::BlockFetch {
+            blkno: blkno as u64,
+            cache_miss: blkno as u64 == self.src.getblkno,
+        });
+        self.cache.insert(blkno, entry);
+        Ok(read_len)
     }
 
     async fn getblk(&mut self) -> io::Result<()> {
@@ -98,17 +402,68 @@ impl<R: AsyncRead + Unpin> SrcBuffer<R> {
         );
 
         let blkno = self.src.getblkno as usize;
+        self.stats.blocks_requested += 1;
+
+        if let Some(data) = self.shared.as_ref() {
+            let src = &mut self.src;
+            let blksize = src.blksize as usize;
+            let start = std::cmp::min(blkno * blksize, data.len());
+            let end = std::cmp::min(start + blksize, data.len());
+            src.curblkno = src.getblkno;
+            src.curblk = data[start..].as_ptr();
+            src.onblk = (end - start) as u32;
+            #[cfg(any(test, feature = "testing"))]
+            self.notify(BlockEvent::BlockHit {
+                blkno: blkno as u64,
+            });
+            return Ok(());
+        }
+
+        #[cfg(any(test, feature = "testing"))]
+        {
+            if self.cache.contains_key(&blkno) {
+                self.notify(BlockEvent::BlockHit {
+                    blkno: blkno as u64,
+                });
+            }
+        }
 
         let entry = loop {
             match self.cache.get_mut(&blkno) {
                 Some(entry) => break entry,
                 None => {
                     if blkno < self.block_offset {
-                        eprintln!("invalid blkno={}, offset={}", blkno, self.block_offset);
-                        for (k, _v) in &self.cache {
-                            eprintln!("key={:?}", k);
+                        let too_small = Xd3Error::SourceWindowTooSmall {
+                            blkno: blkno as u64,
+                            source_window_size: self.src.max_winsize,
+                        };
+                        let seek = match self.seek {
+                            Some(seek) => seek,
+                            None => {
+                                warn!(
+                                    "source block {} was evicted (next block to read is {}), \
+                                     consider a larger source_window_size",
+                                    blkno, self.block_offset
+                                );
+                                return Err(io::Error::new(io::ErrorKind::Other, too_small));
+                            }
+                        };
+                        if self.stats.blocks_refetched >= self.churn_threshold() {
+                            if self.strict {
+                                return Err(io::Error::new(io::ErrorKind::Other, too_small));
+                            }
+                            if !self.churn_warned {
+                                self.churn_warned = true;
+                                warn!(
+                                    "read {} evicted source blocks again, \
+                                     consider a larger source_window_size",
+                                    self.stats.blocks_refetched
+                                );
+                            }
                         }
-                        panic!("invalid blkno");
+
+                        self.refetch(seek, blkno).await?;
+                        continue;
                     }
 
                     self.fetch().await?;
@@ -125,24 +480,54 @@ impl<R: AsyncRead + Unpin> SrcBuffer<R> {
         src.curblk = data.as_ptr();
         src.onblk = buf_len as u32;
 
-        src.eof_known = self.eof_known as i32;
-        if !self.eof_known {
-            src.max_blkno = src.curblkno;
-            src.onlastblk = src.onblk;
-        } else {
-            src.max_blkno = (self.block_offset - 1) as u64;
-            src.onlastblk = (self.read_len % src.blksize as usize) as u32;
+        if self.size.is_none() {
+            src.eof_known = self.eof_known as i32;
+            if !self.eof_known {
+                src.max_blkno = src.curblkno;
+                src.onlastblk = src.onblk;
+            } else {
+                src.max_blkno = (self.block_offset - 1) as u64;
+                src.onlastblk = (self.read_len % src.blksize as usize) as u32;
+            }
         }
+
+        trace!(
+            "getsrcblk: total_blocks={:?}, remaining_blocks={:?}",
+            self.total_blocks_in_source(),
+            self.remaining_blocks(),
+        );
         Ok(())
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Xd3Config {
     inner: Box<binding::xd3_config>,
 
     // source config
     source_window_size: u64,
+    source_window_factor: Option<f64>,
+    dictionary: Option<Arc<[u8]>>,
+    buffer_source: bool,
+    source_dedup: bool,
+    src_block_alignment: u64,
+    strict_source_window: bool,
+
+    // input config
+    deterministic_windows: bool,
+    input_buffer_size: Option<usize>,
+    adaptive_winsize: Option<(u32, u32)>,
+
+    // output config
+    output_buffer_size: Option<usize>,
+    flush_each_window: bool,
+    header_only: bool,
+    window_padding: Option<u32>,
+
+    // file I/O config
+    io_buffer_size: usize,
+
+    deadline: Option<std::time::Instant>,
 }
 unsafe impl Send for Xd3Config {}
 
@@ -155,6 +540,21 @@ impl Xd3Config {
         let config = Self {
             inner: Box::new(cfg),
             source_window_size: XD3_DEFAULT_SRCWINSZ,
+            source_window_factor: None,
+            dictionary: None,
+            buffer_source: false,
+            source_dedup: false,
+            src_block_alignment: 1,
+            strict_source_window: false,
+            deterministic_windows: false,
+            input_buffer_size: None,
+            adaptive_winsize: None,
+            output_buffer_size: None,
+            flush_each_window: false,
+            header_only: false,
+            window_padding: None,
+            io_buffer_size: DEFAULT_IO_BUFFER_SIZE,
+            deadline: None,
         };
         config
     }
@@ -162,7 +562,18 @@ impl Xd3Config {
     pub fn window_size(mut self, winsize: u32) -> Self {
         let inner = self.inner.as_mut();
         inner.winsize = winsize.next_power_of_two();
-        self
+        match self.source_window_factor {
+            Some(factor) => self.source_window_scaling_factor(factor),
+            None => self,
+        }
+    }
+
+    /// Set the window size to `1 << bits` bytes.
+    ///
+    /// Panics if `bits` is greater than 31.
+    pub fn window_size_bits(self, bits: u8) -> Self {
+        assert!(bits <= 31, "window size bits must be at most 31");
+        self.window_size(1 << bits)
     }
 
     pub fn sprev_size(mut self, sprevsz: u32) -> Self {
@@ -171,11 +582,281 @@ impl Xd3Config {
         self
     }
 
+    /// Set `sprevsz` to `1 << bits` bytes, the way xdelta3 itself specifies it (the
+    /// default is `1 << 18`).
+    ///
+    /// Panics if `bits` is greater than 31.
+    pub fn sprevsz_bits(self, bits: u8) -> Self {
+        assert!(bits <= 31, "sprevsz bits must be at most 31");
+        self.sprev_size(1 << bits)
+    }
+
+    /// Check that `sprevsz` is at least `winsize`, returning the config unchanged if so.
+    ///
+    /// With a shorter small-match history the small string matcher cannot find copies
+    /// spanning a whole window, and compression quietly gets worse on self-similar input.
+    /// This is a heuristic rather than an xdelta3 requirement; note that the defaults (256
+    /// KiB history, 8 MiB window) do not pass it.
+    pub fn sprevsz_validated(self) -> std::result::Result<Self, Xd3ConfigError> {
+        let sprevsz = self.inner.sprevsz;
+        let winsize = self.inner.winsize;
+        if sprevsz < winsize {
+            return Err(Xd3ConfigError::SprevSzTooSmall {
+                sprevsz,
+                winsize,
+                recommended_min: winsize,
+            });
+        }
+        Ok(self)
+    }
+
+    /// Set the size of the instruction optimization buffer, in instructions.
+    ///
+    /// xdelta3 holds candidate instructions in this buffer and picks the cheapest
+    /// combination before emitting them, so a bigger buffer can shave bytes off the delta,
+    /// mostly on inputs with many short, overlapping matches. Each slot costs a few dozen
+    /// bytes. `Xd3Config::new` leaves this at 0, which xdelta3 treats as unlimited: the
+    /// buffer grows as needed for the best selection. The xdelta3 command line uses
+    /// `XD3_DEFAULT_IOPT_SIZE` instead, to bound memory.
+    pub fn iopt_size(mut self, iopt_size: u32) -> Self {
+        self.inner.iopt_size = iopt_size;
+        self
+    }
+
     pub fn source_window_size(mut self, source_window_size: u64) -> Self {
         self.source_window_size = source_window_size.next_power_of_two();
+        self.source_window_factor = None;
+        self
+    }
+
+    /// Size the source window as a multiple of the input window, rounded up to a power of
+    /// two.
+    ///
+    /// With `factor = 8.0`, which is how the defaults (8 MiB window, 64 MiB source
+    /// window) relate, copies can reach back over 8 windows' worth of source. The ratio is
+    /// kept if `window_size` is changed afterwards; `source_window_size` replaces it with
+    /// an absolute size again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `factor` is less than 1 or not finite.
+    pub fn source_window_scaling_factor(mut self, factor: f64) -> Self {
+        assert!(
+            factor.is_finite() && factor >= 1.0,
+            "source window scaling factor must be at least 1, got {}",
+            factor
+        );
+        let size = (f64::from(self.inner.winsize) * factor) as u64;
+        self = self.source_window_size(size);
+        self.source_window_factor = Some(factor);
+        self
+    }
+
+    /// Set the source window size to `1 << bits` bytes.
+    ///
+    /// Panics if `bits` is greater than 63.
+    pub fn source_window_size_bits(self, bits: u64) -> Self {
+        assert!(bits <= 63, "source window size bits must be at most 63");
+        self.source_window_size(1 << bits)
+    }
+
+    /// Read the whole source into memory before processing starts.
+    ///
+    /// The source window is grown to cover the entire source, so blocks are never evicted
+    /// and xdelta3 can copy from anywhere in the source regardless of how far back the
+    /// match is. xdelta3 reads blocks straight out of the buffer, so the source is held in
+    /// memory once, without a block cache. Only enable this for sources that comfortably
+    /// fit in memory.
+    pub fn buffer_source(mut self, buffer_source: bool) -> Self {
+        self.buffer_source = buffer_source;
+        self
+    }
+
+    /// Prime the matcher with `dictionary`, a sample of content the targets are likely to
+    /// share, in addition to the source.
+    ///
+    /// The dictionary is placed in front of the source, so copies from it are ordinary
+    /// source copies and the delta stays plain VCDIFF. The decoder has to be given the same
+    /// dictionary, otherwise the output is garbage. Keep `source_window_size` at least as
+    /// large as the dictionary and the source together so the dictionary stays reachable
+    /// for the whole input. Applies to `process` and the functions built on it; callers
+    /// driving a `ProcessState` chain the dictionary in front of the source themselves.
+    pub fn dictionary(mut self, dictionary: Vec<u8>) -> Self {
+        self.dictionary = Some(dictionary.into());
+        self
+    }
+
+    /// Keep a single copy of source blocks with identical content in the block cache.
+    ///
+    /// Each block is hashed as it is read, which costs some CPU but can save a lot of memory
+    /// for sources with many repeated blocks, such as sparse disk images.
+    pub fn source_dedup(mut self, source_dedup: bool) -> Self {
+        self.source_dedup = source_dedup;
+        self
+    }
+
+    /// Round the source block size up to a multiple of `alignment` bytes.
+    ///
+    /// Every source read then starts on an `alignment` boundary, which helps device files
+    /// and direct I/O where aligned reads are much faster (e.g. 512 for NVMe, 4096 for
+    /// most disks). This can only grow the block size, and with it the memory used by the
+    /// block cache.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `alignment` is not a power of two.
+    pub fn src_block_alignment(mut self, alignment: u64) -> Self {
+        assert!(
+            alignment.is_power_of_two(),
+            "source block alignment must be a power of two, got {}",
+            alignment
+        );
+        self.src_block_alignment = alignment;
+        self
+    }
+
+    /// Fail with `Xd3Error::SourceWindowTooSmall` instead of logging a warning when the
+    /// source window is too small for the delta.
+    ///
+    /// The block cache holds about twice the source window. A source opened with
+    /// `ProcessState::with_source_refetch` reads blocks again when xdelta3 goes back to
+    /// evicted ones, which is counted in `SrcStats::blocks_refetched`; once more blocks than
+    /// the cache holds had to be read again, this fails the operation rather than keep
+    /// seeking around the source. Sources that cannot seek always fail on an evicted block.
+    pub fn strict_source_window(mut self, strict_source_window: bool) -> Self {
+        self.strict_source_window = strict_source_window;
+        self
+    }
+
+    /// Fill the whole input window before handing input to xdelta3.
+    ///
+    /// With this set, window boundaries depend only on the window size and not on how the
+    /// input reader happens to chunk its data, so the same input and config always produce
+    /// byte-identical deltas. xdelta3 already buffers partial windows internally; this makes
+    /// the guarantee explicit and also saves a round trip through the state machine per
+    /// short read.
+    pub fn deterministic_windows(mut self, deterministic_windows: bool) -> Self {
+        self.deterministic_windows = deterministic_windows;
+        self
+    }
+
+    /// Read input in chunks of up to `input_buffer_size` bytes instead of a whole window.
+    ///
+    /// xdelta3 collects input into full windows on its own, so this only changes how much
+    /// is asked of the reader per `read().await`. Over a network transport, larger buffers
+    /// mean fewer await points and better throughput; smaller ones hand data to the encoder
+    /// sooner and use less memory. Defaults to the window size; 0 is taken as 1, since an
+    /// empty read would end the input.
+    pub fn input_buffer_size(mut self, input_buffer_size: usize) -> Self {
+        self.input_buffer_size = Some(std::cmp::max(input_buffer_size, 1));
+        self
+    }
+
+    /// Adapt the window size to the data while encoding, between `min` and `max` bytes.
+    ///
+    /// After each window, the size is halved for the next one if the window barely
+    /// compressed (output over 90% of input), since a big window buys nothing on
+    /// incompressible data, and doubled if it compressed well (under 30%). The starting
+    /// size is the configured window size clamped to the range; bounds are rounded up to
+    /// powers of two. xdelta3 can't change the window size of a running stream, so a fresh
+    /// stream is started per window and the source is read into memory up front, as with
+    /// `buffer_source`. Has no effect when decoding.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is greater than `max`.
+    pub fn adaptive_winsize(mut self, min: u32, max: u32) -> Self {
+        assert!(min <= max, "adaptive window size range is empty");
+        self.adaptive_winsize = Some((min.next_power_of_two(), max.next_power_of_two()));
+        self
+    }
+
+    /// Buffer writes to the output in `process` and `process_async`, `buffer_size` bytes at
+    /// a time.
+    ///
+    /// xdelta3 hands over output in pieces, each written with its own `write_all`, which
+    /// costs one syscall apiece on unbuffered outputs such as sockets or raw files. The
+    /// buffer is flushed when processing ends (and after each window with
+    /// `flush_each_window`). Leave it off for outputs that already buffer.
+    pub fn buffer_output(mut self, buffer_size: usize) -> Self {
+        self.output_buffer_size = Some(buffer_size);
+        self
+    }
+
+    /// Flush the output after every chunk xdelta3 produces, instead of only once at the end.
+    ///
+    /// Turn this on for sockets and pipes, where the peer should see each window as soon as
+    /// it is ready. Leave it off for files, where flushing a `BufWriter` per window only
+    /// costs extra syscalls.
+    pub fn flush_each_window(mut self, flush_each_window: bool) -> Self {
+        self.flush_each_window = flush_each_window;
+        self
+    }
+
+    /// Give up with `Xd3Error::DeadlineExceeded` (kind `TimedOut`) once `deadline` has
+    /// passed.
+    ///
+    /// The deadline is checked cooperatively, before each step of the xdelta3 state
+    /// machine, so a read or write that blocks is not interrupted; the job stops at the
+    /// first step after it returns. Steps are at most a window's worth of work, so this
+    /// bounds synchronous batch jobs without extra threads. Async callers can use their
+    /// runtime's timeout instead.
+    pub fn deadline(mut self, deadline: std::time::Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Stop encoding once the VCDIFF file header has been written.
+    ///
+    /// The output is a valid delta with no windows, which decodes to an empty target. It
+    /// carries the same header (secondary compressor id, application header) as a full
+    /// encode with this config, for protocols that send the header ahead of the windows.
+    /// Has no effect when decoding.
+    pub fn encode_header_only(mut self) -> Self {
+        self.header_only = true;
+        self
+    }
+
+    /// Set the buffer size used for the input, source and output files in `process_files`
+    /// and the other path-based helpers. Defaults to 8 KiB.
+    ///
+    /// Larger buffers mean fewer, bigger reads and writes, which improves throughput on
+    /// spinning disks; smaller ones keep memory use down on constrained systems. Each call
+    /// allocates three buffers of this size.
+    pub fn io_buffer_size(mut self, io_buffer_size: usize) -> Self {
+        self.io_buffer_size = io_buffer_size;
         self
     }
 
+    /// Route xdelta3's internal allocations through `alloc` and `free`.
+    ///
+    /// Both functions receive `opaque` as their first argument, which makes it a convenient
+    /// place to hang allocator state such as counters or an arena.
+    ///
+    /// # Safety
+    ///
+    /// `alloc` must return memory suitable for any C object of the requested size (or null
+    /// on failure), `free` must accept every pointer returned by `alloc`, and `opaque` must
+    /// stay valid for as long as any stream built from this config is alive.
+    pub unsafe fn allocator(
+        mut self,
+        alloc: xd3_alloc_func,
+        free: xd3_free_func,
+        opaque: *mut libc::c_void,
+    ) -> Self {
+        let inner = self.inner.as_mut();
+        inner.alloc = alloc;
+        inner.freef = free;
+        inner.opaque = opaque;
+        self
+    }
+
+    /// Restrict xdelta3's primary matcher to the source (`XD3_NOCOMPRESS`).
+    ///
+    /// Despite the name this does not touch secondary compression (see
+    /// `no_secondary_compression`): the delta is still VCDIFF-encoded against the source,
+    /// but data is no longer matched against earlier parts of the target, so a target that
+    /// repeats itself is stored as literal adds.
     pub fn no_compress(mut self, no_compress: bool) -> Self {
         let inner = self.inner.as_mut();
         if no_compress {
@@ -186,12 +867,213 @@ impl Xd3Config {
         self
     }
 
+    /// Same as `no_compress(true)`.
+    pub fn no_primary_compression(self) -> Self {
+        self.no_compress(true)
+    }
+
+    /// Compress the data, instruction and address sections of every window with
+    /// `compressor`, or with none (the default).
+    ///
+    /// The delta header then names the compressor, and decoding needs a build of xdelta3
+    /// that includes it. `no_secondary_compression` overrides this.
+    pub fn secondary_compression(mut self, compressor: Option<SecondaryCompressor>) -> Self {
+        use binding::xd3_flags::*;
+        let inner = self.inner.as_mut();
+        inner.flags &= !(XD3_SEC_TYPE as i32);
+        inner.flags |= match compressor {
+            None => 0,
+            Some(SecondaryCompressor::Djw) => XD3_SEC_DJW as i32,
+            Some(SecondaryCompressor::Fgk) => XD3_SEC_FGK as i32,
+            #[cfg(feature = "lzma")]
+            Some(SecondaryCompressor::Lzma) => XD3_SEC_LZMA as i32,
+        };
+        self
+    }
+
+    /// Leave the data, instruction and address sections of every window uncompressed by
+    /// the secondary compressor, by setting `XD3_SEC_NODATA`, `XD3_SEC_NOINST` and
+    /// `XD3_SEC_NOADDR`.
+    ///
+    /// This overrides `secondary_compression`; the delta header still names the
+    /// compressor, but no section uses it. With the default config this changes nothing.
+    pub fn no_secondary_compression(mut self) -> Self {
+        let inner = self.inner.as_mut();
+        inner.flags |= binding::xd3_flags::XD3_SEC_NOALL as i32;
+        self
+    }
+
+    /// Add an Adler-32 checksum of the target to every window.
+    ///
+    /// Each window then carries `VCD_ADLER32` in its indicator (see `vcdiff::VCD_ADLER32`)
+    /// and the decoder rejects a window whose output does not match its checksum. This is
+    /// the only window indicator bit a producer chooses: xdelta3 sets `VCD_SOURCE` on
+    /// windows that copy from the source and never emits `VCD_TARGET`. Decoders that only
+    /// implement RFC 3284 reject the checksum bit, so leave it off for those.
+    ///
+    /// This sets `XD3_ADLER32`. xdelta3 has no separate checksum for secondary-compressed
+    /// sections: the window checksum is computed over the decoded target, so it also
+    /// catches corruption inside them.
+    pub fn adler32(mut self, adler32: bool) -> Self {
+        let inner = self.inner.as_mut();
+        if adler32 {
+            inner.flags |= binding::xd3_flags::XD3_ADLER32 as i32;
+        } else {
+            inner.flags &= !(binding::xd3_flags::XD3_ADLER32 as i32);
+        }
+        self
+    }
+
+    /// Produce deltas that a decoder can apply while streaming its output.
+    ///
+    /// xdelta3 never emits `VCD_TARGET` windows, but by default it does copy from earlier
+    /// parts of the current target window. This sets `XD3_NOCOMPRESS`, which restricts the
+    /// matcher to the source, so every window is built from source copies, adds and runs
+    /// only. A decoder that writes instructions straight to its output then needs the source
+    /// window plus a small output buffer, instead of also keeping up to `winsize` bytes of
+    /// target resident. Deltas get larger when the target repeats itself.
+    pub fn streaming_decode_friendly(self) -> Self {
+        self.no_compress(true)
+    }
+
+    /// Produce deltas that are easy to combine with the next delta in a patch chain
+    /// (v1 -> v2 -> v3).
+    ///
+    /// Every copy then refers to the source (`no_compress`), so a window's output is a
+    /// function of the source and its own adds, and windows start at fixed multiples of the
+    /// window size regardless of how the input was read (`deterministic_windows`). Tools
+    /// that compose or rebase deltas can then work window by window on aligned target
+    /// ranges, with the window size as the granularity. The price is the same as for
+    /// `streaming_decode_friendly`: targets that repeat themselves encode larger.
+    pub fn chain_friendly(self) -> Self {
+        self.no_compress(true).deterministic_windows(true)
+    }
+
+    /// Preset for patching firmware images that are applied on small devices.
+    ///
+    /// - 4 KiB input windows, a typical flash page, with a 16 KiB small-match history:
+    ///   the decoder only needs a page of target plus the source segment in memory.
+    /// - Level 9, so the encoder works hard on the host.
+    /// - `no_secondary_compression`, so decoding stays a plain copy/add loop on the device
+    ///   even if a `secondary_compression` is chosen on top of the preset.
+    /// - `adler32`, so a corrupted patch or wrong base image is caught per window.
+    /// - `deterministic_windows`, so the same images always give the same patch.
+    ///
+    /// Small windows cost compression: matches can't span pages, and every window pays for
+    /// its own header. Raise `window_size` if the device has the memory for it.
+    pub fn for_firmware_update() -> Self {
+        Xd3Config::new()
+            .window_size(4 << 10)
+            .sprev_size(16 << 10)
+            .level(9)
+            .no_secondary_compression()
+            .adler32(true)
+            .deterministic_windows(true)
+    }
+
+    /// Keep the VCDIFF framing as small as possible, for tiny diffs where the header and
+    /// per-window fields would otherwise dominate.
+    ///
+    /// Raises the window size to cover an input of `input_len` bytes, up to
+    /// `XD3_HARDMAXWINSIZE`, so that it is encoded as a single window with a single
+    /// window header, and turns off `adaptive_winsize`, the per-window `adler32` checksum
+    /// (4 bytes per window) and secondary compression (whose tables cost more than they
+    /// save on small sections). xdelta3 never writes an application header or a custom
+    /// code table.
+    ///
+    /// What's left is a 5 byte file header and a window header of at least 8 bytes, plus
+    /// the instructions themselves: for targets under about 16 bytes, or ones that share
+    /// little with the source, storing the raw target is smaller. `encode_smallest` makes
+    /// that choice automatically.
+    pub fn compact_header(mut self, input_len: usize) -> Self {
+        self.adaptive_winsize = None;
+        let winsize = std::cmp::min(input_len, XD3_HARDMAXWINSIZE) as u32;
+        let cfg = if winsize > self.inner.winsize {
+            self.window_size(winsize)
+        } else {
+            self
+        };
+        cfg.adler32(false).no_secondary_compression()
+    }
+
+    /// Pad the output into frames whose sizes are multiples of `frame_size`, so that
+    /// packet lengths on an encrypted transport reveal less about the content.
+    ///
+    /// VCDIFF has no padding a decoder would skip, so this is a framing of this crate. The
+    /// stream starts with `io::PADDED_MAGIC` and the `io::PADDED_FORMAT_VERSION` byte, then
+    /// every chunk xdelta3 emits (a window, or a part of a large window's output) is
+    /// written as its length as a big-endian `u32`, the chunk, and zero bytes up to the
+    /// next multiple of `frame_size`; the 5 header bytes count towards the first frame.
+    /// That costs up to `frame_size + 3` bytes per chunk. The result is not a VCDIFF delta:
+    /// decode it with a config that has the same `pad_windows_to`, or strip the frames with
+    /// `io::AsyncUnpadReader`, which rejects streams of an unknown version. Both ends have
+    /// to agree on `frame_size`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frame_size` is 0.
+    pub fn pad_windows_to(mut self, frame_size: u32) -> Self {
+        assert!(frame_size > 0, "frame size must not be 0");
+        self.window_padding = Some(frame_size);
+        self
+    }
+
     pub fn set_smatch_config(mut self, smatch_cfg: binding::xd3_smatch_cfg) -> Self {
         let inner = self.inner.as_mut();
         inner.smatch_cfg = smatch_cfg;
         self
     }
 
+    /// Trade delta size for encoding speed by capping how hard the matcher searches.
+    ///
+    /// See `MatchEffort`. Overrides any earlier `set_smatch_config` or `align_to` matcher.
+    pub fn match_effort(self, effort: MatchEffort) -> Self {
+        self.set_smatch_config(effort.smatch_cfg())
+    }
+
+    /// Tune source reads and matching for data made of fixed-size pages, such as
+    /// filesystem images or database files.
+    ///
+    /// Source blocks are aligned to `block_size`, and the source is only indexed four times
+    /// per `block_size` bytes, at the same offsets in every page, so matches against whole
+    /// pages are found cheaply even when pages moved around. On low-entropy pages, where
+    /// short strings repeat all over the source, the sparse index also keeps checksums of
+    /// one page from being overwritten by those of every other page. Four checksums per
+    /// page keep hash collisions from losing the page. This is a hint: unaligned matches can
+    /// still be found through the target, and the delta stays valid whatever the data looks
+    /// like.
+    /// Overrides any `set_smatch_config` choice with a soft matcher config.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is not a power of two.
+    pub fn align_to(self, block_size: u32) -> Self {
+        let mut cfg = self.src_block_alignment(u64::from(block_size));
+        let inner = cfg.inner.as_mut();
+        inner.smatch_cfg = binding::xd3_smatch_cfg::XD3_SMATCH_SOFT;
+        // xdelta3's default matcher, except for the source checksum step
+        inner.smatcher_soft.large_look = 9;
+        inner.smatcher_soft.large_step = std::cmp::max(block_size / 4, 1);
+        inner.smatcher_soft.small_look = 4;
+        inner.smatcher_soft.small_chain = 8;
+        inner.smatcher_soft.small_lchain = 2;
+        inner.smatcher_soft.max_lazy = 36;
+        inner.smatcher_soft.long_enough = 70;
+        cfg
+    }
+
+    /// Set the compression level from a `CompressionLevel`.
+    pub fn compression_level(self, level: CompressionLevel) -> Self {
+        self.level(i32::from(level.get()))
+    }
+
+    /// Like `level`, but fails on a level outside 0 to 9 instead of clamping it.
+    pub fn try_level(self, level: i32) -> std::result::Result<Self, Xd3ConfigError> {
+        Ok(self.compression_level(CompressionLevel::new(level)?))
+    }
+
+    /// Set the compression level. Values outside 0 to 9 are clamped; see `try_level` and
+    /// `compression_level` for checked alternatives.
     pub fn level(mut self, mut level: i32) -> Self {
         use binding::xd3_flags::*;
 
@@ -207,91 +1089,2265 @@ impl Xd3Config {
         self.inner.flags = flags;
         self
     }
-}
 
-struct Xd3Stream {
-    inner: Box<binding::xd3_stream>,
-}
-impl Xd3Stream {
-    fn new() -> Self {
-        let inner: binding::xd3_stream = unsafe { std::mem::zeroed() };
-        return Self {
-            inner: Box::new(inner),
-        };
-    }
-}
-impl Drop for Xd3Stream {
-    fn drop(&mut self) {
-        unsafe {
-            binding::xd3_free_stream(self.inner.as_mut() as *mut _);
+    /// Apply every `Some` field of `overrides` on top of `base`, keeping `base` elsewhere.
+    ///
+    /// Meant for layered configuration, e.g. a config file overridden by command-line
+    /// flags. Fields go through the same setters as when set directly, so sizes are still
+    /// rounded up to powers of two.
+    pub fn merge(base: Self, overrides: Xd3ConfigOverride) -> Self {
+        let mut cfg = base;
+        if let Some(winsize) = overrides.window_size {
+            cfg = cfg.window_size(winsize);
+        }
+        if let Some(sprevsz) = overrides.sprev_size {
+            cfg = cfg.sprev_size(sprevsz);
+        }
+        if let Some(iopt_size) = overrides.iopt_size {
+            cfg = cfg.iopt_size(iopt_size);
+        }
+        if let Some(level) = overrides.level {
+            cfg = cfg.level(level);
+        }
+        if let Some(smatch_cfg) = overrides.smatch_cfg {
+            cfg = cfg.set_smatch_config(smatch_cfg);
+        }
+        if let Some(no_compress) = overrides.no_compress {
+            cfg = cfg.no_compress(no_compress);
+        }
+        match overrides.no_secondary_compression {
+            Some(true) => cfg = cfg.no_secondary_compression(),
+            Some(false) => cfg.inner.flags &= !(binding::xd3_flags::XD3_SEC_NOALL as i32),
+            None => {}
+        }
+        if let Some(adler32) = overrides.adler32 {
+            cfg = cfg.adler32(adler32);
         }
+        if let Some(source_window_size) = overrides.source_window_size {
+            cfg = cfg.source_window_size(source_window_size);
+        }
+        // after `window_size`, which it scales, and `source_window_size`, which it replaces
+        if let Some(factor) = overrides.source_window_scaling_factor {
+            cfg = cfg.source_window_scaling_factor(factor);
+        }
+        if let Some(dictionary) = overrides.dictionary {
+            cfg = cfg.dictionary(dictionary);
+        }
+        if let Some(buffer_source) = overrides.buffer_source {
+            cfg = cfg.buffer_source(buffer_source);
+        }
+        if let Some(source_dedup) = overrides.source_dedup {
+            cfg = cfg.source_dedup(source_dedup);
+        }
+        if let Some(alignment) = overrides.src_block_alignment {
+            cfg = cfg.src_block_alignment(alignment);
+        }
+        if let Some(strict_source_window) = overrides.strict_source_window {
+            cfg = cfg.strict_source_window(strict_source_window);
+        }
+        if let Some(deterministic_windows) = overrides.deterministic_windows {
+            cfg = cfg.deterministic_windows(deterministic_windows);
+        }
+        if let Some(input_buffer_size) = overrides.input_buffer_size {
+            cfg = cfg.input_buffer_size(input_buffer_size);
+        }
+        if let Some((min, max)) = overrides.adaptive_winsize {
+            cfg = cfg.adaptive_winsize(min, max);
+        }
+        if let Some(buffer_size) = overrides.output_buffer_size {
+            cfg = cfg.buffer_output(buffer_size);
+        }
+        if let Some(flush_each_window) = overrides.flush_each_window {
+            cfg = cfg.flush_each_window(flush_each_window);
+        }
+        if let Some(header_only) = overrides.header_only {
+            cfg.header_only = header_only;
+        }
+        if let Some(frame_size) = overrides.pad_windows_to {
+            cfg = cfg.pad_windows_to(frame_size);
+        }
+        if let Some(io_buffer_size) = overrides.io_buffer_size {
+            cfg = cfg.io_buffer_size(io_buffer_size);
+        }
+        if let Some(deadline) = overrides.deadline {
+            cfg = cfg.deadline(deadline);
+        }
+        cfg
     }
 }
-unsafe impl Send for Xd3Stream {}
 
-pub async fn decode_async<R1, R2, W>(input: R1, src: R2, out: W) -> io::Result<()>
-where
-    R1: AsyncRead + Unpin,
-    R2: AsyncRead + Unpin,
-    W: AsyncWrite + Unpin,
-{
-    let cfg = Xd3Config::new();
-    process_async(cfg, ProcessMode::Decode, input, src, out).await
-}
+#[cfg(feature = "toml")]
+impl Xd3Config {
+    /// Build a config from TOML, starting from `Xd3Config::new()`.
+    ///
+    /// Keys are the setter names: `window_size`, `sprev_size`, `iopt_size`, `level`,
+    /// `no_compress`, `secondary_compression` (`"none"`, `"djw"`, `"fgk"` or, with the
+    /// `lzma` feature, `"lzma"`), `no_secondary_compression`, `adler32`, `smatch_cfg` (`"default"`,
+    /// `"slow"`, `"fast"`, `"faster"`, `"fastest"` or `"soft"`), `source_window_size`,
+    /// `buffer_source`, `source_dedup`, `src_block_alignment`, `strict_source_window`,
+    /// `deterministic_windows`, `input_buffer_size`, `adaptive_winsize` (a `[min, max]`
+    /// pair), `buffer_output`, `flush_each_window`, `header_only`, `pad_windows_to` and
+    /// `io_buffer_size`. Sizes are integers or strings with a binary `K`, `M` or `G` suffix,
+    /// e.g. `window_size = "8M"`. Unknown keys are rejected.
+    ///
+    /// `dictionary`, `deadline` and `allocator` hold runtime values and can't be set here.
+    pub fn from_toml_str(s: &str) -> std::result::Result<Self, Xd3ConfigError> {
+        let invalid = |msg: String| Xd3ConfigError::InvalidToml(msg);
+        let value: toml::Value = s.parse().map_err(|e| invalid(format!("{}", e)))?;
+        let table = match value {
+            toml::Value::Table(table) => table,
+            _ => return Err(invalid("expected a table".to_owned())),
+        };
 
-pub async fn encode_async<R1, R2, W>(input: R1, src: R2, out: W) -> io::Result<()>
-where
-    R1: AsyncRead + Unpin,
-    R2: AsyncRead + Unpin,
+        let mut cfg = Self::new();
+        for (key, value) in &table {
+            let size = || toml_size(key, value);
+            let flag = || {
+                value
+                    .as_bool()
+                    .ok_or_else(|| invalid(format!("{}: expected a boolean", key)))
+            };
+            cfg =
+                match key.as_str() {
+                    "window_size" => cfg.window_size(narrow_size(key, size()?)?),
+                    "sprev_size" => cfg.sprev_size(narrow_size(key, size()?)?),
+                    "iopt_size" => cfg.iopt_size(narrow_size(key, size()?)?),
+                    "level" => {
+                        let level = value
+                            .as_integer()
+                            .ok_or_else(|| invalid(format!("{}: expected an integer", key)))?;
+                        let level = std::convert::TryFrom::try_from(level)
+                            .unwrap_or(if level < 0 { i32::MIN } else { i32::MAX });
+                        cfg.try_level(level)?
+                    }
+                    "no_compress" => cfg.no_compress(flag()?),
+                    "secondary_compression" => {
+                        let name = value.as_str().unwrap_or_default();
+                        let compressor = secondary_compressor_from_name(name).ok_or_else(|| {
+                            invalid(format!("{}: unknown compressor {:?}", key, value))
+                        })?;
+                        cfg.secondary_compression(compressor)
+                    }
+                    "no_secondary_compression" => {
+                        if flag()? {
+                            cfg.no_secondary_compression()
+                        } else {
+                            cfg.inner.flags &= !(binding::xd3_flags::XD3_SEC_NOALL as i32);
+                            cfg
+                        }
+                    }
+                    "adler32" => cfg.adler32(flag()?),
+                    "smatch_cfg" => {
+                        let name = value.as_str().unwrap_or_default();
+                        let smatch_cfg = smatch_cfg_from_name(name).ok_or_else(|| {
+                            invalid(format!("{}: unknown matcher {:?}", key, value))
+                        })?;
+                        cfg.set_smatch_config(smatch_cfg)
+                    }
+                    "source_window_size" => cfg.source_window_size(size()?),
+                    "buffer_source" => cfg.buffer_source(flag()?),
+                    "source_dedup" => cfg.source_dedup(flag()?),
+                    "src_block_alignment" => {
+                        let alignment = size()?;
+                        if !alignment.is_power_of_two() {
+                            return Err(invalid(format!("{}: must be a power of two", key)));
+                        }
+                        cfg.src_block_alignment(alignment)
+                    }
+                    "strict_source_window" => cfg.strict_source_window(flag()?),
+                    "deterministic_windows" => cfg.deterministic_windows(flag()?),
+                    "input_buffer_size" => cfg.input_buffer_size(narrow_size(key, size()?)?),
+                    "adaptive_winsize" => {
+                        let range = match value.as_array().map(Vec::as_slice) {
+                            Some([min, max]) => (toml_size(key, min)?, toml_size(key, max)?),
+                            _ => return Err(invalid(format!("{}: expected [min, max]", key))),
+                        };
+                        let (min, max) = (narrow_size(key, range.0)?, narrow_size(key, range.1)?);
+                        if min > max {
+                            return Err(invalid(format!("{}: min is greater than max", key)));
+                        }
+                        cfg.adaptive_winsize(min, max)
+                    }
+                    "buffer_output" => cfg.buffer_output(narrow_size(key, size()?)?),
+                    "flush_each_window" => cfg.flush_each_window(flag()?),
+                    "header_only" => {
+                        cfg.header_only = flag()?;
+                        cfg
+                    }
+                    "pad_windows_to" => {
+                        let frame_size = narrow_size(key, size()?)?;
+                        if frame_size == 0 {
+                            return Err(invalid(format!("{}: must not be 0", key)));
+                        }
+                        cfg.pad_windows_to(frame_size)
+                    }
+                    "io_buffer_size" => cfg.io_buffer_size(narrow_size(key, size()?)?),
+                    _ => return Err(invalid(format!("unknown key {:?}", key))),
+                };
+        }
+        Ok(cfg)
+    }
+
+    /// Serialize the settings `from_toml_str` understands.
+    ///
+    /// A `source_window_scaling_factor` is written as the `source_window_size` it gave.
+    pub fn to_toml_string(&self) -> String {
+        use binding::xd3_flags::*;
+        use toml::Value;
+
+        let size = |size: u64| Value::String(format_size(size));
+        let flags = self.inner.flags;
+        let level = (flags & XD3_COMPLEVEL_MASK as i32) >> XD3_COMPLEVEL_SHIFT as i32;
+
+        let mut table = toml::value::Table::new();
+        let mut set = |key: &str, value: Value| {
+            table.insert(key.to_owned(), value);
+        };
+        set("window_size", size(u64::from(self.inner.winsize)));
+        set("sprev_size", size(u64::from(self.inner.sprevsz)));
+        set("iopt_size", size(u64::from(self.inner.iopt_size)));
+        set("level", Value::Integer(i64::from(level)));
+        set(
+            "no_compress",
+            Value::Boolean(flags & XD3_NOCOMPRESS as i32 != 0),
+        );
+        set(
+            "secondary_compression",
+            Value::String(secondary_compressor_name(flags).to_owned()),
+        );
+        set(
+            "no_secondary_compression",
+            Value::Boolean(flags & XD3_SEC_NOALL as i32 == XD3_SEC_NOALL as i32),
+        );
+        set("adler32", Value::Boolean(flags & XD3_ADLER32 as i32 != 0));
+        set(
+            "smatch_cfg",
+            Value::String(smatch_cfg_name(self.inner.smatch_cfg).to_owned()),
+        );
+        set("source_window_size", size(self.source_window_size));
+        set("buffer_source", Value::Boolean(self.buffer_source));
+        set("source_dedup", Value::Boolean(self.source_dedup));
+        set("src_block_alignment", size(self.src_block_alignment));
+        set(
+            "strict_source_window",
+            Value::Boolean(self.strict_source_window),
+        );
+        set(
+            "deterministic_windows",
+            Value::Boolean(self.deterministic_windows),
+        );
+        if let Some(input_buffer_size) = self.input_buffer_size {
+            set("input_buffer_size", size(input_buffer_size as u64));
+        }
+        if let Some((min, max)) = self.adaptive_winsize {
+            let range = vec![size(u64::from(min)), size(u64::from(max))];
+            set("adaptive_winsize", Value::Array(range));
+        }
+        if let Some(output_buffer_size) = self.output_buffer_size {
+            set("buffer_output", size(output_buffer_size as u64));
+        }
+        set("flush_each_window", Value::Boolean(self.flush_each_window));
+        set("header_only", Value::Boolean(self.header_only));
+        if let Some(frame_size) = self.window_padding {
+            set("pad_windows_to", size(u64::from(frame_size)));
+        }
+        set("io_buffer_size", size(self.io_buffer_size as u64));
+
+        toml::to_string(&Value::Table(table)).expect("a flat table always serializes")
+    }
+}
+
+#[cfg(feature = "toml")]
+fn toml_size(key: &str, value: &toml::Value) -> std::result::Result<u64, Xd3ConfigError> {
+    let invalid = || {
+        Xd3ConfigError::InvalidToml(format!(
+            "{}: expected a size such as 65536 or \"64K\", got {}",
+            key, value
+        ))
+    };
+    match value {
+        toml::Value::Integer(size) if *size >= 0 => Ok(*size as u64),
+        toml::Value::String(size) => {
+            let size = size.trim();
+            let (digits, shift) = match size.chars().last() {
+                Some('K') | Some('k') => (&size[..size.len() - 1], 10),
+                Some('M') | Some('m') => (&size[..size.len() - 1], 20),
+                Some('G') | Some('g') => (&size[..size.len() - 1], 30),
+                _ => (size, 0),
+            };
+            let n: u64 = digits.trim().parse().map_err(|_| invalid())?;
+            n.checked_mul(1 << shift).ok_or_else(invalid)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(feature = "toml")]
+fn narrow_size<T>(key: &str, size: u64) -> std::result::Result<T, Xd3ConfigError>
+where
+    T: std::convert::TryFrom<u64>,
+{
+    T::try_from(size)
+        .map_err(|_| Xd3ConfigError::InvalidToml(format!("{}: {} is too large", key, size)))
+}
+
+#[cfg(feature = "toml")]
+fn format_size(size: u64) -> String {
+    for &(shift, suffix) in &[(30, "G"), (20, "M"), (10, "K")] {
+        if size != 0 && size % (1 << shift) == 0 {
+            return format!("{}{}", size >> shift, suffix);
+        }
+    }
+    size.to_string()
+}
+
+#[cfg(feature = "toml")]
+fn smatch_cfg_from_name(name: &str) -> Option<binding::xd3_smatch_cfg> {
+    use binding::xd3_smatch_cfg::*;
+    Some(match name {
+        "default" => XD3_SMATCH_DEFAULT,
+        "slow" => XD3_SMATCH_SLOW,
+        "fast" => XD3_SMATCH_FAST,
+        "faster" => XD3_SMATCH_FASTER,
+        "fastest" => XD3_SMATCH_FASTEST,
+        "soft" => XD3_SMATCH_SOFT,
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "toml")]
+fn secondary_compressor_from_name(name: &str) -> Option<Option<SecondaryCompressor>> {
+    Some(match name {
+        "none" => None,
+        "djw" => Some(SecondaryCompressor::Djw),
+        "fgk" => Some(SecondaryCompressor::Fgk),
+        #[cfg(feature = "lzma")]
+        "lzma" => Some(SecondaryCompressor::Lzma),
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "toml")]
+fn secondary_compressor_name(flags: i32) -> &'static str {
+    use binding::xd3_flags::*;
+    match flags & XD3_SEC_TYPE as i32 {
+        f if f == XD3_SEC_DJW as i32 => "djw",
+        f if f == XD3_SEC_FGK as i32 => "fgk",
+        f if f == XD3_SEC_LZMA as i32 => "lzma",
+        _ => "none",
+    }
+}
+
+#[cfg(feature = "toml")]
+fn smatch_cfg_name(smatch_cfg: binding::xd3_smatch_cfg) -> &'static str {
+    use binding::xd3_smatch_cfg::*;
+    match smatch_cfg {
+        XD3_SMATCH_DEFAULT => "default",
+        XD3_SMATCH_SLOW => "slow",
+        XD3_SMATCH_FAST => "fast",
+        XD3_SMATCH_FASTER => "faster",
+        XD3_SMATCH_FASTEST => "fastest",
+        XD3_SMATCH_SOFT => "soft",
+    }
+}
+
+/// How much work the string matcher puts into each window, for `Xd3Config::match_effort`.
+///
+/// Each preset is one of xdelta3's built-in matcher configurations, which bound the hash
+/// chain lengths searched and when a match counts as long enough to stop looking. Lower
+/// effort makes the time per window shorter and more predictable, at the cost of a
+/// larger delta.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MatchEffort {
+    /// Long chains and lazy matching (`XD3_SMATCH_SLOW`).
+    Exhaustive,
+    /// The matcher the compression level selects (`XD3_SMATCH_DEFAULT`); see
+    /// `CompressionLevel`.
+    Balanced,
+    /// Very short chains (`XD3_SMATCH_FASTER`).
+    Fast,
+}
+
+impl MatchEffort {
+    fn smatch_cfg(self) -> binding::xd3_smatch_cfg {
+        use binding::xd3_smatch_cfg::*;
+        match self {
+            MatchEffort::Exhaustive => XD3_SMATCH_SLOW,
+            MatchEffort::Balanced => XD3_SMATCH_DEFAULT,
+            MatchEffort::Fast => XD3_SMATCH_FASTER,
+        }
+    }
+}
+
+/// A secondary compressor, for `Xd3Config::secondary_compression`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SecondaryCompressor {
+    /// Static Huffman coding with several tables (`XD3_SEC_DJW`).
+    Djw,
+    /// Adaptive Huffman coding (`XD3_SEC_FGK`).
+    Fgk,
+    /// LZMA (`XD3_SEC_LZMA`), only with the `lzma` feature.
+    #[cfg(feature = "lzma")]
+    Lzma,
+}
+
+/// A compression level from 0 to 9.
+///
+/// Unless a matcher is chosen explicitly (`match_effort`, `set_smatch_config`, `align_to`),
+/// xdelta3 picks its string matcher from the level: 1 is the fastest, 2 faster, 3 to 5
+/// fast, 6 the default and 7 to 9 slow. Level 0 keeps the default matcher. Slower matchers
+/// index the source more densely and find shorter and more distant matches. The level also
+/// sets the preset of LZMA secondary compression, when that is in use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompressionLevel(u8);
+
+impl CompressionLevel {
+    pub const FASTEST: Self = Self(1);
+    /// The xdelta3 command line's default, `-3`.
+    pub const DEFAULT: Self = Self(3);
+    pub const BEST: Self = Self(9);
+
+    /// Fails with `LevelOutOfRange` unless `level` is between 0 and 9.
+    pub fn new(level: i32) -> std::result::Result<Self, Xd3ConfigError> {
+        if (0..=9).contains(&level) {
+            Ok(Self(level as u8))
+        } else {
+            Err(Xd3ConfigError::LevelOutOfRange { level })
+        }
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl Default for CompressionLevel {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Optional overrides for `Xd3Config::merge`, one field per `Xd3Config` setting.
+///
+/// Setters that are shorthands for these (`window_size_bits`, `sprevsz_bits`,
+/// `source_window_size_bits`, `compression_level`, `try_level`, `match_effort`,
+/// `no_primary_compression`, `encode_header_only`) and the presets built on them have no
+/// field of their own, and neither does the unsafe `allocator`.
+#[derive(Clone, Debug, Default)]
+pub struct Xd3ConfigOverride {
+    pub window_size: Option<u32>,
+    pub sprev_size: Option<u32>,
+    pub iopt_size: Option<u32>,
+    pub level: Option<i32>,
+    pub smatch_cfg: Option<binding::xd3_smatch_cfg>,
+    pub no_compress: Option<bool>,
+    pub no_secondary_compression: Option<bool>,
+    pub adler32: Option<bool>,
+    pub source_window_size: Option<u64>,
+    pub source_window_scaling_factor: Option<f64>,
+    pub dictionary: Option<Vec<u8>>,
+    pub buffer_source: Option<bool>,
+    pub source_dedup: Option<bool>,
+    pub src_block_alignment: Option<u64>,
+    pub strict_source_window: Option<bool>,
+    pub deterministic_windows: Option<bool>,
+    pub input_buffer_size: Option<usize>,
+    pub adaptive_winsize: Option<(u32, u32)>,
+    pub output_buffer_size: Option<usize>,
+    pub flush_each_window: Option<bool>,
+    pub header_only: Option<bool>,
+    pub pad_windows_to: Option<u32>,
+    pub io_buffer_size: Option<usize>,
+    pub deadline: Option<std::time::Instant>,
+}
+
+struct Xd3Stream {
+    inner: Box<binding::xd3_stream>,
+}
+impl Xd3Stream {
+    /// An all-zero stream, which is what `xd3_config_stream` expects to start from. It must
+    /// be configured with `xd3_config_stream` before being handed to any other xdelta3
+    /// function, except `xd3_free_stream` when dropped.
+    fn new() -> Self {
+        // SAFETY: `xd3_stream` is a plain C struct. All-zero bytes are a valid value for
+        // every field bindgen generates for it: integers, raw pointers, `Option` function
+        // pointers, and the `xd3_encode_state`/`xd3_decode_state` enums, whose zero
+        // variants are `ENC_INIT` and `DEC_VCHEAD`. The stream's own invariants are set up
+        // by `xd3_config_stream`. Freeing a zeroed stream is fine too: `xd3_free_stream`
+        // skips null buffers.
+        let inner = unsafe { std::mem::MaybeUninit::<binding::xd3_stream>::zeroed().assume_init() };
+        Self {
+            inner: Box::new(inner),
+        }
+    }
+}
+impl Drop for Xd3Stream {
+    fn drop(&mut self) {
+        unsafe {
+            binding::xd3_free_stream(self.inner.as_mut() as *mut _);
+        }
+    }
+}
+unsafe impl Send for Xd3Stream {}
+
+pub async fn decode_async<R1, R2, W>(input: R1, src: R2, out: W) -> io::Result<()>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let cfg = Xd3Config::new();
+    process_async(cfg, ProcessMode::Decode, input, src, out).await
+}
+
+/// Like `decode_async`, calling `progress` with the total number of bytes written to `out`
+/// after every decoded window.
+pub async fn decode_async_with_progress<R1, R2, W, F>(
+    input: R1,
+    src: R2,
+    out: W,
+    mut progress: F,
+) -> io::Result<()>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnMut(u64),
+{
+    let mode = ProcessMode::Decode;
+    let res = async {
+        let (cfg, input) = fit_source_window_to_delta(Xd3Config::new(), input).await?;
+        let mut state = ProcessState::new(cfg, src)?;
+        drive_async(&mut state, mode, input, out, |stats| {
+            progress(stats.output_bytes)
+        })
+        .await
+    };
+    res.await.map_err(|e| mode.annotate(e))
+}
+
+pub async fn encode_async<R1, R2, W>(input: R1, src: R2, out: W) -> io::Result<()>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let cfg = Xd3Config::new();
+    process_async(cfg, ProcessMode::Encode, input, src, out).await
+}
+
+/// Like `encode_async` with `cfg`, guaranteeing that each chunk of output reaches `output`
+/// as soon as xdelta3 produces it.
+///
+/// Nothing is buffered between xdelta3 and `output`: `buffer_output` is ignored and the
+/// output is flushed after every chunk, as with `flush_each_window`. A peer reading from
+/// `output` sees every window when it is encoded, not when the whole input is done.
+pub async fn encode_to_writer_async<R1, R2, W>(
+    cfg: Xd3Config,
+    input: R1,
+    src: R2,
+    output: W,
+) -> io::Result<()>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut cfg = cfg.flush_each_window(true);
+    cfg.output_buffer_size = None;
+    process_async(cfg, ProcessMode::Encode, input, src, output).await
+}
+
+/// Encode only the first window of `input`, returning as soon as xdelta3 finishes it.
+///
+/// The result is a complete delta, file header included, that decodes to the first
+/// `window_size` bytes of `input` (or all of it, if shorter). Input beyond the first
+/// window is not read further once the window is done. Useful for protocols that want to
+/// send something before the rest of the input has arrived.
+pub async fn encode_first_window_async<R1, R2>(
+    cfg: Xd3Config,
+    input: R1,
+    src: R2,
+) -> io::Result<Vec<u8>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+{
+    let mode = ProcessMode::Encode;
+    let res = async {
+        let mut state = ProcessState::new(cfg, src)?;
+        let mut out = Vec::new();
+        drive_async_until(
+            &mut state,
+            mode,
+            input,
+            &mut out,
+            |_| ControlFlow::Break(()),
+        )
+        .await?;
+        Ok(out)
+    };
+    res.await.map_err(|e| mode.annotate(e))
+}
+
+/// Like `encode_async`, with the input and the source arriving as streams of byte chunks,
+/// e.g. `Bytes` from upstream producers.
+///
+/// The streams are polled only when xdelta3 asks for more data: the input when the current
+/// window needs filling, the source when a block is requested. Only one of them is awaited
+/// at a time, so a slow source stalls the encode instead of letting input pile up, and
+/// neither producer can deadlock waiting on the other. Beyond the source window cache, at
+/// most one partially consumed chunk per stream is held in memory.
+pub async fn encode_async_streams<S1, S2, B1, B2, W>(input: S1, src: S2, out: W) -> io::Result<()>
+where
+    S1: futures_util::stream::Stream<Item = io::Result<B1>> + Unpin,
+    S2: futures_util::stream::Stream<Item = io::Result<B2>> + Unpin,
+    B1: AsRef<[u8]>,
+    B2: AsRef<[u8]>,
+    W: AsyncWrite + Unpin,
+{
+    use futures_util::stream::TryStreamExt;
+
+    encode_async(input.into_async_read(), src.into_async_read(), out).await
+}
+
+/// Like `encode_async`, but identical source blocks share one buffer in the source cache.
+pub async fn encode_with_source_dedup<R1, R2, W>(input: R1, src: R2, out: W) -> io::Result<()>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let cfg = Xd3Config::new().source_dedup(true);
+    process_async(cfg, ProcessMode::Encode, input, src, out).await
+}
+
+/// Byte and window counters collected while processing.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "camelCase")
+)]
+pub struct EncodeStats {
+    /// Bytes consumed from the input.
+    pub input_bytes: u64,
+    /// Bytes read from the source.
+    pub source_bytes: u64,
+    /// Bytes written to the output.
+    pub output_bytes: u64,
+    /// Number of windows completed.
+    pub windows_processed: u64,
+    /// Block cache counters for the source.
+    pub source_stats: SrcStats,
+}
+
+/// Source block cache counters.
+///
+/// `blocks_requested - blocks_read` is the number of requests served from the cache.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "camelCase")
+)]
+pub struct SrcStats {
+    /// Blocks xdelta3 asked for.
+    pub blocks_requested: u64,
+    /// Blocks read from the source reader.
+    pub blocks_read: u64,
+    /// Blocks dropped from the cache to make room for new ones.
+    pub blocks_evicted: u64,
+    /// Blocks that share storage with an identical cached block (see `source_dedup`).
+    pub blocks_shared: u64,
+    /// Evicted blocks read again because xdelta3 went back to them (see
+    /// `strict_source_window`). Included in `blocks_read`.
+    pub blocks_refetched: u64,
+}
+
+/// Adds every counter, saturating at `u64::MAX`, to total up a batch of encodes.
+impl std::ops::AddAssign<&EncodeStats> for EncodeStats {
+    fn add_assign(&mut self, other: &EncodeStats) {
+        self.input_bytes = self.input_bytes.saturating_add(other.input_bytes);
+        self.source_bytes = self.source_bytes.saturating_add(other.source_bytes);
+        self.output_bytes = self.output_bytes.saturating_add(other.output_bytes);
+        self.windows_processed = self
+            .windows_processed
+            .saturating_add(other.windows_processed);
+        self.source_stats += &other.source_stats;
+    }
+}
+
+impl std::iter::Sum for EncodeStats {
+    fn sum<I: Iterator<Item = EncodeStats>>(iter: I) -> Self {
+        iter.fold(EncodeStats::default(), |mut total, stats| {
+            total += &stats;
+            total
+        })
+    }
+}
+
+impl std::ops::AddAssign<&SrcStats> for SrcStats {
+    fn add_assign(&mut self, other: &SrcStats) {
+        self.blocks_requested = self.blocks_requested.saturating_add(other.blocks_requested);
+        self.blocks_read = self.blocks_read.saturating_add(other.blocks_read);
+        self.blocks_evicted = self.blocks_evicted.saturating_add(other.blocks_evicted);
+        self.blocks_shared = self.blocks_shared.saturating_add(other.blocks_shared);
+        self.blocks_refetched = self.blocks_refetched.saturating_add(other.blocks_refetched);
+    }
+}
+
+impl EncodeStats {
+    /// Output bytes per input byte, or 0 if no input was consumed.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.input_bytes == 0 {
+            return 0.0;
+        }
+        self.output_bytes as f64 / self.input_bytes as f64
+    }
+
+    /// Serialize to a camelCase JSON object, including `compressionRatio`.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        #[derive(serde::Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Json<'a> {
+            #[serde(flatten)]
+            stats: &'a EncodeStats,
+            compression_ratio: f64,
+        }
+
+        let json = Json {
+            stats: self,
+            compression_ratio: self.compression_ratio(),
+        };
+        serde_json::to_string(&json).expect("stats always serialize")
+    }
+
+    /// Parse the output of `to_json`. Computed fields such as `compressionRatio` are
+    /// ignored.
+    #[cfg(feature = "serde")]
+    pub fn from_json(s: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(s)
+    }
+}
+
+/// Progress of `encode_files_with_progress`, reported after every window.
+#[derive(Clone, Debug)]
+pub struct FileProgressInfo {
+    pub new_bytes_read: u64,
+    pub new_total: u64,
+    pub old_bytes_read: u64,
+    pub old_total: u64,
+    pub patch_bytes_written: u64,
+}
+
+/// Blocking counterpart of `process_async` for `std::io` readers and writers.
+pub fn process<R1, R2, W>(
+    cfg: Xd3Config,
+    mode: ProcessMode,
+    input: R1,
+    src: R2,
+    output: W,
+) -> io::Result<()>
+where
+    R1: io::Read,
+    R2: io::Read,
+    W: io::Write,
+{
+    block_on_sync(process_async(
+        cfg,
+        mode,
+        AllowStdIo::new(input),
+        AllowStdIo::new(src),
+        AllowStdIo::new(output),
+    ))
+}
+
+/// General-purpose compressor applied on top of a VCDIFF delta by `encode_compressed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OuterCodec {
+    /// Plain VCDIFF, no outer compression.
+    None,
+    /// gzip at the default level.
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// zstd at the default level.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Encode `input` against `src` and compress the delta with `outer` in the same pass.
+///
+/// The encoder writes straight into the compressor, so the uncompressed delta is never
+/// buffered as a whole. The result is not a VCDIFF delta: undo the outer codec (e.g. with
+/// `flate2::read::GzDecoder` or `zstd::decode_all`) before handing it to `decode`.
+pub fn encode_compressed<R1, R2>(
+    input: R1,
+    src: R2,
+    outer: OuterCodec,
+    cfg: Xd3Config,
+) -> io::Result<Vec<u8>>
+where
+    R1: io::Read,
+    R2: io::Read,
+{
+    match outer {
+        OuterCodec::None => {
+            let mut out = Vec::new();
+            process(cfg, ProcessMode::Encode, input, src, &mut out)?;
+            Ok(out)
+        }
+        #[cfg(feature = "gzip")]
+        OuterCodec::Gzip => {
+            let mut out = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            process(cfg, ProcessMode::Encode, input, src, &mut out)?;
+            out.finish()
+        }
+        #[cfg(feature = "zstd")]
+        OuterCodec::Zstd => {
+            let mut out = zstd::stream::write::Encoder::new(Vec::new(), 0)?;
+            process(cfg, ProcessMode::Encode, input, src, &mut out)?;
+            out.finish()
+        }
+    }
+}
+
+/// Result of `encode_smallest`: whichever of the delta and the raw target is smaller.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeltaOrRaw {
+    /// A VCDIFF delta against the source.
+    Delta(Vec<u8>),
+    /// The target itself, because no delta was smaller.
+    Raw(Vec<u8>),
+}
+
+impl DeltaOrRaw {
+    /// Rebuild the target, decoding against `src` if this is a delta.
+    pub fn apply(&self, src: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            DeltaOrRaw::Delta(delta) => {
+                let mut out = Vec::new();
+                process(
+                    Xd3Config::new(),
+                    ProcessMode::Decode,
+                    &delta[..],
+                    src,
+                    &mut out,
+                )?;
+                Ok(out)
+            }
+            DeltaOrRaw::Raw(data) => Ok(data.clone()),
+        }
+    }
+}
+
+/// Encode `input` against `src` with `Xd3Config::compact_header`, falling back to storing
+/// `input` as is when the delta is not smaller.
+///
+/// The caller has to record which variant it got, e.g. in a flag byte, to know whether
+/// to decode on the way back.
+pub fn encode_smallest(input: &[u8], src: &[u8]) -> io::Result<DeltaOrRaw> {
+    let mut delta = Vec::new();
+    process(
+        Xd3Config::new().compact_header(input.len()),
+        ProcessMode::Encode,
+        input,
+        src,
+        &mut delta,
+    )?;
+    if delta.len() < input.len() {
+        Ok(DeltaOrRaw::Delta(delta))
+    } else {
+        Ok(DeltaOrRaw::Raw(input.to_vec()))
+    }
+}
+
+/// Like `process`, but hands each chunk of output to `on_output` as `Bytes`.
+///
+/// Every chunk is what xdelta3 emitted in one go, normally a whole window (the first one
+/// also carries the file header), copied out of the stream's buffer exactly once. Handy
+/// for transports that take refcounted buffers, such as `hyper` bodies or broadcast
+/// channels, since clones of a chunk share its storage.
+#[cfg(feature = "bytes")]
+pub fn process_bytes<R1, R2, F>(
+    cfg: Xd3Config,
+    mode: ProcessMode,
+    input: R1,
+    src: R2,
+    on_output: F,
+) -> io::Result<()>
+where
+    R1: io::Read,
+    R2: io::Read,
+    F: FnMut(bytes::Bytes),
+{
+    struct BytesSink<F>(F);
+
+    impl<F: FnMut(bytes::Bytes)> io::Write for BytesSink<F> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !buf.is_empty() {
+                (self.0)(bytes::Bytes::copy_from_slice(buf));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    process(cfg, mode, input, src, BytesSink(on_output))
+}
+
+/// A region of the expected target: `len` bytes at `offset` whose checksum, as computed by
+/// `checksum::checksum` with the manifest's hasher, is `hash`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub offset: u64,
+    pub len: u64,
+    pub hash: u64,
+}
+
+/// Expected checksums of regions of a target, computed with the `checksum::Hasher` `H`.
+///
+/// Regions may come in any order and may overlap; target bytes outside every region are
+/// not checked.
+pub struct Manifest<H> {
+    // sorted by offset
+    entries: Arc<[ManifestEntry]>,
+    hasher: std::marker::PhantomData<fn() -> H>,
+}
+
+impl<H: checksum::Hasher + Default> Manifest<H> {
+    pub fn new(entries: Vec<ManifestEntry>) -> Self {
+        let mut entries = entries;
+        entries.sort_by_key(|entry| entry.offset);
+        Self {
+            entries: entries.into(),
+            hasher: std::marker::PhantomData,
+        }
+    }
+
+    pub fn entries(&self) -> &[ManifestEntry] {
+        &self.entries
+    }
+}
+
+impl<H> Clone for Manifest<H> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            hasher: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<H> fmt::Debug for Manifest<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Manifest").field(&self.entries).finish()
+    }
+}
+
+/// Blocking counterpart of `decode_with_manifest_async` for `std::io` readers and writers.
+pub fn decode_with_manifest<H, R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    manifest: &Manifest<H>,
+) -> io::Result<()>
+where
+    H: checksum::Hasher + Default,
+    R1: io::Read,
+    R2: io::Read,
+    W: io::Write,
+{
+    block_on_sync(decode_with_manifest_async(
+        AllowStdIo::new(input),
+        AllowStdIo::new(src),
+        AllowStdIo::new(out),
+        manifest,
+    ))
+}
+
+/// Decode `input` against `src` into `out`, checking the target against `manifest` as it
+/// is produced.
+///
+/// Each chunk of output is checked before it is written: if a region that ends within it
+/// has the wrong checksum, decoding stops with `Xd3Error::ManifestMismatch` for the first
+/// such region, and neither that chunk nor anything after it reaches `out`. Bytes of a
+/// region that spans several windows may already be written by then. A region that
+/// extends past the end of the target fails the same way once decoding is done. Every
+/// target byte is hashed once, however many writes it takes `out` to accept it.
+pub async fn decode_with_manifest_async<H, R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    manifest: &Manifest<H>,
+) -> io::Result<()>
+where
+    H: checksum::Hasher + Default,
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut out = ManifestWriter {
+        inner: out,
+        regions: ManifestRegions::<H> {
+            entries: manifest.entries.clone(),
+            next: 0,
+            active: Vec::new(),
+            pos: 0,
+        },
+        unwritten: 0,
+    };
+    decode_async(input, src, &mut out).await?;
+
+    let regions = &out.regions;
+    let unfinished = regions
+        .active
+        .first()
+        .map(|(entry, _)| entry)
+        .or_else(|| regions.entries.get(regions.next));
+    match unfinished {
+        Some(entry) => Err(manifest_mismatch(entry)),
+        None => Ok(()),
+    }
+}
+
+fn manifest_mismatch(entry: &ManifestEntry) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        Xd3Error::ManifestMismatch {
+            offset: entry.offset,
+            len: entry.len,
+        },
+    )
+}
+
+/// Progress through the target for `decode_with_manifest_async`.
+struct ManifestRegions<H> {
+    // sorted by offset
+    entries: Arc<[ManifestEntry]>,
+    // first entry that hasn't started yet
+    next: usize,
+    // started but not finished, in offset order
+    active: Vec<(ManifestEntry, H)>,
+    // target bytes seen so far
+    pos: u64,
+}
+
+impl<H: checksum::Hasher + Default> ManifestRegions<H> {
+    /// Hash the next `data` bytes of the target into every region they overlap, returning
+    /// the first region finished by them whose checksum doesn't match.
+    fn feed(&mut self, data: &[u8]) -> Option<ManifestEntry> {
+        let start = self.pos;
+        let end = start + data.len() as u64;
+        while self.next < self.entries.len() && self.entries[self.next].offset < end {
+            self.active.push((self.entries[self.next], H::default()));
+            self.next += 1;
+        }
+
+        let mut mismatch = None;
+        let mut i = 0;
+        while i < self.active.len() {
+            let (entry, hasher) = &mut self.active[i];
+            let region_end = entry.offset.saturating_add(entry.len);
+            let from = std::cmp::max(entry.offset, start);
+            let to = std::cmp::min(region_end, end);
+            if from < to {
+                hasher.update(&data[(from - start) as usize..(to - start) as usize]);
+            }
+            if region_end <= end {
+                if mismatch.is_none() && hasher.finish() != entry.hash {
+                    mismatch = Some(*entry);
+                }
+                self.active.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        self.pos = end;
+        mismatch
+    }
+}
+
+/// Writer checking everything written through it against a manifest.
+struct ManifestWriter<H, W> {
+    inner: W,
+    regions: ManifestRegions<H>,
+    // bytes of the last chunk already checked (and fed into `regions`) but not yet
+    // accepted by `inner`; a writer retrying after `Pending` or a short write passes the
+    // rest of that same chunk, as `write_all` does
+    unwritten: usize,
+}
+
+// the hashers are never pinned
+impl<H, W: Unpin> Unpin for ManifestWriter<H, W> {}
+
+impl<H, W> AsyncWrite for ManifestWriter<H, W>
+where
+    H: checksum::Hasher + Default,
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.unwritten == 0 {
+            // check the whole chunk up front, so that nothing of it is written on a mismatch
+            if let Some(entry) = this.regions.feed(buf) {
+                return std::task::Poll::Ready(Err(manifest_mismatch(&entry)));
+            }
+            this.unwritten = buf.len();
+        }
+
+        let len = std::cmp::min(buf.len(), this.unwritten);
+        let res = std::pin::Pin::new(&mut this.inner).poll_write(cx, &buf[..len]);
+        if let std::task::Poll::Ready(Ok(written)) = res {
+            this.unwritten -= written;
+        }
+        res
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Like `process` in encode mode, but decodes the delta again and checks that it gives
+/// back the input before writing it to `out`.
+///
+/// Meant for development and tests, not production: the delta and its decoded output are
+/// held in memory, and the input and source are read twice (both are rewound to where
+/// they started). A mismatch fails with `Xd3Error::VerificationFailed` and nothing is
+/// written. Only encodes can be verified, so `ProcessMode::Decode` fails with
+/// `InvalidInput`.
+pub fn process_verify<R1, R2, W>(
+    cfg: Xd3Config,
+    mode: ProcessMode,
+    mut input: R1,
+    mut src: R2,
+    mut out: W,
+) -> io::Result<EncodeStats>
+where
+    R1: io::Read + io::Seek,
+    R2: io::Read + io::Seek,
+    W: io::Write,
+{
+    if mode != ProcessMode::Encode {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "process_verify only verifies encodes",
+        ));
+    }
+    let input_start = input.seek(io::SeekFrom::Current(0))?;
+    let src_start = src.seek(io::SeekFrom::Current(0))?;
+
+    let mut delta = Vec::new();
+    let stats = block_on_sync(run_async(
+        cfg.clone(),
+        mode,
+        AllowStdIo::new(&mut input),
+        AllowStdIo::new(&mut src),
+        AllowStdIo::new(&mut delta),
+    ))
+    .map_err(|e| mode.annotate(e))?;
+
+    src.seek(io::SeekFrom::Start(src_start))?;
+    let mut decoded = Vec::new();
+    process(cfg, ProcessMode::Decode, &delta[..], &mut src, &mut decoded)?;
+
+    input.seek(io::SeekFrom::Start(input_start))?;
+    let mut at = 0;
+    let mut buf = vec![0u8; DEFAULT_IO_BUFFER_SIZE];
+    let mismatch = loop {
+        let n = input.read(&mut buf)?;
+        let expected = &decoded[at..std::cmp::min(at + n, decoded.len())];
+        if let Some(i) = buf.iter().zip(expected).position(|(a, b)| a != b) {
+            break Some(at + i);
+        }
+        at += expected.len();
+        if expected.len() < n {
+            // the input is longer than the decoded output
+            break Some(at);
+        }
+        if n == 0 {
+            break if at < decoded.len() { Some(at) } else { None };
+        }
+    };
+    if let Some(at_byte) = mismatch {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            Xd3Error::VerificationFailed {
+                at_byte: at_byte as u64,
+            },
+        ));
+    }
+
+    out.write_all(&delta)?;
+    out.flush()?;
+    Ok(stats)
+}
+
+/// Like `process`, but hands the reader and writers back when done.
+///
+/// Handy for getting the buffer out of an output `Cursor`, or for reusing the files.
+pub fn process_owned<R1, R2, W>(
+    cfg: Xd3Config,
+    mode: ProcessMode,
+    mut input: R1,
+    mut src: R2,
+    mut output: W,
+) -> io::Result<(R1, R2, W)>
+where
+    R1: io::Read,
+    R2: io::Read,
+    W: io::Write,
+{
+    process(cfg, mode, &mut input, &mut src, &mut output)?;
+    Ok((input, src, output))
+}
+
+/// Largest source window `encode_adaptive` grows to (4 GiB).
+pub const ADAPTIVE_MAX_SRCWINSZ: u64 = 1 << 32;
+
+/// Encode `input`, doubling the source window and starting over whenever the window turns
+/// out to be too small for the matches xdelta3 wants to make.
+///
+/// `src_factory` is called for a fresh source reader on every attempt, since a consumed
+/// reader can't be rewound. Gives up once the window would exceed `ADAPTIVE_MAX_SRCWINSZ`
+/// and returns the last error.
+pub fn encode_adaptive<F, R>(
+    input: &[u8],
+    mut src_factory: F,
+    cfg: Xd3Config,
+) -> io::Result<Vec<u8>>
+where
+    F: FnMut() -> io::Result<R>,
+    R: io::Read,
+{
+    let mut cfg = cfg;
+    loop {
+        let source_window_size = cfg.source_window_size;
+        let mut out = Vec::new();
+        let err = match process(
+            cfg.clone(),
+            ProcessMode::Encode,
+            input,
+            src_factory()?,
+            &mut out,
+        ) {
+            Ok(()) => return Ok(out),
+            Err(err) => err,
+        };
+        if !is_window_too_small(&err) || source_window_size >= ADAPTIVE_MAX_SRCWINSZ {
+            return Err(err);
+        }
+        debug!(
+            "encode_adaptive: source window {} too small, retrying",
+            source_window_size
+        );
+        cfg = cfg.source_window_size(source_window_size * 2);
+    }
+}
+
+/// Encode `input` against `src` at the lowest compression level whose delta fits in
+/// `max_delta_size` bytes.
+///
+/// Tries increasingly thorough levels and returns the first delta that fits, or `None` if
+/// even `CompressionLevel::BEST` can't meet the budget. With the default config the level
+/// only picks the string matcher, so one level per matcher is tried: 1, 2, 3, 6 and 7.
+/// The levels in between give the same delta as the one below them.
+pub fn encode_to_budget(
+    input: &[u8],
+    src: &[u8],
+    max_delta_size: u64,
+) -> io::Result<Option<(CompressionLevel, Vec<u8>)>> {
+    for &level in &[1, 2, 3, 6, 7] {
+        let level = CompressionLevel(level);
+        let cfg = Xd3Config::new().compression_level(level);
+        let mut out = Vec::new();
+        process(cfg, ProcessMode::Encode, input, src, &mut out)?;
+        if out.len() as u64 <= max_delta_size {
+            return Ok(Some((level, out)));
+        }
+        debug!(
+            "encode_to_budget: level {} gave {} bytes, budget is {}",
+            level.get(),
+            out.len(),
+            max_delta_size
+        );
+    }
+    Ok(None)
+}
+
+/// Encode `input` against `src`, returning the delta together with its SHA-256.
+///
+/// The hash is computed as the delta is written, so it always matches the returned bytes
+/// without a second pass. To use it as a content address the delta itself has to be
+/// reproducible, so pass a config with `deterministic_windows(true)`; otherwise the same
+/// inputs read in different chunks may encode, and hash, differently.
+#[cfg(feature = "sha2")]
+pub fn encode_addressed<R1, R2>(
+    input: R1,
+    src: R2,
+    cfg: Xd3Config,
+) -> io::Result<(Vec<u8>, [u8; 32])>
+where
+    R1: io::Read,
+    R2: io::Read,
+{
+    let mut output = crate::io::HashingWriter::<_, sha2::Sha256>::new(Vec::new());
+    process(cfg, ProcessMode::Encode, input, src, &mut output)?;
+    let (delta, hash) = output.finalize();
+    Ok((delta, hash.into()))
+}
+
+/// Where an interrupted encode can pick up again, taken at a window boundary.
+///
+/// xdelta3's matcher state can't be saved, so resuming restarts at the beginning of the
+/// window that was in progress: up to one window (`window_size`) of input is encoded
+/// again, and the source is read again from its start.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EncodeCheckpoint {
+    /// Input bytes covered by the finished windows.
+    pub input_offset: u64,
+    /// Delta bytes written for those windows, file header included.
+    pub output_len: u64,
+}
+
+/// Like `process` in encode mode, reporting an `EncodeCheckpoint` after every window.
+///
+/// Output is flushed before each checkpoint is reported, so once `on_checkpoint` has
+/// persisted it, the first `output_len` bytes of the delta are safe to keep. Pass the
+/// latest checkpoint to `resume_encode` to carry on after a restart; only encodes without
+/// a source can be resumed.
+///
+/// Fails with `InvalidInput` if `cfg` uses `buffer_source`, `dictionary` or
+/// `adaptive_winsize`, which checkpointed encodes don't support.
+pub fn encode_with_checkpoints<R1, R2, W, F>(
+    cfg: Xd3Config,
+    input: R1,
+    src: R2,
+    output: W,
+    on_checkpoint: F,
+) -> io::Result<()>
+where
+    R1: io::Read,
+    R2: io::Read,
+    W: io::Write,
+    F: FnMut(EncodeCheckpoint),
+{
+    run_checkpointed(
+        cfg,
+        EncodeCheckpoint::default(),
+        input,
+        src,
+        output,
+        on_checkpoint,
+    )
+}
+
+/// Continue an encode from `checkpoint`.
+///
+/// `output` must already hold the first `checkpoint.output_len` bytes of the interrupted
+/// delta (truncate anything written after them); the rest of the delta is appended to it.
+/// `input` is seeked to `checkpoint.input_offset` and `src` must be the same source, read
+/// from its start. Checkpoints reported here are relative to the whole delta, so an
+/// encode can be resumed any number of times.
+///
+/// With `deterministic_windows`, the result is byte-identical to an uninterrupted run.
+/// That only holds without a source: xdelta3 places source windows based on earlier
+/// windows, so a resumed encode would produce a different delta. Resuming a delta that
+/// has already started (`output_len` above 0) therefore fails with `InvalidInput` if
+/// `src` is not empty, as well as for the settings `encode_with_checkpoints` rejects.
+pub fn resume_encode<R1, R2, W, F>(
+    cfg: Xd3Config,
+    checkpoint: EncodeCheckpoint,
+    mut input: R1,
+    mut src: R2,
+    output: W,
+    on_checkpoint: F,
+) -> io::Result<()>
+where
+    R1: io::Read + io::Seek,
+    R2: io::Read,
+    W: io::Write,
+    F: FnMut(EncodeCheckpoint),
+{
+    if checkpoint.output_len > 0 {
+        let mut probe = Vec::new();
+        io::Read::read_to_end(&mut io::Read::take(&mut src, 1), &mut probe)?;
+        if !probe.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "resume_encode: encodes against a source can't be resumed",
+            ));
+        }
+    }
+    io::Seek::seek(&mut input, io::SeekFrom::Start(checkpoint.input_offset))?;
+    run_checkpointed(cfg, checkpoint, input, src, output, on_checkpoint)
+}
+
+fn run_checkpointed<R1, R2, W, F>(
+    cfg: Xd3Config,
+    base: EncodeCheckpoint,
+    input: R1,
+    src: R2,
+    output: W,
+    mut on_checkpoint: F,
+) -> io::Result<()>
+where
+    R1: io::Read,
+    R2: io::Read,
+    W: io::Write,
+    F: FnMut(EncodeCheckpoint),
+{
+    let unsupported = if cfg.buffer_source {
+        Some("buffer_source")
+    } else if cfg.dictionary.is_some() {
+        Some("dictionary")
+    } else if cfg.adaptive_winsize.is_some() {
+        Some("adaptive_winsize")
+    } else {
+        None
+    };
+    if let Some(setting) = unsupported {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("checkpointed encodes don't support {}", setting),
+        ));
+    }
+
+    let cfg = cfg.flush_each_window(true);
+    let mut state = ProcessState::new(cfg, AllowStdIo::new(src))?;
+    // the delta being resumed already has a file header
+    state.skip_file_header = base.output_len > 0;
+    let winsize = u64::from(state.stream.inner.winsize);
+
+    let mode = ProcessMode::Encode;
+    block_on_sync(drive_async(
+        &mut state,
+        mode,
+        AllowStdIo::new(input),
+        AllowStdIo::new(output),
+        |stats| {
+            let consumed = std::cmp::min(stats.windows_processed * winsize, stats.input_bytes);
+            on_checkpoint(EncodeCheckpoint {
+                input_offset: base.input_offset + consumed,
+                output_len: base.output_len + stats.output_bytes,
+            })
+        },
+    ))
+    .map_err(|e| mode.annotate(e))
+}
+
+fn is_window_too_small(err: &io::Error) -> bool {
+    match err.xd3_error() {
+        Some(Xd3Error::SourceWindowTooSmall { .. }) => true,
+        Some(Xd3Error::Stream { code, .. }) => *code == binding::xd3_rvalues::XD3_TOOFARBACK as i32,
+        _ => false,
+    }
+}
+
+/// Decode `delta` against a source produced on demand by `source_fn`.
+///
+/// `source_fn(offset, len)` is called as source blocks are fetched and must return the
+/// source bytes starting at `offset`, up to `len` of them. Returning fewer than `len` bytes
+/// marks the end of the source. Offsets are requested in increasing order, so a generator
+/// that can only run forwards works too.
+pub fn decode_with_source_fn<F>(delta: &[u8], source_fn: F) -> io::Result<Vec<u8>>
+where
+    F: FnMut(u64, usize) -> Vec<u8>,
+{
+    let src = FnSource {
+        source_fn,
+        offset: 0,
+        pending: Vec::new(),
+        eof: false,
+    };
+    let mut out = Vec::new();
+    block_on_sync(process_async(
+        Xd3Config::new(),
+        ProcessMode::Decode,
+        delta,
+        src,
+        &mut out,
+    ))?;
+    Ok(out)
+}
+
+/// Decode `delta` against a source shared with other threads, without copying it.
+///
+/// xdelta3 reads source blocks straight out of `source`, so any number of concurrent
+/// decodes can use one in-memory copy of a common base, each holding a clone of the `Arc`.
+pub fn decode_shared(delta: &[u8], source: Arc<[u8]>) -> io::Result<Vec<u8>> {
+    let len = source.len() as u64;
+    let cfg = Xd3Config::new().source_window_size(std::cmp::max(len, XD3_DEFAULT_SRCWINSZ));
+    let mut state = ProcessState::with_source_size(cfg, futures_util::io::empty(), len)?;
+    state.src_buf.shared = Some(source);
+
+    let mode = ProcessMode::Decode;
+    let mut out = Vec::new();
+    block_on_sync(drive_async(&mut state, mode, delta, &mut out, |_| ()))
+        .map_err(|e| mode.annotate(e))?;
+    Ok(out)
+}
+
+/// `AsyncRead` over the bytes returned by a `decode_with_source_fn` closure.
+struct FnSource<F> {
+    source_fn: F,
+    // offset of the next byte to ask for
+    offset: u64,
+    // bytes returned by the closure but not read yet
+    pending: Vec<u8>,
+    eof: bool,
+}
+
+// the closure is never pinned
+impl<F> Unpin for FnSource<F> {}
+
+impl<F> AsyncRead for FnSource<F>
+where
+    F: FnMut(u64, usize) -> Vec<u8>,
+{
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.pending.is_empty() && !this.eof && !buf.is_empty() {
+            let data = (this.source_fn)(this.offset, buf.len());
+            this.eof = data.len() < buf.len();
+            this.offset += data.len() as u64;
+            this.pending = data;
+        }
+
+        let len = std::cmp::min(buf.len(), this.pending.len());
+        buf[..len].copy_from_slice(&this.pending[..len]);
+        this.pending.drain(..len);
+        std::task::Poll::Ready(Ok(len))
+    }
+}
+
+/// Encode every `(input, source)` pair in `jobs` with `cfg`, returning one result per job.
+///
+/// A failing job does not stop the batch; the remaining jobs still run and each result
+/// lines up with its job. Use `try_encode_many` to stop at the first error instead.
+pub fn encode_many<I, S, J>(cfg: &Xd3Config, jobs: J) -> Vec<io::Result<Vec<u8>>>
+where
+    I: AsRef<[u8]>,
+    S: AsRef<[u8]>,
+    J: IntoIterator<Item = (I, S)>,
+{
+    jobs.into_iter()
+        .map(|(input, src)| encode_one(cfg, input.as_ref(), src.as_ref()))
+        .collect()
+}
+
+/// Like `encode_many`, but stops at the first failing job and returns its error.
+pub fn try_encode_many<I, S, J>(cfg: &Xd3Config, jobs: J) -> io::Result<Vec<Vec<u8>>>
+where
+    I: AsRef<[u8]>,
+    S: AsRef<[u8]>,
+    J: IntoIterator<Item = (I, S)>,
+{
+    jobs.into_iter()
+        .map(|(input, src)| encode_one(cfg, input.as_ref(), src.as_ref()))
+        .collect()
+}
+
+fn encode_one(cfg: &Xd3Config, input: &[u8], src: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    process(cfg.clone(), ProcessMode::Encode, input, src, &mut out)?;
+    Ok(out)
+}
+
+/// Run `process` on files given by path, with buffered I/O.
+pub fn process_files<P1, P2, P3>(
+    cfg: Xd3Config,
+    mode: ProcessMode,
+    input_path: P1,
+    src_path: P2,
+    output_path: P3,
+) -> io::Result<EncodeStats>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+{
+    run_files(cfg, mode, input_path, src_path, output_path, |_, _, _| ())
+}
+
+/// Encode the file at `new_path` against `old_path` into `patch_path`, calling
+/// `on_progress` after every window.
+///
+/// The file sizes are read up front so that progress can be shown as a percentage.
+pub fn encode_files_with_progress<P1, P2, P3, F>(
+    cfg: Xd3Config,
+    new_path: P1,
+    old_path: P2,
+    patch_path: P3,
+    mut on_progress: F,
+) -> io::Result<EncodeStats>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+    F: FnMut(FileProgressInfo),
+{
+    run_files(
+        cfg,
+        ProcessMode::Encode,
+        new_path,
+        old_path,
+        patch_path,
+        |stats, new_total, old_total| {
+            on_progress(FileProgressInfo {
+                new_bytes_read: stats.input_bytes,
+                new_total,
+                old_bytes_read: stats.source_bytes,
+                old_total,
+                patch_bytes_written: stats.output_bytes,
+            })
+        },
+    )
+}
+
+/// Write a delta turning `old_path` into `new_path` to `delta_path`.
+///
+/// The source window is sized to cover all of `old_path`, so matches are found anywhere in
+/// the old file. Keep in mind that the old file may end up fully cached in memory.
+pub fn diff_files<P1, P2, P3>(old_path: P1, new_path: P2, delta_path: P3) -> io::Result<EncodeStats>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+{
+    let cfg = whole_source_config(old_path.as_ref())?;
+    process_files(cfg, ProcessMode::Encode, new_path, old_path, delta_path)
+}
+
+/// Apply the delta at `delta_path` to `old_path`, writing the result to `new_path`.
+///
+/// This is the counterpart of `diff_files`.
+pub fn patch_files<P1, P2, P3>(
+    old_path: P1,
+    delta_path: P2,
+    new_path: P3,
+) -> io::Result<EncodeStats>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+{
+    let cfg = whole_source_config(old_path.as_ref())?;
+    process_files(cfg, ProcessMode::Decode, delta_path, old_path, new_path)
+}
+
+/// Decode `delta` against `src`, writing the target into `file` starting at `base_offset`.
+///
+/// Output goes out with positioned writes, so bytes outside the target's range are left
+/// alone and the file's cursor is not used. On Unix the cursor is not moved either; on
+/// Windows `seek_write` leaves it after the last byte written, so seek before using it
+/// again. Meant for patching one region of a larger file, such as a partition in a disk
+/// image. Returns the length of the target.
+#[cfg(any(unix, windows))]
+pub fn decode_at<R1, R2>(delta: R1, src: R2, file: &File, base_offset: u64) -> io::Result<u64>
+where
+    R1: io::Read,
+    R2: io::Read,
+{
+    let mut output = PositionedWriter {
+        file,
+        pos: base_offset,
+    };
+    process(
+        Xd3Config::new(),
+        ProcessMode::Decode,
+        delta,
+        src,
+        &mut output,
+    )?;
+    Ok(output.pos - base_offset)
+}
+
+/// Patch `file` in place: decode `delta` against the file's current contents and replace
+/// them with the target. Returns the length of the target.
+///
+/// The source is read with positioned reads, and the target is first decoded into the
+/// region after the end of the source, so nothing xdelta3 may still copy from is
+/// overwritten while decoding, however the delta's copies overlap. The target is then
+/// moved to the start of the file and the file truncated to its length. The file needs
+/// room for source and target together while this runs, and is left inconsistent if it
+/// fails or is interrupted halfway, so keep a way to recover for anything important.
+#[cfg(any(unix, windows))]
+pub fn patch_same_file<R>(file: &mut File, delta: R) -> io::Result<u64>
+where
+    R: io::Read,
+{
+    let file: &File = file;
+    let source_len = file.metadata()?.len();
+    let source = PositionedReader {
+        file,
+        pos: 0,
+        end: source_len,
+    };
+    let target_len = decode_at(delta, source, file, source_len)?;
+
+    // the reader stays ahead of the writer, so every chunk is read before it's overwritten
+    let mut decoded = PositionedReader {
+        file,
+        pos: source_len,
+        end: source_len + target_len,
+    };
+    let mut output = PositionedWriter { file, pos: 0 };
+    io::copy(&mut decoded, &mut output)?;
+    file.set_len(target_len)?;
+    Ok(target_len)
+}
+
+/// `io::Read` over positioned reads of `file` from `pos` up to `end`.
+#[cfg(any(unix, windows))]
+struct PositionedReader<'a> {
+    file: &'a File,
+    pos: u64,
+    end: u64,
+}
+
+#[cfg(any(unix, windows))]
+impl io::Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = std::cmp::min(buf.len() as u64, self.end - self.pos) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(self.file, &mut buf[..max], self.pos)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(self.file, &mut buf[..max], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// `io::Write` over positioned writes to a file, starting at `pos`.
+#[cfg(any(unix, windows))]
+struct PositionedWriter<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+#[cfg(any(unix, windows))]
+impl io::Write for PositionedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::write_at(self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_write(self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One file pair for `parallel_encode_files`.
+#[cfg(feature = "rayon")]
+#[derive(Clone, Debug)]
+pub struct EncodeJob {
+    pub new_file: PathBuf,
+    pub old_file: PathBuf,
+    pub patch_file: PathBuf,
+}
+
+/// Encode all `jobs` concurrently on rayon's global thread pool.
+///
+/// Every job runs to completion and gets its own result, in the order of `jobs`. Each
+/// running job holds its own source cache and input window, so memory use grows with the
+/// number of threads; use `parallel_encode_files_with_threads` to bound it.
+#[cfg(feature = "rayon")]
+pub fn parallel_encode_files(
+    cfg: &Xd3Config,
+    jobs: Vec<EncodeJob>,
+) -> Vec<io::Result<EncodeStats>> {
+    use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+    jobs.into_iter()
+        .map(|job| (cfg.clone(), job))
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|(cfg, job)| {
+            process_files(
+                cfg,
+                ProcessMode::Encode,
+                &job.new_file,
+                &job.old_file,
+                &job.patch_file,
+            )
+        })
+        .collect()
+}
+
+/// Like `parallel_encode_files`, on a dedicated pool of at most `max_threads` threads.
+#[cfg(feature = "rayon")]
+pub fn parallel_encode_files_with_threads(
+    cfg: &Xd3Config,
+    jobs: Vec<EncodeJob>,
+    max_threads: usize,
+) -> io::Result<Vec<io::Result<EncodeStats>>> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(max_threads)
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let cfg = cfg.clone();
+    Ok(pool.install(move || parallel_encode_files(&cfg, jobs)))
+}
+
+fn whole_source_config(src_path: &Path) -> io::Result<Xd3Config> {
+    let src_len = std::fs::metadata(src_path)?.len();
+    let source_window_size = std::cmp::max(src_len, XD3_DEFAULT_SRCWINSZ);
+    Ok(Xd3Config::new().source_window_size(source_window_size))
+}
+
+fn run_files<P1, P2, P3, F>(
+    cfg: Xd3Config,
+    mode: ProcessMode,
+    input_path: P1,
+    src_path: P2,
+    output_path: P3,
+    mut on_window: F,
+) -> io::Result<EncodeStats>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+    F: FnMut(&EncodeStats, u64, u64),
+{
+    let open = |path: &Path| {
+        File::open(path).map_err(|e| e.with_xd3_context(&format!("opening {}", path.display())))
+    };
+    let input_file = open(input_path.as_ref())?;
+    let input_total = input_file.metadata()?.len();
+    let src_file = open(src_path.as_ref())?;
+    let src_total = src_file.metadata()?.len();
+    let output_path = output_path.as_ref();
+    let output_file = File::create(output_path)
+        .map_err(|e| e.with_xd3_context(&format!("creating {}", output_path.display())))?;
+
+    let buf_size = cfg.io_buffer_size;
+    let input = AllowStdIo::new(io::BufReader::with_capacity(buf_size, input_file));
+    let src = AllowStdIo::new(io::BufReader::with_capacity(buf_size, src_file));
+    let output = AllowStdIo::new(io::BufWriter::with_capacity(buf_size, output_file));
+
+    let (cfg, input) = match mode {
+        ProcessMode::Decode => {
+            let (cfg, input) = block_on_sync(fit_source_window_to_delta(cfg, input))?;
+            (cfg, Either::Left(input))
+        }
+        ProcessMode::Encode => (cfg, Either::Right(input)),
+    };
+
+    let mut state = ProcessState::with_source_size(cfg, src, src_total)?;
+    block_on_sync(drive_async(&mut state, mode, input, output, |stats| {
+        on_window(stats, input_total, src_total)
+    }))
+    .map_err(|e| mode.annotate(e))?;
+    Ok(state.into_stats())
+}
+
+/// Run `fut` on the calling thread, parking it while the future is pending.
+///
+/// Futures over `AllowStdIo` never are, but a `ProcessState` finished with
+/// `flush_and_finish` may read from an async source.
+fn block_on_sync<F: Future>(fut: F) -> F::Output {
+    struct Unparker(std::thread::Thread);
+
+    impl std::task::Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = std::task::Waker::from(Arc::new(Unparker(std::thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    futures_util::pin_mut!(fut);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            std::task::Poll::Ready(out) => return out,
+            std::task::Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessMode {
+    Encode,
+    Decode,
+}
+
+impl ProcessMode {
+    fn name(self) -> &'static str {
+        match self {
+            ProcessMode::Encode => "encode",
+            ProcessMode::Decode => "decode",
+        }
+    }
+
+    /// Name the operation in `err`. An `Xd3Error::Stream` already names it; any other
+    /// `Xd3Error` stays reachable through `Xd3IoErrorExt::xd3_error`.
+    fn annotate(self, err: io::Error) -> io::Error {
+        match err.xd3_error() {
+            Some(Xd3Error::Stream { .. }) => err,
+            _ => err.with_xd3_context(&format!("xdelta3 {} failed", self.name())),
+        }
+    }
+}
+
+pub async fn process_async<R1, R2, W>(
+    cfg: Xd3Config,
+    mode: ProcessMode,
+    input: R1,
+    src: R2,
+    output: W,
+) -> io::Result<()>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    #[cfg(feature = "tracing")]
+    {
+        use tracing::field::Empty;
+        use tracing::Instrument;
+
+        let span = tracing::info_span!(
+            "xdelta3",
+            mode = mode.name(),
+            winsize = cfg.inner.winsize,
+            source_window_size = cfg.source_window_size,
+            input_bytes = Empty,
+            source_bytes = Empty,
+            output_bytes = Empty,
+            windows = Empty,
+        );
+        let stats = run_async(cfg, mode, input, src, output)
+            .instrument(span.clone())
+            .await
+            .map_err(|e| mode.annotate(e))?;
+        span.record("input_bytes", stats.input_bytes);
+        span.record("source_bytes", stats.source_bytes);
+        span.record("output_bytes", stats.output_bytes);
+        span.record("windows", stats.windows_processed);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    run_async(cfg, mode, input, src, output)
+        .await
+        .map(|_| ())
+        .map_err(|e| mode.annotate(e))
+}
+
+async fn run_async<R1, R2, W>(
+    mut cfg: Xd3Config,
+    mode: ProcessMode,
+    input: R1,
+    src: R2,
+    output: W,
+) -> io::Result<EncodeStats>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut src = match cfg.dictionary.take() {
+        Some(dictionary) => Either::Left(Cursor::new(dictionary).chain(src)),
+        None => Either::Right(src),
+    };
+    let (cfg, input) = match mode {
+        ProcessMode::Decode => {
+            // the frames are stripped from the delta, the decoded target is not padded
+            let input = match cfg.window_padding.take() {
+                Some(frame_size) => Either::Left(AsyncUnpadReader::new(input, frame_size)),
+                None => Either::Right(input),
+            };
+            let (cfg, input) = fit_source_window_to_delta(cfg, input).await?;
+            (cfg, Either::Left(input))
+        }
+        ProcessMode::Encode => (cfg, Either::Right(input)),
+    };
+    let output = match cfg.output_buffer_size {
+        Some(buffer_size) => Either::Left(BufWriter::with_capacity(buffer_size, output)),
+        None => Either::Right(output),
+    };
+
+    if let (Some((min, max)), ProcessMode::Encode) = (cfg.adaptive_winsize, mode) {
+        return encode_adaptive_windows(cfg, min, max, input, src, output).await;
+    }
+
+    if cfg.buffer_source {
+        let mut src_data = Vec::new();
+        src.read_to_end(&mut src_data)
+            .await
+            .map_err(|e| e.with_xd3_context("buffering source"))?;
+
+        // blocks point straight into the buffer, as in `decode_shared`
+        let len = src_data.len() as u64;
+        let source_window_size = std::cmp::max(cfg.source_window_size, len);
+        let cfg = cfg.source_window_size(source_window_size);
+        let mut state = ProcessState::with_source_size(cfg, futures_util::io::empty(), len)?;
+        state.src_buf.shared = Some(src_data.into());
+        drive_async(&mut state, mode, input, output, |_| ()).await?;
+        return Ok(state.into_stats());
+    }
+
+    let mut state = ProcessState::new(cfg, src)?;
+    drive_async(&mut state, mode, input, output, |_| ()).await?;
+    Ok(state.into_stats())
+}
+
+/// Encode for `Xd3Config::adaptive_winsize`: one stream per window, each sized from how
+/// well the previous window compressed.
+async fn encode_adaptive_windows<R1, R2, W>(
+    cfg: Xd3Config,
+    min: u32,
+    max: u32,
+    mut input: R1,
+    mut src: R2,
+    mut output: W,
+) -> io::Result<EncodeStats>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
 {
-    let cfg = Xd3Config::new();
-    process_async(cfg, ProcessMode::Encode, input, src, out).await
-}
+    let mut stats = EncodeStats::default();
+    let mut src_data = Vec::new();
+    src.read_to_end(&mut src_data)
+        .await
+        .map_err(|e| e.with_xd3_context("buffering source"))?;
+    let source_window_size = std::cmp::max(cfg.source_window_size, src_data.len() as u64);
+    let cfg = cfg.source_window_size(source_window_size);
 
-#[derive(Clone, Copy)]
-pub enum ProcessMode {
-    Encode,
-    Decode,
+    let mut winsize = std::cmp::min(std::cmp::max(cfg.inner.winsize, min), max);
+    let mut chunk = Vec::new();
+    let mut first = true;
+    loop {
+        chunk.clear();
+        (&mut input)
+            .take(u64::from(winsize))
+            .read_to_end(&mut chunk)
+            .await
+            .map_err(|e| e.with_xd3_context("reading input"))?;
+        if chunk.is_empty() && !first {
+            break;
+        }
+
+        let mut state = ProcessState::new(cfg.clone().window_size(winsize), src_data.as_slice())?;
+        // every window after the first continues the same delta
+        state.skip_file_header = !first;
+        state.encode_segment(chunk.as_slice(), &mut output).await?;
+        state.finish_encode(&mut output).await?;
+        first = false;
+
+        let window_stats = state.into_stats();
+        stats.input_bytes += window_stats.input_bytes;
+        stats.source_bytes = std::cmp::max(stats.source_bytes, window_stats.source_bytes);
+        stats.output_bytes += window_stats.output_bytes;
+        stats.windows_processed += window_stats.windows_processed;
+        if chunk.len() < winsize as usize {
+            break;
+        }
+
+        let ratio = window_stats.output_bytes as f64 / chunk.len() as f64;
+        let next = if ratio > 0.9 {
+            std::cmp::max(winsize / 2, min)
+        } else if ratio < 0.3 {
+            std::cmp::min(winsize.saturating_mul(2), max)
+        } else {
+            winsize
+        };
+        trace!(
+            "adaptive window: ratio={:.2}, winsize {} -> {}",
+            ratio,
+            winsize,
+            next
+        );
+        winsize = next;
+    }
+    Ok(stats)
 }
 
-pub async fn process_async<R1, R2, W>(
+/// How much of a delta is read up front to find the first window's source segment.
+const DELTA_PEEK_SIZE: usize = 4096;
+
+/// Grow the source window so the decoder can address the first window's source segment.
+///
+/// VCDIFF does not record the source block size the encoder used, but the source segment
+/// of a window is the range of source the decoder must be able to copy from. When the
+/// configured window is smaller than that, blocks the delta still needs would be evicted
+/// and decoding fails with `invalid blkno`. Returns a reader that replays the peeked bytes
+/// before the rest of `input`.
+async fn fit_source_window_to_delta<R>(
     cfg: Xd3Config,
+    mut input: R,
+) -> io::Result<(Xd3Config, Chain<Cursor<Vec<u8>>, R>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut prefix = vec![0u8; DELTA_PEEK_SIZE];
+    let mut len = 0;
+    while len < prefix.len() {
+        let read = input.read(&mut prefix[len..]).await?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+    prefix.truncate(len);
+
+    // anything unparseable is left for xdelta3 to report
+    let segment = vcdiff::first_source_segment(&prefix).unwrap_or(None);
+    let cfg = match segment {
+        Some(segment) if segment > cfg.source_window_size => {
+            debug!(
+                "growing source window from {} to fit a {} byte source segment",
+                cfg.source_window_size, segment
+            );
+            cfg.source_window_size(segment)
+        }
+        _ => cfg,
+    };
+    Ok((cfg, Cursor::new(prefix).chain(input)))
+}
+
+/// Write `data` as one `pad_windows_to` frame, returning the frame size. The first frame
+/// of a stream (`first`) also carries the stream header.
+async fn write_padded_frame<W>(
+    output: &mut W,
+    data: &[u8],
+    frame_size: u32,
+    first: bool,
+) -> io::Result<u64>
+where
+    W: Unpin + AsyncWrite,
+{
+    let len: u32 = std::convert::TryFrom::try_from(data.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "output chunk too large for a padded frame",
+        )
+    })?;
+    let prefix_len = if first { PADDED_HEADER_LEN } else { 0 };
+    let frame_len = padded_frame_len(prefix_len, u64::from(len), frame_size);
+    if first {
+        output.write_all(&PADDED_MAGIC).await?;
+        output.write_all(&[PADDED_FORMAT_VERSION]).await?;
+    }
+    output.write_all(&len.to_be_bytes()).await?;
+    output.write_all(data).await?;
+
+    const ZEROS: [u8; 512] = [0; 512];
+    let mut padding = frame_len - prefix_len - 4 - data.len() as u64;
+    while padding > 0 {
+        let n = std::cmp::min(padding, ZEROS.len() as u64) as usize;
+        output.write_all(&ZEROS[..n]).await?;
+        padding -= n as u64;
+    }
+    Ok(frame_len)
+}
+
+async fn drive_async<R1, R2, W, F>(
+    state: &mut ProcessState<R2>,
+    mode: ProcessMode,
+    input: R1,
+    output: W,
+    mut on_window: F,
+) -> io::Result<()>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnMut(&EncodeStats),
+{
+    drive_async_until(state, mode, input, output, |stats| {
+        on_window(stats);
+        ControlFlow::Continue(())
+    })
+    .await
+}
+
+/// Like `drive_async`, stopping after the window for which `on_window` returns `Break`.
+async fn drive_async_until<R1, R2, W, F>(
+    state: &mut ProcessState<R2>,
     mode: ProcessMode,
     mut input: R1,
-    src: R2,
     mut output: W,
+    mut on_window: F,
 ) -> io::Result<()>
 where
     R1: AsyncRead + Unpin,
     R2: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
+    F: FnMut(&EncodeStats) -> ControlFlow<()>,
 {
-    let mut state = ProcessState::new(cfg, src)?;
-
     use binding::xd3_rvalues::*;
 
     loop {
+        state.check_deadline()?;
         let res = state.step(mode);
         match res {
             XD3_INPUT => {
                 if state.eof {
+                    if mode == ProcessMode::Decode {
+                        state.check_delta_complete()?;
+                    }
                     break;
                 }
                 state.read_input(&mut input).await?;
             }
+            XD3_OUTPUT if state.cfg.header_only && mode == ProcessMode::Encode => {
+                state.write_file_header(&mut output).await?;
+                break;
+            }
             XD3_OUTPUT => {
                 state.write_output(&mut output).await?;
+                if state.cfg.flush_each_window {
+                    output.flush().await?;
+                }
             }
             XD3_GETSRCBLK => {
-                state.src_buf.getblk().await?;
+                state.getblk().await?;
+            }
+            XD3_WINFINISH => {
+                state.finish_window();
+                if on_window(&state.stats).is_break() {
+                    break;
+                }
             }
-            XD3_GOTHEADER | XD3_WINSTART | XD3_WINFINISH => {
+            XD3_GOTHEADER | XD3_WINSTART => {
                 // do nothing
             }
             XD3_TOOFARBACK | XD3_INTERNAL | XD3_INVALID | XD3_INVALID_INPUT | XD3_NOSECOND
             | XD3_UNIMPLEMENTED => {
-                return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", res)));
+                return Err(state.stream_error(mode, res));
             }
         }
     }
@@ -299,27 +3355,174 @@ where
     output.flush().await
 }
 
-struct ProcessState<R> {
-    #[allow(unused)]
+/// Snapshot of the xdelta3 stream's internal state machines, for debugging why `step`
+/// returned what it did.
+///
+/// A stream only runs one of the two machines; the other stays in its initial state
+/// (`ENC_INIT` or `DEC_VCHEAD`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Xd3StreamState {
+    pub encode: xd3_encode_state,
+    pub decode: xd3_decode_state,
+}
+
+impl fmt::Display for Xd3StreamState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}/{:?}", self.encode, self.decode)
+    }
+}
+
+/// Encoder/decoder state for callers that drive the xdelta3 loop themselves.
+///
+/// `process_async` is a thin loop over this type: call `step` and handle the returned
+/// `xd3_rvalues` with `read_input`, `write_output` or `getblk` until `step` asks for input
+/// after `is_eof` became true.
+///
+/// A state is `Send` only if its source reader is:
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<xdelta3::stream::ProcessState<std::rc::Rc<[u8]>>>();
+/// ```
+pub struct ProcessState<R> {
     cfg: Xd3Config,
     stream: Xd3Stream,
     src_buf: SrcBuffer<R>,
 
     input_buf: Vec<u8>,
     eof: bool,
+    stats: EncodeStats,
+
+    // drop the VCDIFF file header from the first output, when appending to a delta
+    skip_file_header: bool,
+
+    // notified on every XD3_WINFINISH
+    progress_callback: Option<Box<dyn FnMut(&EncodeStats) + Send>>,
+    progress_tx: Option<mpsc::Sender<EncodeStats>>,
+    // notified when xdelta3 moves to a different source segment
+    source_window_callback: Option<Box<dyn FnMut(u64, u64) + Send>>,
+    last_source_window: Option<(u64, u64)>,
+
+    // when decoding, the file header and the input read since the current window started,
+    // for telling a malformed delta apart from other failures xdelta3 reports the same way
+    decoding: bool,
+    delta_log: Vec<u8>,
+    delta_header_len: Option<usize>,
+}
+
+impl<R> ProcessState<R>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    /// Start over with a fresh xdelta3 stream, rewinding the source to its start.
+    ///
+    /// This lets one state encode or decode several inputs against the same source
+    /// without reopening it. Counters are reset; progress callbacks and channels are kept.
+    pub async fn reset(&mut self) -> io::Result<()> {
+        let mut stream = Self::config_stream(&mut self.cfg)?;
+        self.src_buf.clear_and_reset_reader().await?;
+        let ret =
+            unsafe { binding::xd3_set_source(stream.inner.as_mut(), self.src_buf.src.as_mut()) };
+        if ret != 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "xd3_set_source"));
+        }
+
+        self.stream = stream;
+        self.eof = false;
+        self.stats = EncodeStats::default();
+        self.last_source_window = None;
+        self.delta_log.clear();
+        self.delta_header_len = None;
+        Ok(())
+    }
+
+    /// Read evicted source blocks again when xdelta3 goes back to them, instead of failing
+    /// with `Xd3Error::SourceWindowTooSmall`.
+    ///
+    /// Each one costs two seeks on the source. See `Xd3Config::strict_source_window` for
+    /// what happens when this keeps occurring.
+    pub fn with_source_refetch(mut self) -> Self {
+        self.src_buf = self.src_buf.with_refetch();
+        self
+    }
 }
 
 impl<R> ProcessState<R>
 where
     R: AsyncRead + Unpin,
 {
-    fn new(mut cfg: Xd3Config, src: R) -> io::Result<Self> {
+    pub fn new(cfg: Xd3Config, src: R) -> io::Result<Self> {
+        Self::new_with_source_size(cfg, src, None)
+    }
+
+    /// Like `new`, for a source whose length is known up front (e.g. from file metadata).
+    ///
+    /// xdelta3 then knows where the source ends before the first block is read, which it
+    /// uses when placing the source window.
+    pub fn with_source_size(cfg: Xd3Config, src: R, src_size: u64) -> io::Result<Self> {
+        Self::new_with_source_size(cfg, src, Some(src_size))
+    }
+
+    fn new_with_source_size(mut cfg: Xd3Config, src: R, src_size: Option<u64>) -> io::Result<Self> {
         // log::info!("ProcessState::new config={:?}", cfg);
+        if cfg.inner.sprevsz < cfg.inner.winsize {
+            debug!(
+                "sprevsz={} is smaller than winsize={}",
+                cfg.inner.sprevsz, cfg.inner.winsize
+            );
+        }
+
+        let mut stream = Self::config_stream(&mut cfg)?;
+        let stream0 = stream.inner.as_mut();
+
+        let mut src_buf = SrcBuffer::new(&cfg, src, src_size)?;
+        if cfg.source_dedup {
+            src_buf = src_buf.with_dedup();
+        }
+        let ret = unsafe { binding::xd3_set_source(stream0, src_buf.src.as_mut()) };
+        if ret != 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "xd3_set_source"));
+        }
+
+        let input_buf_size = cfg.input_buffer_size.unwrap_or(stream0.winsize as usize);
+        trace!(
+            "stream.winsize={}, input_buf_size={}",
+            stream0.winsize,
+            input_buf_size
+        );
+        let mut input_buf = Vec::with_capacity(input_buf_size);
+        input_buf.resize(input_buf_size, 0u8);
+
+        Ok(Self {
+            cfg,
+            stream,
+            src_buf,
+            input_buf,
+            eof: false,
+            stats: EncodeStats::default(),
+            skip_file_header: false,
+            progress_callback: None,
+            progress_tx: None,
+            source_window_callback: None,
+            last_source_window: None,
+            decoding: false,
+            delta_log: Vec::new(),
+            delta_header_len: None,
+        })
+    }
 
+    fn config_stream(cfg: &mut Xd3Config) -> io::Result<Xd3Stream> {
         let mut stream = Xd3Stream::new();
         let stream0 = stream.inner.as_mut();
 
+        // xdelta3 rejects the `no_secondary_compression` flags when there is no secondary
+        // compressor for them to turn off
+        let flags = cfg.inner.flags;
+        if flags & binding::xd3_flags::XD3_SEC_TYPE as i32 == 0 {
+            cfg.inner.flags &= !(binding::xd3_flags::XD3_SEC_NOALL as i32);
+        }
         let ret = unsafe { binding::xd3_config_stream(stream0, cfg.inner.as_mut()) };
+        cfg.inner.flags = flags;
         if ret != 0 {
             let err = if stream0.msg == std::ptr::null() {
                 Error::new(io::ErrorKind::Other, "xd3_config_stream: null")
@@ -333,28 +3536,293 @@ where
             };
             return Err(err);
         }
+        Ok(stream)
+    }
 
-        let mut src_buf = SrcBuffer::new(&cfg, src)?;
-        let ret = unsafe { binding::xd3_set_source(stream0, src_buf.src.as_mut()) };
-        if ret != 0 {
-            return Err(io::Error::new(io::ErrorKind::Other, "xd3_set_source"));
+    /// Largest chunk `write_output` is expected to hand to the writer, which is the stream
+    /// window size.
+    ///
+    /// When decoding, each `XD3_OUTPUT` carries one target window, which fits as long as the
+    /// delta was encoded with a window no larger than this. When encoding, windows of
+    /// incompressible data can exceed it by the VCDIFF per-window overhead, so leave some
+    /// headroom when sizing a ring buffer from this value.
+    pub fn output_buffer_capacity(&self) -> usize {
+        self.stream.inner.winsize as usize
+    }
+
+    /// Call `callback` with the accumulated stats every time a window is finished.
+    pub fn set_progress_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(&EncodeStats) + Send + 'static,
+    {
+        self.progress_callback = Some(Box::new(callback));
+    }
+
+    /// Send a snapshot of the accumulated stats on `tx` every time a window is finished.
+    ///
+    /// Sending stops silently once the receiver is dropped.
+    pub fn set_progress_channel(&mut self, tx: mpsc::Sender<EncodeStats>) {
+        self.progress_tx = Some(tx);
+    }
+
+    /// Call `callback` with the start offset and length of the source window whenever
+    /// xdelta3 moves on to a different one, before the first block of it is fetched.
+    ///
+    /// When decoding this is the source segment named by each window header, so callers
+    /// can prefetch that range of the source; consecutive windows copying from the same
+    /// segment report it once. Windows that don't copy from the source report nothing.
+    pub fn set_source_window_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(u64, u64) + Send + 'static,
+    {
+        self.source_window_callback = Some(Box::new(callback));
+    }
+
+    /// Turn an error returned by the state machine into an `Xd3Error::Stream`.
+    fn stream_error(&self, mode: ProcessMode, res: binding::xd3_rvalues) -> io::Error {
+        use binding::xd3_rvalues::*;
+
+        let stream = self.stream.inner.as_ref();
+        let msg = if stream.msg.is_null() {
+            None
+        } else {
+            let msg = unsafe { std::ffi::CStr::from_ptr(stream.msg) };
+            Some(msg.to_string_lossy().into_owned())
+        };
+        let kind = match res {
+            XD3_INVALID | XD3_INVALID_INPUT => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::Other,
+        };
+
+        // the decoder also uses XD3_INVALID_INPUT for e.g. checksum mismatches, so only
+        // call the delta malformed if its bytes show why
+        if mode == ProcessMode::Decode && res == XD3_INVALID_INPUT {
+            let violation = crate::vcdiff::window_violation(
+                &self.delta_log,
+                stream.current_window,
+                stream.dec_winstart,
+            );
+            if let Some(err) = violation {
+                return io::Error::new(kind, err);
+            }
         }
 
-        let input_buf_size = stream0.winsize as usize;
-        trace!("stream.winsize={}", input_buf_size);
-        let mut input_buf = Vec::with_capacity(input_buf_size);
-        input_buf.resize(input_buf_size, 0u8);
+        io::Error::new(
+            kind,
+            Xd3Error::Stream {
+                operation: mode.name(),
+                code: res as i32,
+                msg,
+            },
+        )
+    }
 
-        Ok(Self {
-            cfg,
-            stream,
-            src_buf,
-            input_buf,
-            eof: false,
-        })
+    /// Fail with `DeadlineExceeded` once the configured deadline has passed.
+    fn check_deadline(&self) -> io::Result<()> {
+        match self.cfg.deadline {
+            Some(deadline) if std::time::Instant::now() >= deadline => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                Xd3Error::DeadlineExceeded,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Once the delta has run out, the decoder must be waiting for the next window
+    /// indicator. Any other state means the delta was cut short, which xdelta3 itself
+    /// does not report: it just asks for more input.
+    fn check_delta_complete(&self) -> io::Result<()> {
+        let dec_state = self.stream.inner.dec_state;
+        if dec_state == binding::xd3_decode_state::DEC_WININD {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            Xd3Error::MalformedDelta(format!("delta ends early (decoder in {:?})", dec_state)),
+        ))
+    }
+
+    /// Bookkeeping for `XD3_WINFINISH`.
+    fn finish_window(&mut self) {
+        if self.decoding {
+            self.forget_window_input();
+        }
+        self.stats.windows_processed += 1;
+        self.sync_source_stats();
+        trace!("window finished: stats={:?}", self.stats);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            windows = self.stats.windows_processed,
+            input_bytes = self.stats.input_bytes,
+            output_bytes = self.stats.output_bytes,
+            "window finished"
+        );
+
+        if let Some(callback) = self.progress_callback.as_mut() {
+            callback(&self.stats);
+        }
+        if let Some(tx) = self.progress_tx.as_ref() {
+            if tx.send(self.stats.clone()).is_err() {
+                self.progress_tx = None;
+            }
+        }
+    }
+
+    /// Drop the input of the window just decoded from `delta_log`, keeping the file header
+    /// and whatever xdelta3 hasn't consumed yet.
+    fn forget_window_input(&mut self) {
+        if self.delta_header_len.is_none() {
+            self.delta_header_len = crate::vcdiff::file_header_len(&self.delta_log).ok();
+        }
+        let header_len = match self.delta_header_len {
+            Some(len) => len,
+            None => return,
+        };
+        let consumed = self.delta_log.len() - self.stream.inner.avail_in as usize;
+        if consumed > header_len {
+            self.delta_log.drain(header_len..consumed);
+        }
+    }
+
+    fn sync_source_stats(&mut self) {
+        self.stats.source_bytes = self.src_buf.read_len as u64;
+        self.stats.source_stats = self.src_buf.stats.clone();
+    }
+
+    /// Counters accumulated so far.
+    ///
+    /// The source counters are only brought up to date at the end of each window; use
+    /// `into_stats` for exact final numbers.
+    pub fn stats(&self) -> &EncodeStats {
+        &self.stats
+    }
+
+    /// Index of the window xdelta3 is working on, read from `xd3_stream.current_window`.
+    ///
+    /// Starts at 0 and moves to the next window once xdelta3 is done with the previous
+    /// one, so inside a progress callback it is still the index of the window that just
+    /// finished. After the last window it equals the number of windows this stream
+    /// processed. Unlike `EncodeStats::windows_processed`, which is aggregated across
+    /// streams, it starts over on `reset`.
+    pub fn current_window_index(&self) -> u64 {
+        self.stream.inner.current_window
+    }
+
+    /// Consume the state, freeing the xdelta3 stream, and return the final counters.
+    pub fn into_stats(mut self) -> EncodeStats {
+        self.sync_source_stats();
+        self.stats
+    }
+
+    /// Consume the state, freeing the xdelta3 stream, and return the source reader.
+    ///
+    /// Useful when the source is a file or socket the caller keeps using afterwards. The
+    /// reader is left wherever the last source block read stopped.
+    pub fn into_source_reader(self) -> R {
+        let Self {
+            stream, src_buf, ..
+        } = self;
+        // the stream points into src_buf.src, so it has to go first
+        drop(stream);
+        src_buf.into_reader()
+    }
+
+    /// Source blocks currently held in the block cache, as `(block number, data)` in block
+    /// order. Only meant for tests and debugging.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn cached_source_blocks(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.src_buf.iter_cached_blocks()
+    }
+
+    /// Call `observer` on every source block fetch, cache hit and eviction, synchronously
+    /// from within `getblk`. Only meant for tests and for collecting access patterns.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_block_observer<F>(mut self, observer: F) -> Self
+    where
+        F: FnMut(BlockEvent) + Send + 'static,
+    {
+        self.src_buf = self.src_buf.with_observer(observer);
+        self
+    }
+
+    /// Tell xdelta3 the source geometry up front: `total_blocks` blocks of the source block
+    /// size, the last one holding `last_block_size` bytes.
+    ///
+    /// Same as passing `(total_blocks - 1) * block_size + last_block_size` to
+    /// `with_source_size`, for callers that know the source in blocks, e.g. from
+    /// `expected_source_blocks` of an earlier run. The block size is the source window
+    /// size divided by 32, rounded up to `src_block_alignment`.
+    ///
+    /// Fails with `InvalidInput` if `total_blocks` is 0, `last_block_size` is 0 or larger
+    /// than a block, or processing has already started.
+    pub fn source_block_count_hint(
+        &mut self,
+        total_blocks: u64,
+        last_block_size: u32,
+    ) -> io::Result<()> {
+        let blksize = self.src_buf.src.blksize;
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        if self.stats.input_bytes != 0 || self.src_buf.stats.blocks_requested != 0 {
+            return invalid("source block count hint given after processing started".to_owned());
+        }
+        if total_blocks == 0 {
+            return invalid("source block count hint of 0 blocks".to_owned());
+        }
+        if last_block_size == 0 || last_block_size > blksize {
+            return invalid(format!(
+                "last block size {} is not in 1..={}",
+                last_block_size, blksize
+            ));
+        }
+
+        let size = (total_blocks - 1) * u64::from(blksize) + u64::from(last_block_size);
+        self.src_buf.set_size(size);
+        Ok(())
+    }
+
+    /// Source length passed to `with_source_size`, if any.
+    pub fn expected_source_size(&self) -> Option<u64> {
+        self.src_buf.expected_source_size()
+    }
+
+    /// Number of source blocks for the length passed to `with_source_size`, if any.
+    ///
+    /// Together with `SrcStats::blocks_read` this gives the source-read progress.
+    pub fn expected_source_blocks(&self) -> Option<u64> {
+        self.src_buf.expected_blocks()
+    }
+
+    /// Total number of source blocks, or `None` while the source length is unknown.
+    ///
+    /// This is `expected_source_blocks` when a size was given, and otherwise becomes known
+    /// once the source reader has reported EOF.
+    pub fn total_source_blocks(&self) -> Option<u64> {
+        self.src_buf.total_blocks_in_source()
+    }
+
+    /// Number of source blocks after the one xdelta3 last loaded, or `None` while the source
+    /// length is unknown. Before any block is loaded this is `total_source_blocks`.
+    pub fn remaining_source_blocks(&self) -> Option<u64> {
+        self.src_buf.remaining_blocks()
+    }
+
+    /// Whether the input reader has reported EOF.
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Where xdelta3's encoder and decoder state machines are.
+    pub fn stream_state(&self) -> Xd3StreamState {
+        Xd3StreamState {
+            encode: self.stream.inner.enc_state,
+            decode: self.stream.inner.dec_state,
+        }
     }
 
-    fn step(&mut self, mode: ProcessMode) -> binding::xd3_rvalues {
+    /// Run xdelta3 until it needs the caller to act, and return what it needs.
+    pub fn step(&mut self, mode: ProcessMode) -> binding::xd3_rvalues {
+        trace!("step: {} in {}", mode.name(), self.stream_state());
+        self.decoding = mode == ProcessMode::Decode;
         unsafe {
             let stream = self.stream.inner.as_mut();
             std::mem::transmute(match mode {
@@ -364,23 +3832,37 @@ where
         }
     }
 
-    async fn read_input<R2>(&mut self, mut input: R2) -> io::Result<()>
+    /// Handle `XD3_INPUT`: read the next chunk of input, flushing the stream on EOF.
+    pub async fn read_input<R2>(&mut self, mut input: R2) -> io::Result<()>
     where
         R2: Unpin + AsyncRead,
     {
         let input_buf = &mut self.input_buf;
 
-        let read_size = match input.read(input_buf).await {
-            Ok(n) => n,
-            Err(_e) => {
-                debug!("error on read: {:?}", _e);
-                return Err(io::Error::new(io::ErrorKind::Other, "xd3: read_input"));
+        let mut read_size = 0;
+        let mut input_eof = false;
+        loop {
+            let len = match input.read(&mut input_buf[read_size..]).await {
+                Ok(n) => n,
+                Err(e) => {
+                    debug!("error on read: {:?}", e);
+                    return Err(e.with_xd3_context("reading input"));
+                }
+            };
+            read_size += len;
+
+            if len == 0 {
+                input_eof = true;
+                break;
             }
-        };
+            if !self.cfg.deterministic_windows || read_size == input_buf.len() {
+                break;
+            }
+        }
 
         {
             let stream = self.stream.inner.as_mut();
-            if read_size == 0 {
+            if input_eof {
                 // xd3_set_flags
                 stream.flags |= binding::xd3_flags::XD3_FLUSH as i32;
                 self.eof = true;
@@ -388,12 +3870,127 @@ where
             // xd3_avail_input
             stream.next_in = input_buf.as_ptr();
             stream.avail_in = read_size as u32;
+            self.stats.input_bytes += read_size as u64;
+        }
+        if self.decoding {
+            self.delta_log.extend_from_slice(&input_buf[..read_size]);
         }
 
         Ok(())
     }
 
-    async fn write_output<W>(&mut self, mut output: W) -> io::Result<()>
+    /// Encode `input` as the next segment of a delta that is still open.
+    ///
+    /// Unlike `process_async`, running out of input does not end the stream: xdelta3 keeps
+    /// partially filled windows buffered until `winsize` bytes have arrived, and the source
+    /// cache is kept between calls. Matches spanning a segment boundary are found as if the
+    /// segments were one input, so the delta is identical to encoding the concatenation in a
+    /// single call. Call `finish_encode` after the last segment.
+    pub async fn encode_segment<R1, W>(&mut self, mut input: R1, mut output: W) -> io::Result<()>
+    where
+        R1: Unpin + AsyncRead,
+        W: Unpin + AsyncWrite,
+    {
+        use binding::xd3_rvalues::*;
+
+        if self.eof {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encode_segment: stream already finished",
+            ));
+        }
+
+        loop {
+            self.check_deadline()?;
+            let res = self.step(ProcessMode::Encode);
+            match res {
+                XD3_INPUT => {
+                    let read_size = input.read(&mut self.input_buf).await?;
+
+                    // xd3_avail_input, also when empty: xdelta3 leaves the input it just
+                    // buffered in `avail_in` and would buffer it again on the next step
+                    let stream = self.stream.inner.as_mut();
+                    stream.next_in = self.input_buf.as_ptr();
+                    stream.avail_in = read_size as u32;
+                    self.stats.input_bytes += read_size as u64;
+                    if read_size == 0 {
+                        return Ok(());
+                    }
+                }
+                XD3_OUTPUT => {
+                    self.write_output(&mut output).await?;
+                    if self.cfg.flush_each_window {
+                        output.flush().await?;
+                    }
+                }
+                XD3_GETSRCBLK => {
+                    self.getblk().await?;
+                }
+                XD3_WINFINISH => {
+                    self.finish_window();
+                }
+                XD3_GOTHEADER | XD3_WINSTART => {
+                    // do nothing
+                }
+                XD3_TOOFARBACK | XD3_INTERNAL | XD3_INVALID | XD3_INVALID_INPUT | XD3_NOSECOND
+                | XD3_UNIMPLEMENTED => {
+                    return Err(self.stream_error(ProcessMode::Encode, res));
+                }
+            }
+        }
+    }
+
+    /// Flush the last window of a delta built with `encode_segment` and close the stream.
+    pub async fn finish_encode<W>(&mut self, output: W) -> io::Result<()>
+    where
+        W: Unpin + AsyncWrite,
+    {
+        drive_async(
+            self,
+            ProcessMode::Encode,
+            futures_util::io::empty(),
+            output,
+            |_| (),
+        )
+        .await
+    }
+
+    /// Blocking `finish_encode` for a `std::io` writer, returning the final counters.
+    ///
+    /// Marks the input as finished, drives the encoder until the last window is written and
+    /// flushes `output`. The calling thread is blocked while the source reader is pending,
+    /// so don't call this from inside an async runtime.
+    pub fn flush_and_finish<W>(&mut self, output: &mut W) -> io::Result<EncodeStats>
+    where
+        W: io::Write,
+    {
+        block_on_sync(self.finish_encode(AllowStdIo::new(output)))?;
+        self.sync_source_stats();
+        Ok(self.stats.clone())
+    }
+
+    /// Handle `XD3_GETSRCBLK`: load the source block xdelta3 asked for.
+    pub async fn getblk(&mut self) -> io::Result<()> {
+        // the encoder tracks its source window in `src`, the decoder in the window header
+        let window = match self.stream.inner.dec_cpylen {
+            0 => (self.src_buf.src.srcbase, self.src_buf.src.srclen as u64),
+            cpylen => (self.stream.inner.dec_cpyoff, cpylen as u64),
+        };
+        if window.1 > 0 && self.last_source_window != Some(window) {
+            self.last_source_window = Some(window);
+            if let Some(callback) = self.source_window_callback.as_mut() {
+                callback(window.0, window.1);
+            }
+        }
+
+        self.src_buf
+            .getblk()
+            .await
+            .map_err(|e| e.with_xd3_context("fetching source block"))
+    }
+
+    /// Write just the file header from the pending output, for `encode_header_only`.
+    async fn write_file_header<W>(&mut self, mut output: W) -> io::Result<()>
     where
         W: Unpin + AsyncWrite,
     {
@@ -401,7 +3998,50 @@ where
             let stream = self.stream.inner.as_mut();
             unsafe { std::slice::from_raw_parts(stream.next_out, stream.avail_out as usize) }
         };
-        output.write_all(out_data).await?;
+        let header_len = vcdiff::file_header_len(out_data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        output
+            .write_all(&out_data[..header_len])
+            .await
+            .map_err(|e| e.with_xd3_context("writing output"))?;
+        self.stats.output_bytes += header_len as u64;
+
+        // xd3_consume_output
+        self.stream.inner.as_mut().avail_out = 0;
+        Ok(())
+    }
+
+    /// Handle `XD3_OUTPUT`: write the pending output and mark it consumed.
+    pub async fn write_output<W>(&mut self, mut output: W) -> io::Result<()>
+    where
+        W: Unpin + AsyncWrite,
+    {
+        // Both `out_data` and the source block xdelta3 last got (`src.curblk`) stay valid
+        // while the write below is pending: the output buffer belongs to the stream, which
+        // is only touched again by the next `step`, and cached blocks live on the heap and
+        // are only evicted by `getblk`. Neither can run before this future completes,
+        // since it holds `&mut self`.
+        let mut out_data = {
+            let stream = self.stream.inner.as_mut();
+            unsafe { std::slice::from_raw_parts(stream.next_out, stream.avail_out as usize) }
+        };
+        // a delta that is continued already has its stream header, like its file header
+        let first = self.stats.output_bytes == 0 && !self.skip_file_header;
+        if self.skip_file_header {
+            let header_len = vcdiff::file_header_len(out_data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            out_data = &out_data[header_len..];
+            self.skip_file_header = false;
+        }
+        let written = match self.cfg.window_padding {
+            Some(frame_size) => write_padded_frame(&mut output, out_data, frame_size, first).await,
+            None => output
+                .write_all(out_data)
+                .await
+                .map(|_| out_data.len() as u64),
+        }
+        .map_err(|e| e.with_xd3_context("writing output"))?;
+        self.stats.output_bytes += written;
 
         // xd3_consume_output
         self.stream.inner.as_mut().avail_out = 0;
@@ -0,0 +1,716 @@
+//! Pure-Rust helpers for the VCDIFF (RFC 3284) container format.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::Xd3Error;
+
+/// Leading bytes of every VCDIFF delta: `VCD` with the high bits set, then version 0.
+pub const VCDIFF_MAGIC: [u8; 4] = [0xD6, 0xC3, 0xC4, 0x00];
+
+/// Header indicator bits that are defined by RFC 3284 and xdelta3.
+const VCD_HDR_MASK: u8 = 0x07;
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+/// Window indicator bit: the window copies from a segment of the source.
+pub const VCD_SOURCE: u8 = 0x01;
+/// Window indicator bit: the window copies from a segment of earlier target data.
+pub const VCD_TARGET: u8 = 0x02;
+/// Window indicator bit: the window header carries an Adler-32 checksum of the target
+/// window, which the decoder verifies. An xdelta3 extension to RFC 3284.
+pub const VCD_ADLER32: u8 = 0x04;
+const VCD_WIN_MASK: u8 = 0x07;
+
+/// Delta indicator bits, one per secondary-compressed section.
+const VCD_DELTA_MASK: u8 = 0x07;
+
+/// A VCDIFF file header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VcdiffHeader {
+    /// Format version, always 0 for RFC 3284 deltas.
+    pub version: u8,
+    /// The `Hdr_Indicator` byte.
+    pub indicator: u8,
+    /// Secondary compressor id (1 = DJW, 2 = LZMA, 16 = FGK), present with `VCD_DECOMPRESS`.
+    pub secondary_compressor: Option<u8>,
+    /// Encoded custom code table, present with `VCD_CODETABLE`.
+    pub code_table: Option<Vec<u8>>,
+    /// Application header, present with `VCD_APPHEADER`. xdelta3 stores file names here.
+    pub app_header: Option<Vec<u8>>,
+}
+
+impl VcdiffHeader {
+    /// Parse the file header at the start of `data`.
+    ///
+    /// This is a plain parse of the header layout and does not run xdelta3; the windows
+    /// after the header are not looked at.
+    pub fn parse(data: &[u8]) -> Result<Self, Xd3Error> {
+        Self::read(&mut Cursor::new(data))
+    }
+
+    fn read(cursor: &mut Cursor) -> Result<Self, Xd3Error> {
+        let data = cursor.data;
+        if data.len() < 4 || data[..3] != VCDIFF_MAGIC[..3] {
+            return Err(Xd3Error::NotAVcdiff);
+        }
+        cursor.skip(4, "magic")?;
+        let version = data[3];
+        let indicator = cursor.byte("header indicator")?;
+        if indicator & !VCD_HDR_MASK != 0 {
+            return Err(Xd3Error::MalformedDelta(format!(
+                "unknown header indicator bits: {:#04x}",
+                indicator
+            )));
+        }
+
+        let mut header = Self {
+            version,
+            indicator,
+            secondary_compressor: None,
+            code_table: None,
+            app_header: None,
+        };
+        if indicator & VCD_DECOMPRESS != 0 {
+            header.secondary_compressor = Some(cursor.byte("secondary compressor id")?);
+        }
+        if indicator & VCD_CODETABLE != 0 {
+            let len = cursor.varint("code table length")?;
+            header.code_table = Some(cursor.bytes(len, "code table")?.to_vec());
+        }
+        if indicator & VCD_APPHEADER != 0 {
+            let len = cursor.varint("application header length")?;
+            header.app_header = Some(cursor.bytes(len, "application header")?.to_vec());
+        }
+        Ok(header)
+    }
+
+    /// Serialize back to the file header bytes.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut out = VCDIFF_MAGIC[..3].to_vec();
+        out.push(self.version);
+        out.push(self.indicator);
+        if let Some(id) = self.secondary_compressor {
+            out.push(id);
+        }
+        if let Some(code_table) = &self.code_table {
+            write_varint(&mut out, code_table.len() as u64);
+            out.extend_from_slice(code_table);
+        }
+        if let Some(app_header) = &self.app_header {
+            write_varint(&mut out, app_header.len() as u64);
+            out.extend_from_slice(app_header);
+        }
+        out
+    }
+}
+
+/// Append a VCDIFF integer to `out`.
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    let mut digits = [0u8; 10];
+    let mut n = 0;
+    loop {
+        digits[n] = (value & 0x7F) as u8;
+        n += 1;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+    }
+    for i in (0..n).rev() {
+        let more = if i == 0 { 0 } else { 0x80 };
+        out.push(digits[i] | more);
+    }
+}
+
+impl TryFrom<&[u8]> for VcdiffHeader {
+    type Error = Xd3Error;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        Self::parse(data)
+    }
+}
+
+impl TryFrom<&Vec<u8>> for VcdiffHeader {
+    type Error = Xd3Error;
+
+    fn try_from(data: &Vec<u8>) -> Result<Self, Self::Error> {
+        Self::parse(data)
+    }
+}
+
+/// Summary of a delta produced by `validate_delta`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeltaInfo {
+    /// Number of windows in the delta.
+    pub window_count: u64,
+    /// Size of the target the delta reconstructs, summed from the target window length in
+    /// every window header. RFC 3284 makes that field mandatory, so every window xdelta3
+    /// writes carries it and decoders never have to infer it.
+    pub total_target_size: u64,
+    /// Whether any window copies from the source, i.e. the delta can't be applied without it.
+    pub references_source: bool,
+    /// Whether any window copies from earlier target data (`VCD_TARGET`).
+    pub references_target: bool,
+    /// Secondary compressor id from the file header (1 = DJW, 2 = LZMA, 16 = FGK).
+    pub secondary_compressor: Option<u8>,
+    /// Indicator byte of every window, in order: a combination of `VCD_SOURCE` or
+    /// `VCD_TARGET` with `VCD_ADLER32`.
+    pub window_indicators: Vec<u8>,
+}
+
+/// Check that `delta` is a structurally valid VCDIFF delta without decoding it.
+///
+/// This walks the file header and every window header, checking that indicator bytes only
+/// use known bits, that each window's section lengths add up to its declared size and that
+/// `VCD_TARGET` segments only cover target data of earlier windows. No source is needed and
+/// no output is produced, so it is cheap enough to run over every delta before publishing
+/// it. Instruction streams are not interpreted: a delta that passes can still fail to
+/// decode, e.g. if it was produced against a different source.
+pub fn validate_delta(delta: &[u8]) -> Result<DeltaInfo, Xd3Error> {
+    let mut cursor = Cursor::new(delta);
+    let mut info = DeltaInfo::default();
+    info.secondary_compressor = VcdiffHeader::read(&mut cursor)?.secondary_compressor;
+
+    while !cursor.is_empty() {
+        let (win_indicator, tgtlen) = check_window(
+            &mut cursor,
+            info.window_count,
+            info.secondary_compressor,
+            info.total_target_size,
+        )?;
+        info.references_source |= win_indicator & VCD_SOURCE != 0;
+        info.references_target |= win_indicator & VCD_TARGET != 0;
+        info.window_count += 1;
+        info.total_target_size += tgtlen;
+        info.window_indicators.push(win_indicator);
+    }
+
+    Ok(info)
+}
+
+/// Check the header of the window at `cursor` and skip over it, returning its indicator
+/// and target length. `target_before` is the target length of all earlier windows.
+fn check_window(
+    cursor: &mut Cursor,
+    window: u64,
+    secondary_compressor: Option<u8>,
+    target_before: u64,
+) -> Result<(u8, u64), Xd3Error> {
+    let malformed =
+        |detail: String| Xd3Error::MalformedDelta(format!("window {}: {}", window, detail));
+
+    let win_indicator = cursor.byte("window indicator")?;
+    if win_indicator & !VCD_WIN_MASK != 0 {
+        return Err(malformed(format!(
+            "unknown window indicator bits: {:#04x}",
+            win_indicator
+        )));
+    }
+    if win_indicator & VCD_SOURCE != 0 && win_indicator & VCD_TARGET != 0 {
+        return Err(malformed(
+            "both VCD_SOURCE and VCD_TARGET are set".to_owned(),
+        ));
+    }
+    if win_indicator & (VCD_SOURCE | VCD_TARGET) != 0 {
+        let len = cursor.varint("source segment size")?;
+        let pos = cursor.varint("source segment position")?;
+        // a target segment can only cover output of earlier windows
+        if win_indicator & VCD_TARGET != 0
+            && pos.checked_add(len).map_or(true, |end| end > target_before)
+        {
+            return Err(malformed(format!(
+                "target segment of {} bytes at {} extends past the {} bytes produced so far",
+                len, pos, target_before
+            )));
+        }
+    }
+
+    let enclen = cursor.varint("delta encoding length")?;
+    let start = cursor.pos;
+
+    let tgtlen = cursor.varint("target window length")?;
+    let delta_indicator = cursor.byte("delta indicator")?;
+    if delta_indicator & !VCD_DELTA_MASK != 0 {
+        return Err(malformed(format!(
+            "unknown delta indicator bits: {:#04x}",
+            delta_indicator
+        )));
+    }
+    if delta_indicator != 0 && secondary_compressor.is_none() {
+        return Err(malformed(
+            "secondary compression used without a compressor id".to_owned(),
+        ));
+    }
+
+    let data_len = cursor.varint("data section length")?;
+    let inst_len = cursor.varint("instruction section length")?;
+    let addr_len = cursor.varint("address section length")?;
+    if win_indicator & VCD_ADLER32 != 0 {
+        cursor.skip(4, "adler32 checksum")?;
+    }
+    let sections = data_len
+        .checked_add(inst_len)
+        .and_then(|len| len.checked_add(addr_len))
+        .ok_or_else(|| malformed("section lengths overflow".to_owned()))?;
+    let declared = (cursor.pos - start) as u64 + sections;
+    if declared != enclen {
+        return Err(malformed(format!(
+            "delta encoding length is {} but its contents take {}",
+            enclen, declared
+        )));
+    }
+    cursor.skip(sections, "window sections")?;
+    Ok((win_indicator, tgtlen))
+}
+
+/// One instruction of a delta, as listed by `disassemble`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// Write `data` at `target_offset`.
+    Add { target_offset: u64, data: Vec<u8> },
+    /// Write `len` copies of `byte` at `target_offset`.
+    Run {
+        target_offset: u64,
+        len: u64,
+        byte: u8,
+    },
+    /// Write `len` bytes read from `from` at `target_offset`.
+    Copy {
+        target_offset: u64,
+        len: u64,
+        from: CopyFrom,
+    },
+}
+
+/// Where an `Instruction::Copy` reads from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyFrom {
+    /// Offset in the source.
+    Source(u64),
+    /// Offset in the target, i.e. output the delta already produced. The copied range may
+    /// overlap the bytes being written, which repeats a pattern.
+    Target(u64),
+}
+
+/// List the ADD, RUN and COPY instructions of `delta`, like `xdelta3 printdelta`.
+///
+/// Offsets are absolute in the whole target and source, not relative to each window.
+/// The instruction and address sections are parsed directly with the RFC 3284 default
+/// code table, so no source is needed. Deltas with a custom code table or secondary
+/// compression fail with `Xd3Error::Unsupported`, since their sections can't be read
+/// without xdelta3's decoder.
+pub fn disassemble(delta: &[u8]) -> Result<Vec<Instruction>, Xd3Error> {
+    validate_delta(delta)?;
+
+    let mut cursor = Cursor::new(delta);
+    if VcdiffHeader::read(&mut cursor)?.code_table.is_some() {
+        return Err(Xd3Error::Unsupported("custom code table".to_owned()));
+    }
+    let table = default_code_table();
+
+    let mut out = Vec::new();
+    let mut window_start = 0u64;
+    let mut window = 0u64;
+    while !cursor.is_empty() {
+        let tgtlen = disassemble_window(&mut cursor, &table, window_start, &mut out).map_err(
+            |e| match e {
+                Xd3Error::MalformedDelta(detail) => {
+                    Xd3Error::MalformedDelta(format!("window {}: {}", window, detail))
+                }
+                e => e,
+            },
+        )?;
+        window_start += tgtlen;
+        window += 1;
+    }
+
+    Ok(out)
+}
+
+/// Decode a delta that only copies from the source, such as one encoded with
+/// `Xd3Config::streaming_decode_friendly`, writing the target to `out` as it is produced.
+///
+/// Nothing of the target is kept: ADDs and RUNs come from the delta, and COPYs are read
+/// from `src` through an 8 KiB buffer, so memory stays at the delta plus that buffer no
+/// matter how large the windows are. Returns the number of bytes written. A copy from the
+/// target fails with `Xd3Error::Unsupported`, as do deltas `disassemble` can't read.
+/// Window checksums are not verified.
+pub fn decode_source_only<R, W>(delta: &[u8], mut src: R, mut out: W) -> io::Result<u64>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut written = 0;
+    let mut buf = [0u8; 8192];
+    for inst in disassemble(delta).map_err(invalid)? {
+        match inst {
+            Instruction::Add { data, .. } => {
+                out.write_all(&data)?;
+                written += data.len() as u64;
+            }
+            Instruction::Run { len, byte, .. } => {
+                let run = [byte; 256];
+                let mut left = len;
+                while left > 0 {
+                    let n = std::cmp::min(left, run.len() as u64) as usize;
+                    out.write_all(&run[..n])?;
+                    left -= n as u64;
+                }
+                written += len;
+            }
+            Instruction::Copy {
+                len,
+                from: CopyFrom::Source(offset),
+                ..
+            } => {
+                src.seek(SeekFrom::Start(offset))?;
+                let mut left = len;
+                while left > 0 {
+                    let n = std::cmp::min(left, buf.len() as u64) as usize;
+                    src.read_exact(&mut buf[..n])?;
+                    out.write_all(&buf[..n])?;
+                    left -= n as u64;
+                }
+                written += len;
+            }
+            Instruction::Copy {
+                target_offset,
+                from: CopyFrom::Target(_),
+                ..
+            } => {
+                return Err(invalid(Xd3Error::Unsupported(format!(
+                    "copy from the target at {}",
+                    target_offset
+                ))));
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// Append the instructions of the window at `cursor` to `out`, returning its target length.
+fn disassemble_window(
+    cursor: &mut Cursor,
+    table: &[[(Op, u8); 2]],
+    window_start: u64,
+    out: &mut Vec<Instruction>,
+) -> Result<u64, Xd3Error> {
+    let win_indicator = cursor.byte("window indicator")?;
+    let (seg_len, seg_pos) = if win_indicator & (VCD_SOURCE | VCD_TARGET) != 0 {
+        let len = cursor.varint("source segment size")?;
+        (len, cursor.varint("source segment position")?)
+    } else {
+        (0, 0)
+    };
+    cursor.varint("delta encoding length")?;
+    let tgtlen = cursor.varint("target window length")?;
+    if cursor.byte("delta indicator")? != 0 {
+        return Err(Xd3Error::Unsupported("secondary compression".to_owned()));
+    }
+    let data_len = cursor.varint("data section length")?;
+    let inst_len = cursor.varint("instruction section length")?;
+    let addr_len = cursor.varint("address section length")?;
+    if win_indicator & VCD_ADLER32 != 0 {
+        cursor.skip(4, "adler32 checksum")?;
+    }
+    let mut data = Cursor::new(cursor.bytes(data_len, "data section")?);
+    let mut inst = Cursor::new(cursor.bytes(inst_len, "instruction section")?);
+    let mut addrs = Cursor::new(cursor.bytes(addr_len, "address section")?);
+
+    let mut cache = AddressCache::new();
+    // position in the target window
+    let mut pos = 0u64;
+    while !inst.is_empty() {
+        let code = inst.byte("instruction")?;
+        for &(op, size) in &table[code as usize] {
+            if op == Op::Noop {
+                continue;
+            }
+            let len = match size {
+                0 => inst.varint("instruction size")?,
+                size => u64::from(size),
+            };
+            if pos.checked_add(len).map_or(true, |end| end > tgtlen) {
+                return Err(Xd3Error::MalformedDelta(format!(
+                    "instruction of {} bytes at {} runs past the target window length {}",
+                    len, pos, tgtlen
+                )));
+            }
+            let target_offset = window_start + pos;
+            out.push(match op {
+                Op::Noop => unreachable!(),
+                Op::Add => Instruction::Add {
+                    target_offset,
+                    data: data.bytes(len, "add data")?.to_vec(),
+                },
+                Op::Run => Instruction::Run {
+                    target_offset,
+                    len,
+                    byte: data.byte("run byte")?,
+                },
+                Op::Copy(mode) => {
+                    let addr = cache.decode(seg_len + pos, mode, &mut addrs)?;
+                    if addr < seg_len && addr.saturating_add(len) > seg_len {
+                        return Err(Xd3Error::MalformedDelta(format!(
+                            "copy of {} bytes at {} crosses the end of the source segment",
+                            len, addr
+                        )));
+                    }
+                    let from = if addr >= seg_len {
+                        CopyFrom::Target(window_start + addr - seg_len)
+                    } else if win_indicator & VCD_TARGET != 0 {
+                        CopyFrom::Target(seg_pos + addr)
+                    } else {
+                        CopyFrom::Source(seg_pos + addr)
+                    };
+                    Instruction::Copy {
+                        target_offset,
+                        len,
+                        from,
+                    }
+                }
+            });
+            pos += len;
+        }
+    }
+    if pos != tgtlen {
+        return Err(Xd3Error::MalformedDelta(format!(
+            "instructions produce {} bytes but the target window length is {}",
+            pos, tgtlen
+        )));
+    }
+    Ok(tgtlen)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Noop,
+    Add,
+    Run,
+    Copy(u8),
+}
+
+/// The RFC 3284 default code table: two `(op, size)` halves per code, size 0 meaning the
+/// size follows in the instruction section.
+fn default_code_table() -> Vec<[(Op, u8); 2]> {
+    let noop = (Op::Noop, 0);
+    let mut table = Vec::with_capacity(256);
+    table.push([(Op::Run, 0), noop]);
+    for size in 0..=17 {
+        table.push([(Op::Add, size), noop]);
+    }
+    for mode in 0..9 {
+        table.push([(Op::Copy(mode), 0), noop]);
+        for size in 4..=18 {
+            table.push([(Op::Copy(mode), size), noop]);
+        }
+    }
+    for mode in 0..6 {
+        for add in 1..=4 {
+            for copy in 4..=6 {
+                table.push([(Op::Add, add), (Op::Copy(mode), copy)]);
+            }
+        }
+    }
+    for mode in 6..9 {
+        for add in 1..=4 {
+            table.push([(Op::Add, add), (Op::Copy(mode), 4)]);
+        }
+    }
+    for mode in 0..9 {
+        table.push([(Op::Copy(mode), 4), (Op::Add, 1)]);
+    }
+    debug_assert_eq!(table.len(), 256);
+    table
+}
+
+const NEAR_SIZE: usize = 4;
+const SAME_SIZE: usize = 3;
+
+/// The per-window address cache of RFC 3284, section 5.1.
+struct AddressCache {
+    near: [u64; NEAR_SIZE],
+    next_slot: usize,
+    same: [u64; SAME_SIZE * 256],
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        Self {
+            near: [0; NEAR_SIZE],
+            next_slot: 0,
+            same: [0; SAME_SIZE * 256],
+        }
+    }
+
+    /// Decode the address of a copy at position `here` of the window's address space.
+    fn decode(&mut self, here: u64, mode: u8, addrs: &mut Cursor) -> Result<u64, Xd3Error> {
+        let mode = mode as usize;
+        let addr = match mode {
+            // VCD_SELF
+            0 => addrs.varint("copy address")?,
+            // VCD_HERE
+            1 => here
+                .checked_sub(addrs.varint("copy address")?)
+                .ok_or_else(|| {
+                    Xd3Error::MalformedDelta("copy address before the window".to_owned())
+                })?,
+            m if m < 2 + NEAR_SIZE => self.near[m - 2]
+                .checked_add(addrs.varint("copy address")?)
+                .ok_or_else(|| Xd3Error::MalformedDelta("copy address overflows".to_owned()))?,
+            m => {
+                let b = addrs.byte("copy address")? as usize;
+                self.same[(m - 2 - NEAR_SIZE) * 256 + b]
+            }
+        };
+        if addr >= here {
+            return Err(Xd3Error::MalformedDelta(format!(
+                "copy address {} is not before the current position {}",
+                addr, here
+            )));
+        }
+
+        self.near[self.next_slot] = addr;
+        self.next_slot = (self.next_slot + 1) % NEAR_SIZE;
+        self.same[(addr % (SAME_SIZE * 256) as u64) as usize] = addr;
+        Ok(addr)
+    }
+}
+
+/// Size of the source segment of the first window in `delta`, or `None` if that window
+/// does not copy from the source.
+///
+/// `delta` only needs to hold the file header and the first window header, so this works
+/// on the first few bytes read from a stream.
+#[cfg(feature = "stream")]
+pub(crate) fn first_source_segment(delta: &[u8]) -> Result<Option<u64>, Xd3Error> {
+    let mut cursor = Cursor::new(delta);
+    VcdiffHeader::read(&mut cursor)?;
+
+    let win_indicator = cursor.byte("window indicator")?;
+    if win_indicator & VCD_SOURCE == 0 {
+        return Ok(None);
+    }
+    cursor.varint("source segment size").map(Some)
+}
+
+/// Length of the file header at the start of `delta`, including the secondary compressor
+/// id, code table and application header if present.
+#[cfg(feature = "stream")]
+pub(crate) fn file_header_len(delta: &[u8]) -> Result<usize, Xd3Error> {
+    let mut cursor = Cursor::new(delta);
+    VcdiffHeader::read(&mut cursor)?;
+    Ok(cursor.pos)
+}
+
+/// The structural violation in one window of a stream that xdelta3 rejected, if there is
+/// one.
+///
+/// `delta` holds the file header followed by the window's bytes as read so far, and
+/// `target_before` is the target length of the windows before it. Running out of bytes is
+/// not a violation, since the rest of the window may simply not have been read yet; nor is
+/// anything `disassemble` doesn't support. `None` means the delta looks well-formed, so the
+/// failure lies elsewhere, e.g. a checksum mismatch from decoding against the wrong source.
+#[cfg(feature = "stream")]
+pub(crate) fn window_violation(delta: &[u8], window: u64, target_before: u64) -> Option<Xd3Error> {
+    let structural = |e: Xd3Error| match e {
+        Xd3Error::MalformedDelta(ref detail) if !detail.starts_with("truncated ") => Some(e),
+        _ => None,
+    };
+
+    let mut cursor = Cursor::new(delta);
+    let header = match VcdiffHeader::read(&mut cursor) {
+        Ok(header) => header,
+        Err(e) => return structural(e),
+    };
+    if cursor.is_empty() {
+        return None;
+    }
+    if let Err(e) = check_window(
+        &mut cursor.clone(),
+        window,
+        header.secondary_compressor,
+        target_before,
+    ) {
+        return structural(e);
+    }
+    if header.code_table.is_some() {
+        return None;
+    }
+    // the whole window is there, so running out of a section is a violation too
+    match disassemble_window(
+        &mut cursor,
+        &default_code_table(),
+        target_before,
+        &mut Vec::new(),
+    ) {
+        Err(Xd3Error::MalformedDelta(detail)) => Some(Xd3Error::MalformedDelta(format!(
+            "window {}: {}",
+            window, detail
+        ))),
+        _ => None,
+    }
+}
+
+/// Byte reader over a delta, reporting truncation as `MalformedDelta`.
+#[derive(Clone)]
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn truncated(what: &str) -> Xd3Error {
+        Xd3Error::MalformedDelta(format!("truncated {}", what))
+    }
+
+    fn byte(&mut self, what: &str) -> Result<u8, Xd3Error> {
+        let b = *self
+            .data
+            .get(self.pos)
+            .ok_or_else(|| Self::truncated(what))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    /// Read a VCDIFF integer: base 128, most significant digit first, with the high bit
+    /// set on every byte but the last.
+    fn varint(&mut self, what: &str) -> Result<u64, Xd3Error> {
+        let mut value = 0u64;
+        loop {
+            let b = self.byte(what)?;
+            if value.leading_zeros() < 7 {
+                return Err(Xd3Error::MalformedDelta(format!("{} overflows", what)));
+            }
+            value = (value << 7) | u64::from(b & 0x7F);
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn bytes(&mut self, len: u64, what: &str) -> Result<&'a [u8], Xd3Error> {
+        let remaining = (self.data.len() - self.pos) as u64;
+        if len > remaining {
+            return Err(Self::truncated(what));
+        }
+        let bytes = &self.data[self.pos..self.pos + len as usize];
+        self.pos += len as usize;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: u64, what: &str) -> Result<(), Xd3Error> {
+        self.bytes(len, what).map(|_| ())
+    }
+}
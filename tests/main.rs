@@ -3,6 +3,8 @@ mod tests {
     use std::fs::File;
     use std::io::Read;
     #[cfg(feature = "stream")]
+    use xdelta3::error::Xd3IoErrorExt;
+    #[cfg(feature = "stream")]
     use xdelta3::stream::*;
     use xdelta3::*;
 
@@ -38,6 +40,3623 @@ mod tests {
         assert_eq!(&recode, &[1, 2, 3, 4, 5, 6, 7]);
     }
 
+    #[test]
+    fn memory_output_sizing() {
+        // a delta given to encode is just data, whatever target length it declares
+        let empty_target = encode(&[], &[1, 2, 3]).expect("failed to encode");
+        let patch = encode(&empty_target, &[1, 2, 3]).expect("failed to encode");
+        assert_eq!(check_decode(&patch, &[1, 2, 3]), empty_target);
+
+        // far more target than delta and source
+        let zeros = vec![0u8; 1 << 20];
+        let patch = encode(&zeros, &[]).expect("failed to encode");
+        assert!(patch.len() < 1 << 10);
+        assert_eq!(check_decode(&patch, &[]), zeros);
+    }
+
+    #[test]
+    #[cfg(feature = "crc32fast")]
+    fn crc32_checksum() {
+        use xdelta3::checksum::{checksum, Crc32, Hasher};
+
+        assert_eq!(checksum::<Crc32>(b"123456789"), 0xCBF4_3926);
+
+        let mut hasher = Crc32::new();
+        hasher.update(b"1234");
+        hasher.update(b"56789");
+        assert_eq!(hasher.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn offset_reader_range() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut reader =
+            xdelta3::io::OffsetReader::new(std::io::Cursor::new(&data), 100, 50).unwrap();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, &data[100..150]);
+
+        // a range running past the end stops at the end
+        let mut reader =
+            xdelta3::io::OffsetReader::new(std::io::Cursor::new(&data), 250, 50).unwrap();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, &data[250..]);
+    }
+
+    #[test]
+    fn limit_reader_stops_at_limit() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut reader = xdelta3::io::LimitReader::new(&data[..], 100);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, &data[..100]);
+        assert_eq!(reader.remaining(), 0);
+
+        let mut reader = xdelta3::io::LimitReader::new(&data[..], 1000);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(reader.remaining(), 1000 - 256);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn async_limit_reader_encodes_prefix() {
+        use futures::io::AsyncReadExt;
+        use xdelta3::io::AsyncLimitReader;
+
+        let source = pseudo_random(50_000, 33);
+        let input = pseudo_random(80_000, 34);
+
+        let mut prefix = Vec::new();
+        futures::executor::block_on(
+            AsyncLimitReader::new(&input[..], 30_000).read_to_end(&mut prefix),
+        )
+        .unwrap();
+        assert_eq!(prefix, &input[..30_000]);
+
+        let mut patch = Vec::new();
+        futures::executor::block_on(xdelta3::stream::encode_async(
+            AsyncLimitReader::new(&input[..], 30_000),
+            &source[..],
+            &mut patch,
+        ))
+        .expect("failed to encode");
+        assert_eq!(&input[..30_000], &check_decode(&patch, &source)[..]);
+    }
+
+    #[test]
+    fn min_delta_size_estimate() {
+        let source = pseudo_random(100_000, 50);
+        assert_eq!(estimate_min_delta_size(&source, &source), 0);
+
+        // unrelated random data can't be compressed at all
+        let input = pseudo_random(100_000, 51);
+        let estimate = estimate_min_delta_size(&input, &source);
+        assert!(estimate > 99_000 && estimate <= 100_000, "{}", estimate);
+
+        // a repeated stretch is found within the target itself
+        let mut input = pseudo_random(10_000, 52);
+        input.extend_from_within(..);
+        let estimate = estimate_min_delta_size(&input, &[]);
+        assert!(estimate <= 10_016, "{}", estimate);
+    }
+
+    #[test]
+    fn version_string() {
+        assert!(version().starts_with("xdelta3 "));
+        assert!(supports_djw());
+        assert_eq!(version().contains("lzma"), supports_lzma());
+    }
+
+    #[test]
+    fn decode_rejects_raw_input() {
+        let result = decode(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7]);
+        assert!(matches!(result, Err(Xd3Error::NotAVcdiff)));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn segmented_encode_matches_single_call() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let single = encode2(&input, &source).expect("failed to encode");
+
+        let mut segmented = Vec::new();
+        let (head, tail) = input.split_at(input.len() / 3);
+        let mut state = ProcessState::new(Xd3Config::new(), &source[..]).unwrap();
+        futures::executor::block_on(async {
+            state.encode_segment(head, &mut segmented).await?;
+            state.encode_segment(tail, &mut segmented).await?;
+            state.finish_encode(&mut segmented).await
+        })
+        .expect("failed to encode");
+
+        let stats = state.into_stats();
+        assert_eq!(stats.input_bytes, input.len() as u64);
+        assert!(stats.source_bytes <= source.len() as u64);
+        assert_eq!(stats.output_bytes, segmented.len() as u64);
+        assert!(stats.source_stats.blocks_read > 0);
+        assert!(stats.source_stats.blocks_requested >= stats.source_stats.blocks_read);
+
+        assert_eq!(single, segmented);
+        assert_eq!(input, check_decode(&segmented, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn flush_and_finish_sync() {
+        let source = pseudo_random(100_000, 22);
+        let mut input = source.clone();
+        input[40_000..40_100].copy_from_slice(&pseudo_random(100, 23));
+
+        let mut patch = Vec::new();
+        let mut state = ProcessState::new(Xd3Config::new(), &source[..]).unwrap();
+        futures::executor::block_on(state.encode_segment(&input[..], &mut patch))
+            .expect("failed to encode");
+        let stats = state
+            .flush_and_finish(&mut patch)
+            .expect("failed to finish");
+
+        assert_eq!(stats.input_bytes, input.len() as u64);
+        assert_eq!(stats.output_bytes, patch.len() as u64);
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn flush_and_finish_waits_for_pending_source() {
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        // pending on every other read, woken from another thread
+        struct LateSource<'a> {
+            data: &'a [u8],
+            pending: bool,
+            waits: usize,
+        }
+
+        impl futures::io::AsyncRead for LateSource<'_> {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<std::io::Result<usize>> {
+                self.pending = !self.pending;
+                if self.pending {
+                    self.waits += 1;
+                    let waker = cx.waker().clone();
+                    std::thread::spawn(move || {
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                        waker.wake();
+                    });
+                    return Poll::Pending;
+                }
+                Pin::new(&mut self.data).poll_read(cx, buf)
+            }
+        }
+
+        let source = pseudo_random(100_000, 22);
+        let mut input = source.clone();
+        input[40_000..40_100].copy_from_slice(&pseudo_random(100, 23));
+
+        let late = LateSource {
+            data: &source,
+            pending: false,
+            waits: 0,
+        };
+        let mut patch = Vec::new();
+        let mut state = ProcessState::new(Xd3Config::new(), late).unwrap();
+        futures::executor::block_on(state.encode_segment(&input[..], &mut patch))
+            .expect("failed to encode");
+        state
+            .flush_and_finish(&mut patch)
+            .expect("failed to finish");
+
+        assert!(state.into_source_reader().waits > 0);
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn process_owned_returns_io() {
+        let source = pseudo_random(50_000, 24);
+        let input = pseudo_random(50_000, 25);
+
+        let (_, _, patch) = process_owned(
+            Xd3Config::new(),
+            ProcessMode::Encode,
+            &input[..],
+            std::io::Cursor::new(&source),
+            std::io::Cursor::new(Vec::new()),
+        )
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch.into_inner(), &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn into_source_reader_after_encode() {
+        let source = pseudo_random(100_000, 29);
+        let mut input = source.clone();
+        input[10_000..10_100].copy_from_slice(&pseudo_random(100, 30));
+
+        let src = futures::io::Cursor::new(source.clone());
+        let mut state = ProcessState::new(Xd3Config::new(), src).unwrap();
+        let mut patch = Vec::new();
+        futures::executor::block_on(async {
+            state.encode_segment(&input[..], &mut patch).await?;
+            state.finish_encode(&mut patch).await
+        })
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let src = state.into_source_reader();
+        assert_eq!(src.into_inner(), source);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn reset_reuses_source() {
+        let source = pseudo_random(100_000, 27);
+        let inputs: Vec<Vec<u8>> = (0..2)
+            .map(|i| {
+                let mut input = source.clone();
+                input[i * 1000..i * 1000 + 100].copy_from_slice(&pseudo_random(100, 28));
+                input
+            })
+            .collect();
+
+        let src = futures::io::Cursor::new(source.clone());
+        let mut state = ProcessState::new(Xd3Config::new(), src).unwrap();
+        for (i, input) in inputs.iter().enumerate() {
+            if i > 0 {
+                futures::executor::block_on(state.reset()).expect("failed to reset");
+            }
+            let mut patch = Vec::new();
+            futures::executor::block_on(async {
+                state.encode_segment(&input[..], &mut patch).await?;
+                state.finish_encode(&mut patch).await
+            })
+            .expect("failed to encode");
+            assert_eq!(state.stats().input_bytes, input.len() as u64);
+            assert_eq!(*input, check_decode(&patch, &source));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn decode_reports_progress() {
+        let source = pseudo_random(100_000, 29);
+        let mut input = pseudo_random(100_000, 30);
+        input[..50_000].copy_from_slice(&source[..50_000]);
+
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().window_size(1 << 14);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+
+        let mut reported = Vec::new();
+        let mut out = Vec::new();
+        futures::executor::block_on(decode_async_with_progress(
+            &patch[..],
+            &source[..],
+            &mut out,
+            |written| reported.push(written),
+        ))
+        .expect("failed to decode");
+
+        assert_eq!(input, out);
+        assert!(reported.len() > 1);
+        assert!(reported.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(reported.last(), Some(&(input.len() as u64)));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn source_dedup_round_trip() {
+        let mut source = vec![0u8; 1 << 20];
+        source[1000..1100].copy_from_slice(&[7u8; 100]);
+        let mut input = source.clone();
+        input[500_000..500_010].copy_from_slice(b"0123456789");
+
+        let mut patch = Vec::new();
+        futures::executor::block_on(encode_with_source_dedup(
+            &input[..],
+            &source[..],
+            &mut patch,
+        ))
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn buffered_source_backward_copies() {
+        // target made of the source blocks in reverse order, so that most copies reach
+        // back behind the previous match
+        let source = pseudo_random(1 << 16, 0x1234_5678);
+        let input: Vec<u8> = source.chunks(1024).rev().flatten().cloned().collect();
+
+        let cfg = Xd3Config::new().buffer_source(true);
+        let mut patch = Vec::new();
+        futures::executor::block_on(process_async(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        ))
+        .expect("failed to encode");
+
+        assert!(patch.len() < input.len() / 4);
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    /// Async reader that yields at most `chunk` bytes per read.
+    #[cfg(feature = "stream")]
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    #[cfg(feature = "stream")]
+    impl futures::io::AsyncRead for ChunkedReader<'_> {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let len = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            std::task::Poll::Ready(Ok(len))
+        }
+    }
+
+    /// Number of COPY instructions in `patch` that read earlier target data.
+    #[cfg(feature = "stream")]
+    fn target_copies(patch: &[u8]) -> usize {
+        use xdelta3::vcdiff::CopyFrom;
+
+        disassemble(patch)
+            .unwrap()
+            .into_iter()
+            .filter(|inst| {
+                matches!(
+                    inst,
+                    Instruction::Copy {
+                        from: CopyFrom::Target(_),
+                        ..
+                    }
+                )
+            })
+            .count()
+    }
+
+    fn pseudo_random(len: usize, mut seed: u32) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn deterministic_windows_ignore_read_chunking() {
+        let source = pseudo_random(300_000, 1);
+        let mut input = source.clone();
+        input[100_000..100_100].copy_from_slice(&pseudo_random(100, 2));
+        input.extend_from_slice(&pseudo_random(50_000, 3));
+
+        let encode_chunked = |chunk: usize| {
+            let cfg = Xd3Config::new()
+                .window_size(1 << 16)
+                .deterministic_windows(true);
+            let reader = ChunkedReader {
+                data: &input,
+                chunk,
+            };
+            let mut patch = Vec::new();
+            futures::executor::block_on(process_async(
+                cfg,
+                ProcessMode::Encode,
+                reader,
+                &source[..],
+                &mut patch,
+            ))
+            .expect("failed to encode");
+            patch
+        };
+
+        let patch = encode_chunked(1000);
+        assert_eq!(patch, encode_chunked(4096));
+        assert_eq!(patch, encode_chunked(1 << 20));
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn encode_files_reports_progress() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let new_path = format!("{}/{}", fixure_path, "file_v2.bin");
+        let old_path = format!("{}/{}", fixure_path, "file_v1.bin");
+        let patch_path = std::env::temp_dir().join("xdelta3-rs-encode-files-progress.vcdiff");
+
+        let mut last = None;
+        let stats = encode_files_with_progress(
+            Xd3Config::new(),
+            &new_path,
+            &old_path,
+            &patch_path,
+            |progress| last = Some(progress),
+        )
+        .expect("failed to encode");
+
+        let last = last.expect("no progress reported");
+        assert_eq!(last.new_bytes_read, last.new_total);
+        assert_eq!(stats.output_bytes, last.patch_bytes_written);
+
+        let patch = read_file(patch_path.to_str().unwrap());
+        std::fs::remove_file(&patch_path).ok();
+        assert_eq!(patch.len() as u64, stats.output_bytes);
+        assert_eq!(
+            read_file(&new_path),
+            check_decode(&patch, &read_file(&old_path))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn diff_and_patch_files() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let old_path = format!("{}/{}", fixure_path, "file_v1.bin");
+        let new_path = format!("{}/{}", fixure_path, "file_v2.bin");
+        let delta_path = std::env::temp_dir().join("xdelta3-rs-diff-files.vcdiff");
+        let patched_path = std::env::temp_dir().join("xdelta3-rs-diff-files.out");
+
+        diff_files(&old_path, &new_path, &delta_path).expect("failed to diff");
+        let stats = patch_files(&old_path, &delta_path, &patched_path).expect("failed to patch");
+
+        let patched = read_file(patched_path.to_str().unwrap());
+        std::fs::remove_file(&delta_path).ok();
+        std::fs::remove_file(&patched_path).ok();
+        assert_eq!(stats.output_bytes, patched.len() as u64);
+        assert_eq!(read_file(&new_path), patched);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn process_files_small_io_buffers() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let old_path = format!("{}/{}", fixure_path, "file_v1.bin");
+        let new_path = format!("{}/{}", fixure_path, "file_v2.bin");
+        let delta_path = std::env::temp_dir().join("xdelta3-rs-small-io.vcdiff");
+        let patched_path = std::env::temp_dir().join("xdelta3-rs-small-io.out");
+
+        let cfg = Xd3Config::new().io_buffer_size(17);
+        process_files(cfg, ProcessMode::Encode, &new_path, &old_path, &delta_path)
+            .expect("failed to encode");
+        let cfg = Xd3Config::new().io_buffer_size(17);
+        process_files(
+            cfg,
+            ProcessMode::Decode,
+            &delta_path,
+            &old_path,
+            &patched_path,
+        )
+        .expect("failed to decode");
+
+        let patched = read_file(patched_path.to_str().unwrap());
+        std::fs::remove_file(&delta_path).ok();
+        std::fs::remove_file(&patched_path).ok();
+        assert_eq!(read_file(&new_path), patched);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn flush_each_window() {
+        #[derive(Default)]
+        struct CountingWriter {
+            data: Vec<u8>,
+            writes: usize,
+            flushes: usize,
+        }
+
+        impl std::io::Write for CountingWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.writes += 1;
+                self.data.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.flushes += 1;
+                Ok(())
+            }
+        }
+
+        let source = pseudo_random(100_000, 7);
+        let mut input = pseudo_random(100_000, 8);
+        input[..50_000].copy_from_slice(&source[..50_000]);
+
+        let encode = |cfg: Xd3Config| {
+            let mut out = CountingWriter::default();
+            process(cfg, ProcessMode::Encode, &input[..], &source[..], &mut out)
+                .expect("failed to encode");
+            out
+        };
+
+        let buffered = encode(Xd3Config::new().window_size(1 << 14));
+        assert_eq!(buffered.flushes, 1);
+
+        let flushed = encode(
+            Xd3Config::new()
+                .window_size(1 << 14)
+                .flush_each_window(true),
+        );
+        assert!(flushed.writes > 1);
+        assert_eq!(flushed.flushes, flushed.writes + 1);
+        assert_eq!(flushed.data, buffered.data);
+        assert_eq!(input, check_decode(&flushed.data, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn decode_fits_source_window_to_delta() {
+        let source = pseudo_random(1 << 19, 9);
+        let (head, tail) = source.split_at(source.len() / 2);
+        let input = [tail, head].concat();
+
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().source_window_size(1 << 20);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+
+        // far too small for the delta's copies on its own
+        let cfg = Xd3Config::new().source_window_size(1 << 12);
+        let mut decoded = Vec::new();
+        process(
+            cfg,
+            ProcessMode::Decode,
+            &patch[..],
+            &source[..],
+            &mut decoded,
+        )
+        .expect("failed to decode");
+        assert_eq!(input, decoded);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn file_errors_name_the_path() {
+        let missing = std::env::temp_dir().join("xdelta3-rs-does-not-exist.bin");
+        let out_path = std::env::temp_dir().join("xdelta3-rs-missing-input.vcdiff");
+        let err = process_files(
+            Xd3Config::new(),
+            ProcessMode::Encode,
+            &missing,
+            &missing,
+            &out_path,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("xdelta3-rs-does-not-exist.bin"));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn encode_many_results_per_job() {
+        let source = pseudo_random(50_000, 10);
+        let mut input = source.clone();
+        input[20_000..20_100].copy_from_slice(&pseudo_random(100, 11));
+
+        let jobs = vec![(&input[..], &source[..]), (&source[..], &input[..])];
+        let results = encode_many(&Xd3Config::new(), jobs.iter().cloned());
+        assert_eq!(results.len(), 2);
+        let patch = results[0].as_ref().expect("failed to encode");
+        assert_eq!(input, check_decode(patch, &source));
+        let patch = results[1].as_ref().expect("failed to encode");
+        assert_eq!(source, check_decode(patch, &input));
+
+        let patches =
+            try_encode_many(&Xd3Config::new(), jobs.iter().cloned()).expect("failed to encode");
+        assert_eq!(patches.len(), 2);
+        assert_eq!(&patches[0], results[0].as_ref().unwrap());
+
+        // every job fails on its own and still gets its result
+        let expired = Xd3Config::new().deadline(std::time::Instant::now());
+        let results = encode_many(&expired, jobs.iter().cloned());
+        assert_eq!(results.len(), 2);
+        for result in &results {
+            let err = result.as_ref().unwrap_err();
+            assert!(matches!(err.xd3_error(), Some(Xd3Error::DeadlineExceeded)));
+        }
+        let err = try_encode_many(&expired, jobs).unwrap_err();
+        assert!(matches!(err.xd3_error(), Some(Xd3Error::DeadlineExceeded)));
+    }
+
+    #[test]
+    #[cfg(all(feature = "stream", feature = "testing"))]
+    fn cached_source_blocks_match_source() {
+        let source = pseudo_random(100_000, 12);
+        let mut input = source.clone();
+        input[60_000..60_100].copy_from_slice(&pseudo_random(100, 13));
+
+        // 2 KiB blocks
+        let cfg = Xd3Config::new().source_window_size(1 << 16);
+        let mut state = ProcessState::new(cfg, &source[..]).unwrap();
+        let mut patch = Vec::new();
+        futures::executor::block_on(async {
+            state.encode_segment(&input[..], &mut patch).await?;
+            state.finish_encode(&mut patch).await
+        })
+        .expect("failed to encode");
+
+        let blocks: Vec<_> = state.cached_source_blocks().collect();
+        assert!(!blocks.is_empty());
+        assert!(blocks.windows(2).all(|w| w[0].0 < w[1].0));
+        for (blkno, data) in blocks {
+            let start = blkno as usize * 2048;
+            assert_eq!(data, &source[start..start + data.len()]);
+        }
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(all(feature = "stream", feature = "testing"))]
+    fn block_observer_sees_cache_operations() {
+        use std::sync::{Arc, Mutex};
+
+        let source = pseudo_random(200_000, 116);
+        let mut input = source.clone();
+        input[150_000..150_100].copy_from_slice(&pseudo_random(100, 117));
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        // 2 KiB blocks, far fewer than the source has
+        let cfg = Xd3Config::new().source_window_size(1 << 16);
+        let mut state = ProcessState::new(cfg, &source[..])
+            .unwrap()
+            .with_block_observer(move |event| seen.lock().unwrap().push(event));
+        let mut patch = Vec::new();
+        futures::executor::block_on(state.encode_segment(&input[..], &mut patch))
+            .expect("failed to encode");
+        let stats = state
+            .flush_and_finish(&mut patch)
+            .expect("failed to finish");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let events = events.lock().unwrap();
+        let count = |f: fn(&BlockEvent) -> bool| events.iter().filter(|e| f(e)).count() as u64;
+        let src = &stats.source_stats;
+        assert_eq!(
+            count(|e| matches!(e, BlockEvent::BlockFetch { .. })),
+            src.blocks_read
+        );
+        assert_eq!(
+            count(|e| matches!(e, BlockEvent::BlockEvict { .. })),
+            src.blocks_evicted
+        );
+        assert!(src.blocks_evicted > 0);
+        assert_eq!(
+            count(|e| matches!(e, BlockEvent::BlockHit { .. }))
+                + count(|e| matches!(
+                    e,
+                    BlockEvent::BlockFetch {
+                        cache_miss: true,
+                        ..
+                    }
+                )),
+            src.blocks_requested
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn source_window_churn() {
+        // 16 KiB pages of the source in reverse order, so every target window copies from
+        // further back in the source than the one before
+        let source = pseudo_random(1 << 18, 140);
+        let input: Vec<u8> = source.chunks(1 << 14).rev().flatten().copied().collect();
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().window_size(1 << 14);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+
+        // 256 byte blocks and a cache of 66 of them, far less than a page
+        let decode = |cfg: Xd3Config| {
+            let src = futures::io::Cursor::new(&source);
+            let mut state = ProcessState::new(cfg, src)?.with_source_refetch();
+            let mut input = &patch[..];
+            let mut out = Vec::new();
+            loop {
+                use futures::executor::block_on;
+                use xdelta3::stream::xd3_rvalues::*;
+
+                match state.step(ProcessMode::Decode) {
+                    XD3_INPUT if state.is_eof() => break,
+                    XD3_INPUT => block_on(state.read_input(&mut input))?,
+                    XD3_OUTPUT => block_on(state.write_output(&mut out))?,
+                    XD3_GETSRCBLK => block_on(state.getblk())?,
+                    XD3_GOTHEADER | XD3_WINSTART | XD3_WINFINISH => {}
+                    res => panic!("decode failed: {:?}", res),
+                }
+            }
+            Ok::<_, std::io::Error>((out, state.into_stats()))
+        };
+        let cfg = Xd3Config::new().source_window_size(1 << 13);
+
+        // past the threshold this only warns
+        let (out, stats) = decode(cfg.clone()).expect("failed to decode");
+        assert_eq!(out, input);
+        assert!(stats.source_stats.blocks_refetched > 66);
+        assert!(stats.source_stats.blocks_read > stats.source_stats.blocks_refetched);
+
+        let err = decode(cfg.strict_source_window(true)).unwrap_err();
+        assert!(matches!(
+            err.xd3_error(),
+            Some(Xd3Error::SourceWindowTooSmall { .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn encode_compressed_round_trip() {
+        use xdelta3::stream::{encode_compressed, OuterCodec};
+
+        let source = pseudo_random(100_000, 118);
+        let mut input = source.clone();
+        input[30_000..30_200].copy_from_slice(&pseudo_random(200, 119));
+
+        let encode_with =
+            |outer| encode_compressed(&input[..], &source[..], outer, Xd3Config::new());
+
+        let patch = encode_with(OuterCodec::None).unwrap();
+        assert_eq!(input, check_decode(&patch, &source));
+
+        #[cfg(feature = "gzip")]
+        {
+            let compressed = encode_with(OuterCodec::Gzip).unwrap();
+            let mut patch = Vec::new();
+            flate2::read::GzDecoder::new(&compressed[..])
+                .read_to_end(&mut patch)
+                .unwrap();
+            assert_eq!(input, check_decode(&patch, &source));
+        }
+
+        #[cfg(feature = "zstd")]
+        {
+            let compressed = encode_with(OuterCodec::Zstd).unwrap();
+            let patch = zstd::decode_all(&compressed[..]).unwrap();
+            assert_eq!(input, check_decode(&patch, &source));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn compact_header_for_tiny_diffs() {
+        use xdelta3::stream::{encode_smallest, DeltaOrRaw};
+
+        let source = pseudo_random(100, 120);
+        let mut input = source.clone();
+        input[50] ^= 0xff;
+
+        let encode_with = |cfg, input: &[u8], source: &[u8]| {
+            let mut patch = Vec::new();
+            process(cfg, ProcessMode::Encode, input, source, &mut patch).expect("failed to encode");
+            patch
+        };
+        let compact = encode_with(
+            Xd3Config::new().compact_header(input.len()),
+            &input,
+            &source,
+        );
+        let checked = encode_with(Xd3Config::new().adler32(true), &input, &source);
+        assert!(compact.len() < checked.len());
+        assert!(compact.len() < input.len());
+        assert_eq!(validate_delta(&compact).unwrap().window_count, 1);
+        assert_eq!(input, check_decode(&compact, &source));
+
+        let smallest = encode_smallest(&input, &source).unwrap();
+        assert_eq!(smallest, DeltaOrRaw::Delta(compact));
+        assert_eq!(smallest.apply(&source).unwrap(), input);
+
+        // an input larger than the default window still gets a single window header
+        let block = pseudo_random(4096, 122);
+        let large: Vec<u8> = block
+            .iter()
+            .cycle()
+            .take(XD3_DEFAULT_WINSIZE + (1 << 20))
+            .cloned()
+            .collect();
+        let default = encode_with(Xd3Config::new(), &large, &block);
+        let compact = encode_with(Xd3Config::new().compact_header(large.len()), &large, &block);
+        assert_eq!(validate_delta(&default).unwrap().window_count, 2);
+        assert_eq!(validate_delta(&compact).unwrap().window_count, 1);
+        assert!(compact.len() < default.len());
+        assert_eq!(large, check_decode(&compact, &block));
+
+        // nothing to copy from: the raw target wins
+        let unrelated = pseudo_random(100, 121);
+        let smallest = encode_smallest(&unrelated, &source).unwrap();
+        assert_eq!(smallest, DeltaOrRaw::Raw(unrelated.clone()));
+        assert_eq!(smallest.apply(&source).unwrap(), unrelated);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn process_state_send_follows_reader() {
+        fn assert_send<T: Send>() {}
+        assert_send::<ProcessState<&'static [u8]>>();
+        assert_send::<ProcessState<futures::io::AllowStdIo<File>>>();
+        // `ProcessState<Rc<_>>` not being Send is checked by a compile_fail doctest
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn stream_init_and_free() {
+        // configures a zeroed xd3_stream, then frees it without processing anything
+        let state = ProcessState::new(Xd3Config::new(), &b""[..]).unwrap();
+        drop(state);
+    }
+
+    #[test]
+    #[cfg(all(feature = "stream", feature = "crc32fast"))]
+    fn decode_with_manifest_stops_at_bad_region() {
+        use xdelta3::checksum::{checksum, Crc32};
+        use xdelta3::stream::{decode_with_manifest, Manifest, ManifestEntry};
+
+        let source = pseudo_random(100_000, 122);
+        let mut input = source.clone();
+        input[20_000..20_100].copy_from_slice(&pseudo_random(100, 123));
+
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().window_size(1 << 14);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+
+        let mut manifest: Vec<_> = (0..10u64)
+            .map(|i| {
+                let offset = i * 10_000;
+                let region = &input[offset as usize..offset as usize + 10_000];
+                ManifestEntry {
+                    offset,
+                    len: 10_000,
+                    hash: checksum::<Crc32>(region),
+                }
+            })
+            .collect();
+
+        let mut out = Vec::new();
+        let good = Manifest::<Crc32>::new(manifest.clone());
+        decode_with_manifest(&patch[..], &source[..], &mut out, &good).expect("failed to decode");
+        assert_eq!(out, input);
+
+        manifest[5].hash ^= 1;
+        let bad = Manifest::<Crc32>::new(manifest);
+        let mut out = Vec::new();
+        let err = decode_with_manifest(&patch[..], &source[..], &mut out, &bad).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        match err.xd3_error() {
+            Some(Xd3Error::ManifestMismatch { offset, len }) => {
+                assert_eq!((*offset, *len), (50_000, 10_000));
+            }
+            other => panic!("expected ManifestMismatch, got {:?}", other),
+        }
+        // the window holding the end of the bad region was never written
+        assert!(out.len() < 60_000);
+        assert_eq!(&out[..], &input[..out.len()]);
+
+        // a region past the end of the target can't be verified
+        let past_end = Manifest::<Crc32>::new(vec![ManifestEntry {
+            offset: 95_000,
+            len: 10_000,
+            hash: 0,
+        }]);
+        let err = decode_with_manifest(&patch[..], &source[..], Vec::new(), &past_end).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn decode_with_manifest_hashes_once() {
+        use std::pin::Pin;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::task::{Context, Poll};
+        use xdelta3::checksum::{checksum, Hasher};
+        use xdelta3::stream::{decode_with_manifest_async, Manifest, ManifestEntry};
+
+        static HASHED: AtomicUsize = AtomicUsize::new(0);
+
+        // FNV-1a, counting every byte it is fed
+        struct Fnv(u64);
+
+        impl Default for Fnv {
+            fn default() -> Self {
+                Fnv(0xcbf2_9ce4_8422_2325)
+            }
+        }
+
+        impl Hasher for Fnv {
+            fn update(&mut self, data: &[u8]) {
+                HASHED.fetch_add(data.len(), Ordering::SeqCst);
+                for &b in data {
+                    self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x100_0000_01b3);
+                }
+            }
+
+            fn finish(&self) -> u64 {
+                self.0
+            }
+        }
+
+        // accepts at most 1000 bytes per write, and only every other poll
+        struct SlowWriter {
+            data: Vec<u8>,
+            ready: bool,
+        }
+
+        impl futures::io::AsyncWrite for SlowWriter {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<std::io::Result<usize>> {
+                if !self.ready {
+                    self.ready = true;
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                self.ready = false;
+                let len = std::cmp::min(buf.len(), 1000);
+                self.data.extend_from_slice(&buf[..len]);
+                Poll::Ready(Ok(len))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let source = pseudo_random(50_000, 126);
+        let mut input = source.clone();
+        input[10_000..10_100].copy_from_slice(&pseudo_random(100, 127));
+        let patch = encode(&input, &source).expect("failed to encode");
+
+        let manifest = Manifest::<Fnv>::new(vec![ManifestEntry {
+            offset: 0,
+            len: input.len() as u64,
+            hash: checksum::<Fnv>(&input),
+        }]);
+        HASHED.store(0, Ordering::SeqCst);
+        let mut out = SlowWriter {
+            data: Vec::new(),
+            ready: false,
+        };
+        futures::executor::block_on(decode_with_manifest_async(
+            &patch[..],
+            &source[..],
+            &mut out,
+            &manifest,
+        ))
+        .expect("failed to decode");
+        assert_eq!(out.data, input);
+        assert_eq!(HASHED.load(Ordering::SeqCst), input.len());
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn padded_windows_round_trip() {
+        use futures::io::AsyncReadExt;
+        use xdelta3::io::{AsyncUnpadReader, PADDED_FORMAT_VERSION, PADDED_MAGIC};
+
+        let source = pseudo_random(100_000, 124);
+        let mut input = source.clone();
+        input[40_000..41_000].copy_from_slice(&pseudo_random(1_000, 125));
+
+        let encode_with = |cfg| {
+            let mut patch = Vec::new();
+            process(
+                cfg,
+                ProcessMode::Encode,
+                &input[..],
+                &source[..],
+                &mut patch,
+            )
+            .expect("failed to encode");
+            patch
+        };
+        let cfg = Xd3Config::new().window_size(1 << 14);
+        let plain = encode_with(cfg.clone());
+        let padded = encode_with(cfg.clone().pad_windows_to(512));
+
+        // a stream header, then frames of a length prefix, the chunk and padding to a
+        // multiple of 512; the first frame also holds the stream header
+        assert_eq!(padded.len() % 512, 0);
+        assert_eq!(padded[..4], PADDED_MAGIC);
+        assert_eq!(padded[4], PADDED_FORMAT_VERSION);
+        let mut rest = &padded[..];
+        let mut prefix = 5;
+        let mut frames = 0;
+        let mut payload = Vec::new();
+        while !rest.is_empty() {
+            let header = &rest[prefix..prefix + 4];
+            let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let start = prefix + 4;
+            let frame_len = (start + len + 511) / 512 * 512;
+            payload.extend_from_slice(&rest[start..start + len]);
+            assert!(rest[start + len..frame_len].iter().all(|&b| b == 0));
+            rest = &rest[frame_len..];
+            prefix = 0;
+            frames += 1;
+        }
+        assert!(frames >= 7);
+        assert_eq!(payload, plain);
+
+        let mut out = Vec::new();
+        process(
+            cfg.pad_windows_to(512),
+            ProcessMode::Decode,
+            &padded[..],
+            &source[..],
+            &mut out,
+        )
+        .expect("failed to decode");
+        assert_eq!(out, input);
+
+        let mut unpadded = Vec::new();
+        futures::executor::block_on(
+            AsyncUnpadReader::new(&padded[..], 512).read_to_end(&mut unpadded),
+        )
+        .unwrap();
+        assert_eq!(unpadded, plain);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn padded_stream_version_is_checked() {
+        use futures::io::AsyncReadExt;
+        use xdelta3::io::{AsyncUnpadReader, PADDED_FORMAT_VERSION};
+
+        let source = pseudo_random(10_000, 126);
+        let mut input = source.clone();
+        input[4_000..4_100].copy_from_slice(&pseudo_random(100, 127));
+
+        let cfg = Xd3Config::new().pad_windows_to(256);
+        let mut padded = Vec::new();
+        process(
+            cfg.clone(),
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut padded,
+        )
+        .expect("failed to encode");
+
+        // as written by a later version of the framing
+        let mut newer = padded.clone();
+        newer[4] = PADDED_FORMAT_VERSION + 1;
+        let unsupported = |err: std::io::Error| {
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            match err.xd3_error() {
+                Some(Xd3Error::UnsupportedFormatVersion {
+                    found,
+                    max_supported,
+                }) => {
+                    assert_eq!(*found, PADDED_FORMAT_VERSION + 1);
+                    assert_eq!(*max_supported, PADDED_FORMAT_VERSION);
+                }
+                other => panic!("expected UnsupportedFormatVersion, got {:?}", other),
+            }
+        };
+        let mut out = Vec::new();
+        let err = futures::executor::block_on(
+            AsyncUnpadReader::new(&newer[..], 256).read_to_end(&mut out),
+        )
+        .unwrap_err();
+        unsupported(err);
+        let err = process(
+            cfg.clone(),
+            ProcessMode::Decode,
+            &newer[..],
+            &source[..],
+            Vec::new(),
+        )
+        .unwrap_err();
+        unsupported(err);
+
+        // a plain delta has no stream header
+        let plain = encode(&input, &source).unwrap();
+        let err = futures::executor::block_on(
+            AsyncUnpadReader::new(&plain[..], 256).read_to_end(&mut out),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut decoded = Vec::new();
+        process(
+            cfg,
+            ProcessMode::Decode,
+            &padded[..],
+            &source[..],
+            &mut decoded,
+        )
+        .expect("failed to decode");
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn sprevsz_validation() {
+        let err = Xd3Config::new()
+            .window_size(1 << 20)
+            .sprev_size(1 << 18)
+            .sprevsz_validated()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            Xd3ConfigError::SprevSzTooSmall {
+                sprevsz: 1 << 18,
+                winsize: 1 << 20,
+                recommended_min: 1 << 20,
+            }
+        );
+
+        assert!(Xd3Config::new()
+            .window_size(1 << 20)
+            .sprev_size(1 << 20)
+            .sprevsz_validated()
+            .is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn bits_setters() {
+        let err = Xd3Config::new()
+            .window_size_bits(20)
+            .sprevsz_bits(18)
+            .sprevsz_validated()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            Xd3ConfigError::SprevSzTooSmall {
+                sprevsz: 1 << 18,
+                winsize: 1 << 20,
+                recommended_min: 1 << 20,
+            }
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    #[should_panic]
+    fn sprevsz_bits_overflow() {
+        Xd3Config::new().sprevsz_bits(32);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn merge_applies_overrides() {
+        use xdelta3::stream::Xd3ConfigOverride;
+
+        let base = Xd3Config::new().window_size(1 << 20).sprev_size(1 << 18);
+        let merged = Xd3Config::merge(base.clone(), Xd3ConfigOverride::default());
+        assert!(merged.sprevsz_validated().is_err());
+
+        let overrides = Xd3ConfigOverride {
+            sprev_size: Some(1 << 20),
+            ..Default::default()
+        };
+        assert!(Xd3Config::merge(base.clone(), overrides)
+            .sprevsz_validated()
+            .is_ok());
+
+        let overrides = Xd3ConfigOverride {
+            window_size: Some(1 << 22),
+            ..Default::default()
+        };
+        let err = Xd3Config::merge(base, overrides)
+            .sprevsz_validated()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            Xd3ConfigError::SprevSzTooSmall {
+                sprevsz: 1 << 18,
+                winsize: 1 << 22,
+                recommended_min: 1 << 22,
+            }
+        );
+
+        // every field goes through its setter
+        let deadline = std::time::Instant::now();
+        let overrides = Xd3ConfigOverride {
+            window_size: Some(1 << 16),
+            sprev_size: Some(1 << 16),
+            iopt_size: Some(1024),
+            level: Some(9),
+            smatch_cfg: Some(xd3_smatch_cfg::XD3_SMATCH_SLOW),
+            no_compress: Some(true),
+            no_secondary_compression: Some(true),
+            adler32: Some(true),
+            source_window_size: Some(1 << 20),
+            source_window_scaling_factor: Some(4.0),
+            dictionary: Some(b"dictionary".to_vec()),
+            buffer_source: Some(true),
+            source_dedup: Some(true),
+            src_block_alignment: Some(4096),
+            strict_source_window: Some(true),
+            deterministic_windows: Some(true),
+            input_buffer_size: Some(1 << 17),
+            adaptive_winsize: Some((1 << 12, 1 << 16)),
+            output_buffer_size: Some(1 << 15),
+            flush_each_window: Some(true),
+            header_only: Some(true),
+            pad_windows_to: Some(512),
+            io_buffer_size: Some(1 << 14),
+            deadline: Some(deadline),
+        };
+        let expected = Xd3Config::new()
+            .window_size(1 << 16)
+            .sprev_size(1 << 16)
+            .iopt_size(1024)
+            .level(9)
+            .set_smatch_config(xd3_smatch_cfg::XD3_SMATCH_SLOW)
+            .no_compress(true)
+            .no_secondary_compression()
+            .adler32(true)
+            .source_window_size(1 << 20)
+            .source_window_scaling_factor(4.0)
+            .dictionary(b"dictionary".to_vec())
+            .buffer_source(true)
+            .source_dedup(true)
+            .src_block_alignment(4096)
+            .strict_source_window(true)
+            .deterministic_windows(true)
+            .input_buffer_size(1 << 17)
+            .adaptive_winsize(1 << 12, 1 << 16)
+            .buffer_output(1 << 15)
+            .flush_each_window(true)
+            .encode_header_only()
+            .pad_windows_to(512)
+            .io_buffer_size(1 << 14)
+            .deadline(deadline);
+        let merged = Xd3Config::merge(Xd3Config::new(), overrides);
+        assert_eq!(format!("{:?}", merged), format!("{:?}", expected));
+
+        // flags can be turned back off
+        let flags = |on| Xd3ConfigOverride {
+            no_compress: Some(on),
+            no_secondary_compression: Some(on),
+            adler32: Some(on),
+            ..Default::default()
+        };
+        let merged = Xd3Config::merge(Xd3Config::new(), flags(true));
+        let merged = Xd3Config::merge(merged, flags(false));
+        assert_eq!(format!("{:?}", merged), format!("{:?}", Xd3Config::new()));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn compression_level_range() {
+        use xdelta3::stream::CompressionLevel;
+
+        assert_eq!(CompressionLevel::new(9), Ok(CompressionLevel::BEST));
+        assert_eq!(
+            CompressionLevel::new(10),
+            Err(Xd3ConfigError::LevelOutOfRange { level: 10 })
+        );
+        assert!(Xd3Config::new().try_level(-5).is_err());
+
+        let source = pseudo_random(50_000, 31);
+        let mut input = source.clone();
+        input[1000..1100].copy_from_slice(&pseudo_random(100, 32));
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().compression_level(CompressionLevel::FASTEST);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn stats_sum() {
+        let stats = |input_bytes, blocks_read| EncodeStats {
+            input_bytes,
+            output_bytes: 10,
+            windows_processed: 1,
+            source_stats: SrcStats {
+                blocks_read,
+                ..SrcStats::default()
+            },
+            ..EncodeStats::default()
+        };
+        let batch = vec![stats(100, 2), stats(200, 3), stats(u64::MAX, 0)];
+
+        let total: EncodeStats = batch[..2].iter().cloned().sum();
+        assert_eq!(total.input_bytes, 300);
+        assert_eq!(total.output_bytes, 20);
+        assert_eq!(total.windows_processed, 2);
+        assert_eq!(total.source_stats.blocks_read, 5);
+
+        let mut total = total;
+        total += &batch[2];
+        assert_eq!(total.input_bytes, u64::MAX);
+        assert_eq!(total.windows_processed, 3);
+    }
+
+    #[test]
+    #[cfg(all(feature = "stream", feature = "serde"))]
+    fn stats_json_round_trip() {
+        let stats = EncodeStats {
+            input_bytes: 1000,
+            output_bytes: 250,
+            windows_processed: 2,
+            source_stats: SrcStats {
+                blocks_read: 3,
+                ..SrcStats::default()
+            },
+            ..EncodeStats::default()
+        };
+
+        let json = stats.to_json();
+        assert!(json.contains("\"inputBytes\":1000"), "{}", json);
+        assert!(json.contains("\"blocksRead\":3"), "{}", json);
+        assert!(json.contains("\"compressionRatio\":0.25"), "{}", json);
+
+        let parsed = EncodeStats::from_json(&json).expect("failed to parse");
+        assert_eq!(parsed.to_json(), json);
+    }
+
+    #[test]
+    #[cfg(all(feature = "stream", feature = "toml"))]
+    fn toml_round_trip() {
+        let cfg = Xd3Config::from_toml_str(
+            r#"
+            window_size = "1M"
+            sprev_size = 262144
+            level = 6
+            smatch_cfg = "fast"
+            source_dedup = true
+            strict_source_window = true
+            io_buffer_size = "64K"
+            adler32 = true
+            secondary_compression = "fgk"
+            no_secondary_compression = true
+            adaptive_winsize = ["64K", 4194304]
+            header_only = true
+            pad_windows_to = 512
+            "#,
+        )
+        .expect("failed to parse");
+        let toml = cfg.to_toml_string();
+        assert!(toml.contains("window_size = \"1M\""), "{}", toml);
+        assert!(toml.contains("sprev_size = \"256K\""), "{}", toml);
+        assert!(toml.contains("smatch_cfg = \"fast\""), "{}", toml);
+        assert!(toml.contains("level = 6"), "{}", toml);
+        assert!(toml.contains("strict_source_window = true"), "{}", toml);
+        assert!(toml.contains("adler32 = true"), "{}", toml);
+        assert!(toml.contains("secondary_compression = \"fgk\""), "{}", toml);
+        assert!(toml.contains("no_secondary_compression = true"), "{}", toml);
+        assert!(
+            toml.contains("adaptive_winsize = [\"64K\", \"4M\"]"),
+            "{}",
+            toml
+        );
+        assert!(toml.contains("header_only = true"), "{}", toml);
+        assert!(toml.contains("pad_windows_to = \"512\""), "{}", toml);
+
+        let again = Xd3Config::from_toml_str(&toml).expect("failed to parse");
+        assert_eq!(again.to_toml_string(), toml);
+
+        assert!(matches!(
+            Xd3Config::from_toml_str("level = 12"),
+            Err(Xd3ConfigError::LevelOutOfRange { level: 12 })
+        ));
+        // 2^32 + 6, which would wrap to 6 if truncated
+        assert!(matches!(
+            Xd3Config::from_toml_str("level = 4294967302"),
+            Err(Xd3ConfigError::LevelOutOfRange { .. })
+        ));
+        assert!(matches!(
+            Xd3Config::from_toml_str("window_size = \"4G\""),
+            Err(Xd3ConfigError::InvalidToml(_))
+        ));
+        // would wrap to 0, unlimited, if truncated to xdelta3's u32
+        assert!(matches!(
+            Xd3Config::from_toml_str("iopt_size = \"4G\""),
+            Err(Xd3ConfigError::InvalidToml(_))
+        ));
+        assert!(matches!(
+            Xd3Config::from_toml_str("adaptive_winsize = [\"1M\", \"64K\"]"),
+            Err(Xd3ConfigError::InvalidToml(_))
+        ));
+        assert!(matches!(
+            Xd3Config::from_toml_str("window_size = \"8X\""),
+            Err(Xd3ConfigError::InvalidToml(_))
+        ));
+        assert!(matches!(
+            Xd3Config::from_toml_str("windowsize = 1"),
+            Err(Xd3ConfigError::InvalidToml(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn encode_to_budget_picks_lowest_level() {
+        use xdelta3::stream::{encode_to_budget, CompressionLevel};
+
+        // short slices of the source at arbitrary offsets, which only a matcher that
+        // indexes the source densely finds
+        let source = pseudo_random(100_000, 77);
+        let offsets = pseudo_random(4 * 5_000, 78);
+        let mut input = Vec::new();
+        for at in offsets.chunks(4) {
+            let at = u32::from_le_bytes([at[0], at[1], at[2], at[3]]) as usize;
+            let at = at % (source.len() - 20);
+            input.extend_from_slice(&source[at..at + 20]);
+        }
+
+        let encode_at = |level| {
+            let cfg = Xd3Config::new().compression_level(level);
+            let mut patch = Vec::new();
+            process(
+                cfg,
+                ProcessMode::Encode,
+                &input[..],
+                &source[..],
+                &mut patch,
+            )
+            .expect("failed to encode");
+            patch
+        };
+        let fastest = encode_at(CompressionLevel::FASTEST);
+        let best = encode_at(CompressionLevel::BEST);
+        assert!(best.len() < fastest.len());
+
+        let (level, patch) = encode_to_budget(&input, &source, u64::MAX)
+            .unwrap()
+            .expect("unlimited budget missed");
+        assert_eq!(level, CompressionLevel::FASTEST);
+        assert_eq!(patch, fastest);
+
+        // only reachable at a high level
+        let (level, patch) = encode_to_budget(&input, &source, best.len() as u64)
+            .unwrap()
+            .expect("reachable budget missed");
+        assert!(level > CompressionLevel::FASTEST);
+        assert!(patch.len() <= best.len());
+        assert_eq!(input, check_decode(&patch, &source));
+
+        assert!(encode_to_budget(&input, &source, 0).unwrap().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn stream_state_snapshot() {
+        use xdelta3::stream::{xd3_decode_state, xd3_encode_state};
+
+        let source = pseudo_random(20_000, 114);
+        let patch = encode(&source, &source).unwrap();
+
+        let mut state = ProcessState::new(Xd3Config::new(), &source[..]).unwrap();
+        let initial = state.stream_state();
+        assert_eq!(initial.encode, xd3_encode_state::ENC_INIT);
+        assert_eq!(initial.decode, xd3_decode_state::DEC_VCHEAD);
+        assert_eq!(initial.to_string(), "ENC_INIT/DEC_VCHEAD");
+
+        // drive the decoder by hand, the way process_async does
+        let mut input = &patch[..];
+        let mut out = Vec::new();
+        loop {
+            use futures::executor::block_on;
+            use xdelta3::stream::xd3_rvalues::*;
+
+            match state.step(ProcessMode::Decode) {
+                XD3_INPUT if state.is_eof() => break,
+                XD3_INPUT => block_on(state.read_input(&mut input)).unwrap(),
+                XD3_OUTPUT => block_on(state.write_output(&mut out)).unwrap(),
+                XD3_GETSRCBLK => block_on(state.getblk()).unwrap(),
+                XD3_GOTHEADER | XD3_WINSTART | XD3_WINFINISH => {}
+                res => panic!("decode failed: {:?}", res),
+            }
+        }
+        assert_eq!(out, source);
+        assert_eq!(state.stream_state().decode, xd3_decode_state::DEC_WININD);
+        assert_eq!(state.stream_state().encode, xd3_encode_state::ENC_INIT);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn source_window_callback_per_segment() {
+        use std::sync::{Arc, Mutex};
+
+        // every target window copies its own 16 KiB of the source
+        let source = pseudo_random(1 << 17, 115);
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().window_size(1 << 14);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &source[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+
+        let windows = Arc::new(Mutex::new(Vec::new()));
+        let seen = windows.clone();
+        let cfg = Xd3Config::new().source_window_size(1 << 16);
+        let mut state = ProcessState::new(cfg, &source[..]).unwrap();
+        state.set_source_window_callback(move |start, len| seen.lock().unwrap().push((start, len)));
+
+        let mut input = &patch[..];
+        let mut out = Vec::new();
+        loop {
+            use futures::executor::block_on;
+            use xdelta3::stream::xd3_rvalues::*;
+
+            match state.step(ProcessMode::Decode) {
+                XD3_INPUT if state.is_eof() => break,
+                XD3_INPUT => block_on(state.read_input(&mut input)).unwrap(),
+                XD3_OUTPUT => block_on(state.write_output(&mut out)).unwrap(),
+                XD3_GETSRCBLK => block_on(state.getblk()).unwrap(),
+                XD3_GOTHEADER | XD3_WINSTART | XD3_WINFINISH => {}
+                res => panic!("decode failed: {:?}", res),
+            }
+        }
+        assert_eq!(out, source);
+
+        let windows = windows.lock().unwrap();
+        assert_eq!(windows.len(), 8);
+        assert!(windows.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(windows.iter().all(|&(_, len)| len > 0));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn current_window_index_counts_windows() {
+        let source = pseudo_random(100_000, 75);
+        let mut input = source.clone();
+        input[80_000..80_100].copy_from_slice(&pseudo_random(100, 76));
+
+        let cfg = Xd3Config::new().window_size(1 << 14);
+        let mut state = ProcessState::new(cfg, &source[..]).unwrap();
+        assert_eq!(state.current_window_index(), 0);
+
+        let mut patch = Vec::new();
+        futures::executor::block_on(state.encode_segment(&input[..], &mut patch))
+            .expect("failed to encode");
+        let stats = state
+            .flush_and_finish(&mut patch)
+            .expect("failed to finish");
+        assert_eq!(stats.windows_processed, 7);
+        assert_eq!(state.current_window_index(), stats.windows_processed);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn source_block_count_hint() {
+        let source = pseudo_random(10_000, 70);
+        let mut input = source.clone();
+        input[5_000..5_010].copy_from_slice(&pseudo_random(10, 71));
+
+        // 2 KiB blocks: four full ones and 1808 bytes
+        let cfg = || Xd3Config::new().source_window_size(1 << 16);
+        let mut state = ProcessState::new(cfg(), &source[..]).unwrap();
+        assert!(state.source_block_count_hint(0, 100).is_err());
+        assert!(state.source_block_count_hint(5, 2049).is_err());
+        state.source_block_count_hint(5, 1808).unwrap();
+        assert_eq!(state.expected_source_size(), Some(10_000));
+        assert_eq!(state.expected_source_blocks(), Some(5));
+
+        let mut patch = Vec::new();
+        futures::executor::block_on(state.encode_segment(&input[..], &mut patch))
+            .expect("failed to encode");
+        state
+            .flush_and_finish(&mut patch)
+            .expect("failed to finish");
+        assert!(state.source_block_count_hint(5, 1808).is_err());
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn tiny_source_window() {
+        let source = pseudo_random(300, 142);
+        let mut input = source.clone();
+        input[100..110].copy_from_slice(&pseudo_random(10, 143));
+
+        // fewer bytes than blocks per window: one byte per block
+        let cfg = || Xd3Config::new().source_window_size(16);
+        let mut state =
+            ProcessState::with_source_size(cfg(), &source[..], source.len() as u64).unwrap();
+        assert_eq!(state.expected_source_blocks(), Some(300));
+
+        let mut patch = Vec::new();
+        futures::executor::block_on(state.encode_segment(&input[..], &mut patch))
+            .expect("failed to encode");
+        state
+            .flush_and_finish(&mut patch)
+            .expect("failed to finish");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let mut patch = Vec::new();
+        process(
+            cfg(),
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn source_window_scaling_factor_follows_window() {
+        let source = pseudo_random(100_000, 60);
+        let input = source.clone();
+
+        // a 16 KiB window scaled by 2 gives a 32 KiB source window, i.e. 1 KiB blocks
+        let cfg = Xd3Config::new()
+            .source_window_scaling_factor(2.0)
+            .window_size(1 << 14);
+        let state = ProcessState::with_source_size(cfg, &source[..], source.len() as u64).unwrap();
+        assert_eq!(state.expected_source_blocks(), Some(98));
+
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new()
+            .window_size(1 << 14)
+            .source_window_scaling_factor(8.0);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    #[should_panic]
+    fn source_window_scaling_factor_below_one() {
+        Xd3Config::new().source_window_scaling_factor(0.5);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn input_buffer_size_sets_read_chunks() {
+        struct CountingReader<'a> {
+            data: &'a [u8],
+            reads: usize,
+        }
+
+        impl futures::io::AsyncRead for CountingReader<'_> {
+            fn poll_read(
+                mut self: std::pin::Pin<&mut Self>,
+                _cx: &mut std::task::Context<'_>,
+                buf: &mut [u8],
+            ) -> std::task::Poll<std::io::Result<usize>> {
+                self.reads += 1;
+                let len = buf.len().min(self.data.len());
+                buf[..len].copy_from_slice(&self.data[..len]);
+                self.data = &self.data[len..];
+                std::task::Poll::Ready(Ok(len))
+            }
+        }
+
+        let source = pseudo_random(200_000, 14);
+        let mut input = source.clone();
+        input[150_000..150_100].copy_from_slice(&pseudo_random(100, 15));
+
+        let encode = |input_buffer_size: usize| {
+            let cfg = Xd3Config::new().input_buffer_size(input_buffer_size);
+            let mut reader = CountingReader {
+                data: &input,
+                reads: 0,
+            };
+            let mut patch = Vec::new();
+            futures::executor::block_on(process_async(
+                cfg,
+                ProcessMode::Encode,
+                &mut reader,
+                &source[..],
+                &mut patch,
+            ))
+            .expect("failed to encode");
+            (patch, reader.reads)
+        };
+
+        let (small_patch, small_reads) = encode(4096);
+        let (large_patch, large_reads) = encode(1 << 20);
+        assert_eq!(input, check_decode(&small_patch, &source));
+        assert_eq!(input, check_decode(&large_patch, &source));
+        assert!(large_reads < small_reads);
+
+        // an empty buffer would read nothing and end the input right away
+        let (tiny_patch, tiny_reads) = encode(0);
+        assert_eq!(input, check_decode(&tiny_patch, &source));
+        assert!(tiny_reads > input.len());
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn decode_with_generated_source() {
+        const SOURCE_LEN: u64 = 300_000;
+        let byte_at = |i: u64| (i.wrapping_mul(2_654_435_761) >> 13) as u8;
+
+        let source: Vec<u8> = (0..SOURCE_LEN).map(byte_at).collect();
+        let mut input = source.clone();
+        input[1000..1100].copy_from_slice(&pseudo_random(100, 16));
+        let patch = encode2(&input, &source).expect("failed to encode");
+
+        let mut calls = 0;
+        let decoded = decode_with_source_fn(&patch, |offset, len| {
+            calls += 1;
+            let end = std::cmp::min(offset + len as u64, SOURCE_LEN);
+            (offset..end).map(byte_at).collect()
+        })
+        .expect("failed to decode");
+        assert!(calls > 0);
+        assert_eq!(input, decoded);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn encode_header_only() {
+        let source = pseudo_random(50_000, 17);
+        let input = pseudo_random(50_000, 18);
+        let full = encode2(&input, &source).expect("failed to encode");
+
+        let mut header = Vec::new();
+        let cfg = Xd3Config::new().encode_header_only();
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut header,
+        )
+        .expect("failed to encode");
+
+        assert!(full.starts_with(&header));
+        assert_eq!(validate_delta(&header).unwrap().window_count, 0);
+        assert!(check_decode(&header, &source).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn expected_source_blocks() {
+        let source = pseudo_random(10_000, 19);
+        // 2 KiB blocks
+        let cfg = Xd3Config::new().source_window_size(1 << 16);
+        let state = ProcessState::with_source_size(cfg, &source[..], source.len() as u64).unwrap();
+        assert_eq!(state.expected_source_size(), Some(10_000));
+        assert_eq!(state.expected_source_blocks(), Some(5));
+
+        let state = ProcessState::new(Xd3Config::new(), &source[..]).unwrap();
+        assert_eq!(state.expected_source_size(), None);
+        assert_eq!(state.expected_source_blocks(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn source_block_progress() {
+        let source = pseudo_random(10_000, 19);
+        let mut input = source.clone();
+        input[9_000..9_100].copy_from_slice(&pseudo_random(100, 20));
+        // 2 KiB blocks
+        let cfg = Xd3Config::new().source_window_size(1 << 16);
+
+        let mut state =
+            ProcessState::with_source_size(cfg.clone(), &source[..], source.len() as u64).unwrap();
+        assert_eq!(state.total_source_blocks(), Some(5));
+        assert_eq!(state.remaining_source_blocks(), Some(5));
+        let mut patch = Vec::new();
+        futures::executor::block_on(state.encode_segment(&input[..], &mut patch)).unwrap();
+        state.flush_and_finish(&mut patch).unwrap();
+        assert_eq!(state.total_source_blocks(), Some(5));
+        assert_eq!(state.remaining_source_blocks(), Some(0));
+
+        let mut state = ProcessState::new(cfg, &source[..]).unwrap();
+        assert_eq!(state.total_source_blocks(), None);
+        assert_eq!(state.remaining_source_blocks(), None);
+        let mut patch = Vec::new();
+        futures::executor::block_on(state.encode_segment(&input[..], &mut patch)).unwrap();
+        state.flush_and_finish(&mut patch).unwrap();
+        assert_eq!(state.total_source_blocks(), Some(5));
+        assert_eq!(state.remaining_source_blocks(), Some(0));
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn align_to_pages() {
+        const PAGE: usize = 4096;
+        let encode = |cfg, input: &[u8], source: &[u8]| {
+            let mut patch = Vec::new();
+            process(cfg, ProcessMode::Encode, input, source, &mut patch).expect("failed to encode");
+            assert_eq!(input, &check_decode(&patch, source)[..]);
+            patch.len()
+        };
+        // the same pages in a different order, with one of them rewritten
+        let shuffle = |source: &[u8]| {
+            let mut input = Vec::new();
+            for page in (0..64).rev() {
+                input.extend_from_slice(&source[page * PAGE..(page + 1) * PAGE]);
+            }
+            input[10 * PAGE..11 * PAGE].copy_from_slice(&pseudo_random(PAGE, 21));
+            input
+        };
+
+        let source = pseudo_random(64 * PAGE, 20);
+        let input = shuffle(&source);
+        let aligned = encode(Xd3Config::new().align_to(PAGE as u32), &input, &source);
+        assert!(aligned < 4 * PAGE);
+        assert!(aligned <= encode(Xd3Config::new(), &input, &source));
+
+        // low-entropy pages, where every short string occurs all over the source
+        let source: Vec<u8> = source.iter().map(|b| b >> 7).collect();
+        let input = shuffle(&source);
+        let aligned = encode(Xd3Config::new().align_to(PAGE as u32), &input, &source);
+        let default = encode(Xd3Config::new(), &input, &source);
+        assert!(aligned < default / 2, "{} {}", aligned, default);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn match_effort_presets_round_trip() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        for &effort in &[
+            MatchEffort::Exhaustive,
+            MatchEffort::Balanced,
+            MatchEffort::Fast,
+        ] {
+            let mut patch = Vec::new();
+            let cfg = Xd3Config::new().match_effort(effort);
+            process(
+                cfg,
+                ProcessMode::Encode,
+                &input[..],
+                &source[..],
+                &mut patch,
+            )
+            .expect("failed to encode");
+            assert_eq!(input, check_decode(&patch, &source), "{:?}", effort);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn decode_at_offset() {
+        let source = pseudo_random(50_000, 55);
+        let mut input = source.clone();
+        input[20_000..20_100].copy_from_slice(&pseudo_random(100, 56));
+        let patch = encode(&input, &source).unwrap();
+
+        let path = std::env::temp_dir().join(format!("xdelta3-decode-at-{}", std::process::id()));
+        let filler = vec![0xAAu8; 100_000];
+        std::fs::write(&path, &filler).unwrap();
+
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let len = decode_at(&patch[..], &source[..], &file, 30_000).expect("failed to decode");
+        drop(file);
+        assert_eq!(len, input.len() as u64);
+
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.len(), filler.len());
+        assert_eq!(&written[..30_000], &filler[..30_000]);
+        assert_eq!(&written[30_000..80_000], &input[..]);
+        assert_eq!(&written[80_000..], &filler[80_000..]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "stream", any(unix, windows)))]
+    fn patch_same_file_with_overlapping_copies() {
+        use xdelta3::stream::patch_same_file;
+
+        // the target swaps the first and last thirds, so the start of the file is
+        // overwritten before the copy that reads it
+        let source = pseudo_random(60_000, 112);
+        let mut input = Vec::new();
+        input.extend_from_slice(&source[40_000..]);
+        input.extend_from_slice(&source[20_000..40_000]);
+        input.extend_from_slice(&source[..20_000]);
+        input.extend_from_slice(&pseudo_random(5_000, 113));
+
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().window_size(1 << 14);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+
+        let path = std::env::temp_dir().join(format!("xdelta3-same-file-{}", std::process::id()));
+        std::fs::write(&path, &source).unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let len = patch_same_file(&mut file, &patch[..]).expect("failed to patch");
+        drop(file);
+        assert_eq!(len, input.len() as u64);
+
+        let patched = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(patched, input);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn parallel_encode_four_files() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let old_path = format!("{}/{}", fixure_path, "file_v1.bin");
+        let new_path = format!("{}/{}", fixure_path, "file_v2.bin");
+
+        let jobs: Vec<_> = (0..4)
+            .map(|i| EncodeJob {
+                new_file: new_path.clone().into(),
+                old_file: old_path.clone().into(),
+                patch_file: std::env::temp_dir().join(format!("xdelta3-rs-parallel-{}.vcdiff", i)),
+            })
+            .collect();
+        let results =
+            parallel_encode_files_with_threads(&Xd3Config::new(), jobs.clone(), 2).unwrap();
+
+        assert_eq!(results.len(), 4);
+        let source = read_file(&old_path);
+        for (job, result) in jobs.iter().zip(results) {
+            let stats = result.expect("failed to encode");
+            let patch = read_file(job.patch_file.to_str().unwrap());
+            std::fs::remove_file(&job.patch_file).ok();
+            assert_eq!(stats.output_bytes, patch.len() as u64);
+            assert_eq!(read_file(&new_path), check_decode(&patch, &source));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn encode_adaptive_round_trip() {
+        let source = pseudo_random(1 << 18, 26);
+        let (head, tail) = source.split_at(source.len() / 2);
+        let input = [tail, head].concat();
+
+        let cfg = Xd3Config::new().source_window_size(1 << 12);
+        // what the first attempt runs into
+        let err = process(
+            cfg.clone(),
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            Vec::new(),
+        )
+        .unwrap_err();
+        match err.xd3_error() {
+            Some(Xd3Error::SourceWindowTooSmall { .. }) => {}
+            Some(Xd3Error::Stream { code, .. }) if *code == xd3_rvalues::XD3_TOOFARBACK as i32 => {}
+            other => panic!("expected a too small source window, got {:?}", other),
+        }
+
+        let mut attempts = 0;
+        let patch = encode_adaptive(
+            &input,
+            || {
+                attempts += 1;
+                Ok(&source[..])
+            },
+            cfg,
+        )
+        .expect("failed to encode");
+        assert!(attempts > 1);
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn small_iopt_size_round_trip() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().iopt_size(16);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn sparse_source_round_trip() {
+        use xdelta3::io::SparseReader;
+
+        let head: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let tail: Vec<u8> = (0..50_000u32).map(|i| (i % 13) as u8).collect();
+        let len = 400_000;
+        let sparse = || SparseReader::new(vec![(250_000, tail.clone()), (0, head.clone())], len);
+
+        let mut dense = vec![0u8; len as usize];
+        dense[..head.len()].copy_from_slice(&head);
+        dense[250_000..300_000].copy_from_slice(&tail);
+        let mut out = Vec::new();
+        sparse().unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, dense);
+
+        let mut input = dense.clone();
+        input[120_000..120_100].copy_from_slice(&[7u8; 100]);
+        input.extend_from_slice(b"appended");
+
+        let mut patch = Vec::new();
+        process(
+            Xd3Config::new(),
+            ProcessMode::Encode,
+            &input[..],
+            sparse().unwrap(),
+            &mut patch,
+        )
+        .expect("failed to encode");
+        assert!(patch.len() < 1000);
+
+        let mut decoded = Vec::new();
+        process(
+            Xd3Config::new(),
+            ProcessMode::Decode,
+            &patch[..],
+            sparse().unwrap(),
+            &mut decoded,
+        )
+        .expect("failed to decode");
+        assert_eq!(decoded, input);
+
+        assert!(SparseReader::new(vec![(0, vec![1; 10]), (5, vec![2; 10])], 100).is_err());
+        assert!(SparseReader::new(vec![(95, vec![1; 10])], 100).is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "stream", feature = "sha2"))]
+    fn encode_addressed_is_reproducible() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let encode = || {
+            let cfg = Xd3Config::new().deterministic_windows(true);
+            xdelta3::stream::encode_addressed(&input[..], &source[..], cfg)
+                .expect("failed to encode")
+        };
+        let (patch, hash) = encode();
+        assert_eq!(encode(), (patch.clone(), hash));
+        assert_ne!(hash, [0u8; 32]);
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(all(feature = "stream", feature = "sha2"))]
+    fn hashing_writers_match_encode_addressed() {
+        use xdelta3::io::{AsyncHashingWriter, HashingWriter};
+
+        let source = pseudo_random(100_000, 63);
+        let mut input = source.clone();
+        input[50_000..50_100].copy_from_slice(&pseudo_random(100, 64));
+        let cfg = || Xd3Config::new().deterministic_windows(true);
+
+        let (expected, expected_hash) =
+            xdelta3::stream::encode_addressed(&input[..], &source[..], cfg())
+                .expect("failed to encode");
+
+        let mut out = HashingWriter::<_, sha2::Sha256>::new(Vec::new());
+        process(
+            cfg(),
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut out,
+        )
+        .expect("failed to encode");
+        let (patch, hash) = out.finalize();
+        assert_eq!(patch, expected);
+        assert_eq!(hash[..], expected_hash[..]);
+
+        let mut out = AsyncHashingWriter::<_, sha2::Sha256>::new(Vec::new());
+        futures::executor::block_on(process_async(
+            cfg(),
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut out,
+        ))
+        .expect("failed to encode");
+        let (patch, hash) = out.finalize();
+        assert_eq!(patch, expected);
+        assert_eq!(hash[..], expected_hash[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn resume_encode_from_checkpoint() {
+        use xdelta3::stream::{encode_with_checkpoints, resume_encode};
+
+        let input: Vec<u8> = (0..300_000u32)
+            .map(|i| ((i / 7) % 253) as u8 ^ (i % 3) as u8)
+            .collect();
+        let cfg = || {
+            Xd3Config::new()
+                .window_size(1 << 16)
+                .deterministic_windows(true)
+        };
+
+        let mut full = Vec::new();
+        let mut checkpoints = Vec::new();
+        encode_with_checkpoints(cfg(), &input[..], &[][..], &mut full, |c| {
+            checkpoints.push(c)
+        })
+        .expect("failed to encode");
+        assert_eq!(checkpoints.len(), 5);
+        assert_eq!(checkpoints[1].input_offset, 2 << 16);
+        assert_eq!(checkpoints.last().unwrap().output_len, full.len() as u64);
+
+        // interrupted after the second window
+        let checkpoint = checkpoints[1];
+        let mut resumed = full[..checkpoint.output_len as usize].to_vec();
+        let mut later = Vec::new();
+        resume_encode(
+            cfg(),
+            checkpoint,
+            std::io::Cursor::new(&input),
+            &[][..],
+            &mut resumed,
+            |c| later.push(c),
+        )
+        .expect("failed to resume");
+        assert_eq!(resumed, full);
+        assert_eq!(later, &checkpoints[2..]);
+        assert_eq!(input, check_decode(&resumed, &[]));
+
+        // with a source the resumed delta could differ, so resuming is refused
+        let source = pseudo_random(10_000, 128);
+        let err = resume_encode(
+            cfg(),
+            checkpoint,
+            std::io::Cursor::new(&input),
+            &source[..],
+            Vec::new(),
+            |_| (),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // settings the checkpointed path doesn't apply are rejected, not ignored
+        for cfg in vec![
+            cfg().buffer_source(true),
+            cfg().dictionary(source.clone()),
+            cfg().adaptive_winsize(1 << 12, 1 << 16),
+        ] {
+            let err =
+                encode_with_checkpoints(cfg, &input[..], &[][..], Vec::new(), |_| ()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn concurrent_decodes_share_source() {
+        use std::sync::Arc;
+
+        let source: Arc<[u8]> = pseudo_random(300_000, 35).into();
+        let jobs: Vec<(Vec<u8>, Vec<u8>)> = (0..4)
+            .map(|i| {
+                let mut input = source.to_vec();
+                let at = 50_000 * (i + 1);
+                input[at..at + 100].copy_from_slice(&pseudo_random(100, 36 + i as u32));
+                let patch = encode(&input, &source).unwrap();
+                (input, patch)
+            })
+            .collect();
+
+        let handles: Vec<_> = jobs
+            .into_iter()
+            .map(|(input, patch)| {
+                let source = Arc::clone(&source);
+                std::thread::spawn(move || {
+                    let decoded =
+                        xdelta3::stream::decode_shared(&patch, source).expect("failed to decode");
+                    assert_eq!(decoded, input);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(Arc::strong_count(&source), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn window_buffer_small_writes() {
+        use xdelta3::buffer::WindowBuffer;
+
+        let source: Vec<u8> = (0..2000)
+            .flat_map(|i| format!("line {} of the old log\n", i).into_bytes())
+            .collect();
+        let lines: Vec<Vec<u8>> = (0..2000)
+            .map(|i| format!("line {} of the new log\n", i).into_bytes())
+            .collect();
+        let input = lines.concat();
+
+        let cfg = Xd3Config::new().window_size(1 << 14);
+        let mut buffer = WindowBuffer::new(cfg, &source[..], Vec::new()).unwrap();
+        for line in &lines {
+            buffer.write(line).unwrap();
+        }
+        assert!(buffer.buffered() < 1 << 14);
+        let stats = buffer.flush().expect("failed to encode");
+        assert!(buffer.write(b"late").is_err());
+        let patch = buffer.into_inner();
+
+        assert_eq!(stats.input_bytes, input.len() as u64);
+        assert_eq!(stats.output_bytes, patch.len() as u64);
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn every_window_has_target_length() {
+        let source = pseudo_random(100_000, 40);
+        let mut input = source.clone();
+        input[70_000..70_100].copy_from_slice(&pseudo_random(100, 41));
+
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().window_size(1 << 14);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+
+        let info = validate_delta(&patch).expect("valid delta rejected");
+        assert_eq!(info.window_count, 7);
+        assert_eq!(info.total_target_size, input.len() as u64);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn truncated_delta_fails_to_decode() {
+        let source = pseudo_random(100_000, 42);
+        let mut input = source.clone();
+        input[30_000..30_100].copy_from_slice(&pseudo_random(100, 43));
+
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().window_size(1 << 14);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+
+        for len in &[0, 3, 10, patch.len() - 1] {
+            let mut out = Vec::new();
+            let result = process(
+                Xd3Config::new(),
+                ProcessMode::Decode,
+                &patch[..*len],
+                &source[..],
+                &mut out,
+            );
+            assert!(result.is_err(), "{} byte prefix decoded", len);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "stream", feature = "bytes"))]
+    fn process_bytes_chunks_concatenate() {
+        let source = pseudo_random(100_000, 44);
+        let mut input = source.clone();
+        input[60_000..60_100].copy_from_slice(&pseudo_random(100, 45));
+
+        let cfg = || Xd3Config::new().window_size(1 << 14);
+        let mut chunks = Vec::new();
+        xdelta3::stream::process_bytes(
+            cfg(),
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            |chunk| chunks.push(chunk),
+        )
+        .expect("failed to encode");
+        assert!(chunks.len() > 1);
+
+        let mut patch = Vec::new();
+        process(
+            cfg(),
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+        assert_eq!(chunks.concat(), patch);
+    }
+
+    /// Serve `data` over HTTP on a local port, answering `HEAD` and ranged `GET` requests
+    /// for any path. Returns the server's URL and the number of `GET`s answered so far.
+    #[cfg(any(feature = "http-source", feature = "s3"))]
+    fn serve_ranges(data: Vec<u8>) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{BufRead, BufReader, Write};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let gets = Arc::new(AtomicUsize::new(0));
+        let counter = gets.clone();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let mut reader = BufReader::new(conn.try_clone().unwrap());
+                let mut request = String::new();
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    let lower = line.to_ascii_lowercase();
+                    if let Some(spec) = lower.strip_prefix("range: bytes=") {
+                        let (start, end) = spec.trim().split_once('-').unwrap();
+                        range = Some((
+                            start.parse::<usize>().unwrap(),
+                            end.parse::<usize>().unwrap(),
+                        ));
+                    }
+                    if request.is_empty() {
+                        request = line;
+                    }
+                }
+
+                let len = data.len();
+                let (head, body): (String, &[u8]) = match range {
+                    _ if request.starts_with("HEAD") => {
+                        (format!("200 OK\r\nContent-Length: {}", len), &[])
+                    }
+                    Some((start, _)) if start >= len => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        (
+                            "416 Range Not Satisfiable\r\nContent-Length: 0".to_owned(),
+                            &[],
+                        )
+                    }
+                    Some((start, end)) => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        let end = std::cmp::min(end, len - 1);
+                        let head = format!(
+                            "206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}",
+                            start,
+                            end,
+                            len,
+                            end + 1 - start
+                        );
+                        (head, &data[start..=end])
+                    }
+                    None => ("400 Bad Request\r\nContent-Length: 0".to_owned(), &[]),
+                };
+                let _ = write!(conn, "HTTP/1.1 {}\r\nConnection: close\r\n\r\n", head);
+                let _ = conn.write_all(body);
+            }
+        });
+        (url, gets)
+    }
+
+    #[test]
+    #[cfg(feature = "http-source")]
+    fn http_source_seeks_into_its_cache() {
+        use futures::io::{AsyncReadExt, AsyncSeekExt};
+        use std::io::SeekFrom;
+        use std::sync::atomic::Ordering;
+        use xdelta3::sources::HttpSource;
+
+        let data = pseudo_random(10_000, 140);
+        let (url, gets) = serve_ranges(data.clone());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut src = HttpSource::new(url, reqwest::Client::new())
+                .await
+                .expect("failed to open source")
+                .block_size(4096)
+                .max_cache_blocks(2);
+            assert_eq!(src.total_size(), Some(10_000));
+
+            let mut buf = vec![0u8; 6000];
+            src.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data[..6000]);
+            assert_eq!(gets.load(Ordering::SeqCst), 2);
+
+            // both blocks are still cached
+            src.seek(SeekFrom::Current(-5000)).await.unwrap();
+            src.read_exact(&mut buf[..4000]).await.unwrap();
+            assert_eq!(buf[..4000], data[1000..5000]);
+            assert_eq!(gets.load(Ordering::SeqCst), 2);
+
+            // the last block evicts the first one
+            let mut rest = Vec::new();
+            src.seek(SeekFrom::End(-2000)).await.unwrap();
+            src.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, data[8000..]);
+            assert_eq!(gets.load(Ordering::SeqCst), 3);
+            src.seek(SeekFrom::Start(0)).await.unwrap();
+            src.read_exact(&mut buf[..10]).await.unwrap();
+            assert_eq!(buf[..10], data[..10]);
+            assert_eq!(gets.load(Ordering::SeqCst), 4);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "s3")]
+    fn s3_source_seeks_into_its_cache() {
+        use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+        use futures::io::{AsyncReadExt, AsyncSeekExt};
+        use std::io::SeekFrom;
+        use std::sync::atomic::Ordering;
+        use xdelta3::sources::S3Source;
+
+        let data = pseudo_random(10_000, 141);
+        let (url, gets) = serve_ranges(data.clone());
+        let conf = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .endpoint_url(url)
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "static"))
+            .force_path_style(true)
+            .build();
+        let client = aws_sdk_s3::Client::from_conf(conf);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut src = S3Source::new(client, "bucket".to_owned(), "key".to_owned())
+                .await
+                .expect("failed to open source")
+                .block_size(4096)
+                .max_cache_blocks(2);
+            assert_eq!(src.object_size(), 10_000);
+
+            let mut buf = vec![0u8; 6000];
+            src.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data[..6000]);
+            assert_eq!(gets.load(Ordering::SeqCst), 2);
+
+            src.seek(SeekFrom::Start(1000)).await.unwrap();
+            src.read_exact(&mut buf[..4000]).await.unwrap();
+            assert_eq!(buf[..4000], data[1000..5000]);
+            assert_eq!(gets.load(Ordering::SeqCst), 2);
+
+            assert!(src.seek(SeekFrom::Current(-10_000)).await.is_err());
+            let mut rest = Vec::new();
+            src.seek(SeekFrom::End(-1000)).await.unwrap();
+            src.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, data[9000..]);
+            assert_eq!(gets.load(Ordering::SeqCst), 3);
+        });
+    }
+
+    /// Needs LocalStack (or another S3-compatible server) listening on
+    /// `$S3_TEST_ENDPOINT`, `http://localhost:4566` by default.
+    #[test]
+    #[ignore]
+    #[cfg(feature = "s3")]
+    fn s3_source_round_trip() {
+        use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+        use aws_sdk_s3::primitives::ByteStream;
+        use xdelta3::sources::S3Source;
+
+        let endpoint = std::env::var("S3_TEST_ENDPOINT")
+            .unwrap_or_else(|_| "http://localhost:4566".to_owned());
+        let conf = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .endpoint_url(endpoint)
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "static"))
+            .force_path_style(true)
+            .build();
+        let client = aws_sdk_s3::Client::from_conf(conf);
+
+        let source = pseudo_random(300_000, 46);
+        let mut input = source.clone();
+        input[200_000..200_100].copy_from_slice(&pseudo_random(100, 47));
+        let patch = encode(&input, &source).unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let decoded = rt.block_on(async {
+            let bucket = "xdelta3-rs-test".to_owned();
+            let key = "source.bin".to_owned();
+            let _ = client.create_bucket().bucket(&bucket).send().await;
+            client
+                .put_object()
+                .bucket(&bucket)
+                .key(&key)
+                .body(ByteStream::from(source.clone()))
+                .send()
+                .await
+                .expect("failed to upload source");
+
+            let src = S3Source::new(client, bucket, key)
+                .await
+                .expect("failed to open source")
+                .block_size(64 * 1024)
+                .max_cache_blocks(2);
+            assert_eq!(src.object_size(), source.len() as u64);
+
+            let mut out = Vec::new();
+            xdelta3::stream::decode_async(&patch[..], src, &mut out)
+                .await
+                .expect("failed to decode");
+            out
+        });
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn adaptive_winsize_round_trip() {
+        // compressible, then random, then compressible again
+        let source = pseudo_random(200_000, 48);
+        let mut input = source[..100_000].to_vec();
+        input.extend_from_slice(&pseudo_random(150_000, 49));
+        input.extend_from_slice(&source[100_000..]);
+
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new()
+            .window_size(1 << 16)
+            .adaptive_winsize(1 << 14, 1 << 17);
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+
+        let info = validate_delta(&patch).expect("valid delta rejected");
+        assert_eq!(info.total_target_size, input.len() as u64);
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn adaptive_winsize_follows_compressibility() {
+        let source = pseudo_random(256 << 10, 144);
+        let window_sizes = |input: &[u8], start: u32| {
+            let mut patch = Vec::new();
+            let cfg = Xd3Config::new()
+                .window_size(start)
+                .adaptive_winsize(16 << 10, 128 << 10);
+            process(cfg, ProcessMode::Encode, input, &source[..], &mut patch)
+                .expect("failed to encode");
+
+            // target bytes written per decoded window, leaving out empty ones
+            let mut written = vec![0];
+            let mut out = Vec::new();
+            futures::executor::block_on(decode_async_with_progress(
+                &patch[..],
+                &source[..],
+                &mut out,
+                |total| written.push(total),
+            ))
+            .expect("failed to decode");
+            assert_eq!(input, &out[..]);
+            let sizes: Vec<u64> = written.windows(2).map(|w| w[1] - w[0]).collect();
+            sizes.into_iter().filter(|&len| len > 0).collect::<Vec<_>>()
+        };
+
+        // nothing to copy: down from 64K to the 16K minimum
+        let random = pseudo_random(256 << 10, 145);
+        let mut expected = vec![64 << 10, 32 << 10];
+        expected.extend(vec![16 << 10; 10]);
+        assert_eq!(window_sizes(&random, 64 << 10), expected);
+        // all copied: up from 16K to the 128K maximum
+        let expected = vec![16 << 10, 32 << 10, 64 << 10, 128 << 10, 16 << 10];
+        assert_eq!(window_sizes(&source, 16 << 10), expected);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn buffer_output_batches_writes() {
+        struct CountingWriter {
+            data: Vec<u8>,
+            writes: usize,
+        }
+
+        impl std::io::Write for CountingWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.writes += 1;
+                self.data.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let source = pseudo_random(100_000, 53);
+        let mut input = source.clone();
+        input[50_000..50_100].copy_from_slice(&pseudo_random(100, 54));
+
+        let encode_with = |cfg: Xd3Config| {
+            let mut out = CountingWriter {
+                data: Vec::new(),
+                writes: 0,
+            };
+            process(cfg, ProcessMode::Encode, &input[..], &source[..], &mut out)
+                .expect("failed to encode");
+            out
+        };
+        let cfg = || Xd3Config::new().window_size(1 << 14);
+        let unbuffered = encode_with(cfg());
+        let buffered = encode_with(cfg().buffer_output(1 << 20));
+
+        assert!(unbuffered.writes > 1);
+        assert_eq!(buffered.writes, 1);
+        assert_eq!(buffered.data, unbuffered.data);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn dictionary_shrinks_delta() {
+        let dictionary = pseudo_random(4_000, 103);
+        let source = pseudo_random(50_000, 104);
+        let mut input = source.clone();
+        input[20_000..24_000].copy_from_slice(&dictionary);
+
+        let encode = |cfg: Xd3Config| {
+            let mut patch = Vec::new();
+            process(
+                cfg,
+                ProcessMode::Encode,
+                &input[..],
+                &source[..],
+                &mut patch,
+            )
+            .expect("failed to encode");
+            patch
+        };
+        let plain = encode(Xd3Config::new());
+        let primed = encode(Xd3Config::new().dictionary(dictionary.clone()));
+        assert!(primed.len() + 3_000 < plain.len());
+
+        let mut out = Vec::new();
+        let cfg = Xd3Config::new().dictionary(dictionary);
+        process(cfg, ProcessMode::Decode, &primed[..], &source[..], &mut out)
+            .expect("failed to decode");
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn encode_first_window_only() {
+        use xdelta3::stream::{encode_first_window_async, encode_to_writer_async};
+
+        let source = pseudo_random(100_000, 98);
+        let mut input = source.clone();
+        input[1_000..1_100].copy_from_slice(&pseudo_random(100, 99));
+        input[50_000..50_100].copy_from_slice(&pseudo_random(100, 100));
+
+        let cfg = || Xd3Config::new().window_size(1 << 14);
+        let first =
+            futures::executor::block_on(encode_first_window_async(cfg(), &input[..], &source[..]))
+                .expect("failed to encode");
+        assert_eq!(validate_delta(&first).unwrap().window_count, 1);
+        assert_eq!(&input[..1 << 14], &check_decode(&first, &source)[..]);
+
+        let mut patch = Vec::new();
+        futures::executor::block_on(encode_to_writer_async(
+            cfg().buffer_output(1 << 20),
+            &input[..],
+            &source[..],
+            &mut patch,
+        ))
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+        // the first window's bytes are a prefix of the full delta
+        assert_eq!(&patch[..first.len()], &first[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn encode_from_paced_streams() {
+        use std::collections::VecDeque;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        // yields its chunks, but is only ready on every `pace`-th poll
+        struct PacedStream {
+            chunks: VecDeque<Vec<u8>>,
+            pace: usize,
+            polls: usize,
+        }
+
+        impl PacedStream {
+            fn new(data: &[u8], chunk_size: usize, pace: usize) -> Self {
+                PacedStream {
+                    chunks: data.chunks(chunk_size).map(|c| c.to_vec()).collect(),
+                    pace,
+                    polls: 0,
+                }
+            }
+        }
+
+        impl futures::Stream for PacedStream {
+            type Item = std::io::Result<Vec<u8>>;
+
+            fn poll_next(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<Option<Self::Item>> {
+                self.polls += 1;
+                if self.polls % self.pace != 0 {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Poll::Ready(self.chunks.pop_front().map(Ok))
+            }
+        }
+
+        let source = pseudo_random(200_000, 61);
+        let mut input = source.clone();
+        input[120_000..120_100].copy_from_slice(&pseudo_random(100, 62));
+
+        let mut patch = Vec::new();
+        futures::executor::block_on(encode_async_streams(
+            PacedStream::new(&input, 1000, 2),
+            PacedStream::new(&source, 4096, 5),
+            &mut patch,
+        ))
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn slow_async_writer_back_pressure() {
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        // accepts at most 7 bytes per write, and only every other poll
+        struct SlowWriter {
+            data: Vec<u8>,
+            ready: bool,
+        }
+
+        impl futures::io::AsyncWrite for SlowWriter {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<std::io::Result<usize>> {
+                if !self.ready {
+                    self.ready = true;
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                self.ready = false;
+                let len = std::cmp::min(buf.len(), 7);
+                self.data.extend_from_slice(&buf[..len]);
+                Poll::Ready(Ok(len))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let source = pseudo_random(300_000, 57);
+        let mut input = source.clone();
+        input[100_000..100_100].copy_from_slice(&pseudo_random(100, 58));
+        input[250_000..250_100].copy_from_slice(&pseudo_random(100, 59));
+
+        let cfg = || {
+            Xd3Config::new()
+                .window_size(1 << 14)
+                .source_window_size(1 << 16)
+        };
+        let mut slow = SlowWriter {
+            data: Vec::new(),
+            ready: false,
+        };
+        futures::executor::block_on(process_async(
+            cfg(),
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut slow,
+        ))
+        .expect("failed to encode");
+
+        let mut patch = Vec::new();
+        process(
+            cfg(),
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+        assert_eq!(slow.data, patch);
+
+        let mut slow = SlowWriter {
+            data: Vec::new(),
+            ready: false,
+        };
+        futures::executor::block_on(process_async(
+            cfg(),
+            ProcessMode::Decode,
+            &patch[..],
+            &source[..],
+            &mut slow,
+        ))
+        .expect("failed to decode");
+        assert_eq!(slow.data, input);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn streaming_decode_friendly_round_trip() {
+        use xdelta3::vcdiff::decode_source_only;
+
+        // new data that repeats within the target, so the default encoder copies from it
+        let source = pseudo_random(100_000, 4);
+        let fresh = pseudo_random(10_000, 126);
+        let mut input = source.clone();
+        input.extend_from_slice(&fresh);
+        input.extend_from_slice(&fresh);
+
+        let encode_with = |cfg| {
+            let mut patch = Vec::new();
+            process(
+                cfg,
+                ProcessMode::Encode,
+                &input[..],
+                &source[..],
+                &mut patch,
+            )
+            .expect("failed to encode");
+            patch
+        };
+        let default = encode_with(Xd3Config::new());
+        assert!(target_copies(&default) > 0);
+
+        let patch = encode_with(Xd3Config::new().streaming_decode_friendly());
+        assert_eq!(target_copies(&patch), 0);
+        assert_eq!(input, check_decode(&patch, &source));
+
+        // applied straight to the output, without keeping the target window
+        let mut out = Vec::new();
+        let written = decode_source_only(&patch, std::io::Cursor::new(&source), &mut out).unwrap();
+        assert_eq!(written, input.len() as u64);
+        assert_eq!(out, input);
+
+        let err =
+            decode_source_only(&default, std::io::Cursor::new(&source), Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn window_progress_channel() {
+        let source = pseudo_random(200_000, 5);
+        let mut input = source.clone();
+        input.reverse();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cfg = Xd3Config::new().window_size(1 << 16);
+        let mut state = ProcessState::new(cfg, &source[..]).unwrap();
+        state.set_progress_channel(tx);
+
+        let mut patch = Vec::new();
+        futures::executor::block_on(async {
+            state.encode_segment(&input[..], &mut patch).await?;
+            state.finish_encode(&mut patch).await
+        })
+        .expect("failed to encode");
+
+        let windows: Vec<EncodeStats> = rx.try_iter().collect();
+        assert_eq!(windows.len() as u64, state.stats().windows_processed);
+        assert_eq!(windows.len(), (input.len() + (1 << 16) - 1) >> 16);
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn stream_errors_name_the_operation() {
+        let bad_delta = [0xD6, 0xC3, 0xC4, 0x00, 0xFF, 0x00, 0x00];
+        let err = process(
+            Xd3Config::new(),
+            ProcessMode::Decode,
+            &bad_delta[..],
+            &[][..],
+            Vec::new(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("xdelta3 decode failed"));
+    }
+
+    #[cfg(feature = "stream")]
+    mod counting_alloc {
+        use std::os::raw::{c_ulong, c_void};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        pub struct Counts {
+            pub allocs: AtomicUsize,
+            pub frees: AtomicUsize,
+        }
+
+        pub unsafe extern "C" fn alloc(
+            opaque: *mut c_void,
+            items: c_ulong,
+            size: u32,
+        ) -> *mut c_void {
+            let counts = &*(opaque as *const Counts);
+            counts.allocs.fetch_add(1, Ordering::SeqCst);
+            libc::malloc(items as usize * size as usize)
+        }
+
+        pub unsafe extern "C" fn free(opaque: *mut c_void, address: *mut c_void) {
+            let counts = &*(opaque as *const Counts);
+            counts.frees.fetch_add(1, Ordering::SeqCst);
+            libc::free(address)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn custom_allocator() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let counts = counting_alloc::Counts {
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+        };
+        let cfg = unsafe {
+            Xd3Config::new().allocator(
+                Some(counting_alloc::alloc),
+                Some(counting_alloc::free),
+                &counts as *const _ as *mut _,
+            )
+        };
+
+        let input = [1u8, 2, 3, 4, 5, 6, 7];
+        let source = [1u8, 2, 4, 4, 7, 6, 7];
+        let mut patch = Vec::new();
+        futures::executor::block_on(process_async(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        ))
+        .expect("failed to encode");
+        assert_eq!(&input[..], check_decode(&patch, &source).as_slice());
+
+        let allocs = counts.allocs.load(Ordering::SeqCst);
+        assert!(allocs > 0);
+        assert_eq!(allocs, counts.frees.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn vcdiff_header_round_trip() {
+        use std::convert::TryFrom;
+
+        let patch = encode(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7]).unwrap();
+        let header = VcdiffHeader::try_from(&patch).expect("failed to parse header");
+        assert_eq!(header.version, 0);
+        assert_eq!(&header.as_bytes()[..], &patch[..5]);
+        assert!(VcdiffHeader::try_from(&[1u8, 2, 3, 4, 5][..]).is_err());
+
+        let header = VcdiffHeader {
+            version: 0,
+            indicator: 0x05,
+            secondary_compressor: Some(2),
+            code_table: None,
+            app_header: Some(vec![b'x'; 200]),
+        };
+        let bytes = header.as_bytes();
+        assert_eq!(
+            &bytes[..8],
+            &[0xD6, 0xC3, 0xC4, 0x00, 0x05, 0x02, 0x81, 0x48]
+        );
+        assert_eq!(VcdiffHeader::parse(&bytes).unwrap(), header);
+        assert!(matches!(
+            VcdiffHeader::parse(&bytes[..bytes.len() - 1]),
+            Err(Xd3Error::MalformedDelta(_))
+        ));
+    }
+
+    #[test]
+    fn validate_delta_structure() {
+        let input = [1, 2, 3, 4, 5, 6, 7];
+        let patch = encode(&input, &[1, 2, 4, 4, 7, 6, 7]).unwrap();
+
+        let info = validate_delta(&patch).expect("valid delta rejected");
+        assert_eq!(info.window_count, 1);
+        assert_eq!(info.total_target_size, input.len() as u64);
+        // too small for xdelta3 to bother copying from the source
+        assert!(!info.references_source);
+
+        let truncated = &patch[..patch.len() - 1];
+        assert!(matches!(
+            validate_delta(truncated),
+            Err(Xd3Error::MalformedDelta(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn primary_and_secondary_compression_presets() {
+        // no source, so only matches within the target can shrink it
+        let input: Vec<u8> = pseudo_random(1000, 67).repeat(50);
+        let encode = |cfg: Xd3Config| {
+            let mut patch = Vec::new();
+            process(cfg, ProcessMode::Encode, &input[..], &[][..], &mut patch)
+                .expect("failed to encode");
+            assert_eq!(input, check_decode(&patch, &[]));
+            patch
+        };
+
+        let default = encode(Xd3Config::new());
+        let no_primary = encode(Xd3Config::new().no_primary_compression());
+        let no_secondary = encode(Xd3Config::new().no_secondary_compression());
+        assert!(default.len() < input.len() / 10);
+        assert!(no_primary.len() > input.len());
+        assert_eq!(no_secondary, default);
+        assert_eq!(
+            validate_delta(&no_secondary).unwrap().secondary_compressor,
+            None
+        );
+
+        // four letters in random order, which Huffman coding packs into about 2 bits each
+        let input: Vec<u8> = pseudo_random(20_000, 68)
+            .iter()
+            .map(|b| b"acgt"[usize::from(b & 3)])
+            .collect();
+        let encode = |cfg: Xd3Config| {
+            let mut patch = Vec::new();
+            process(cfg, ProcessMode::Encode, &input[..], &[][..], &mut patch)
+                .expect("failed to encode");
+            assert_eq!(input, check_decode(&patch, &[]));
+            patch
+        };
+        let plain = encode(Xd3Config::new());
+        let djw = encode(Xd3Config::new().secondary_compression(Some(SecondaryCompressor::Djw)));
+        let overridden = encode(
+            Xd3Config::new()
+                .secondary_compression(Some(SecondaryCompressor::Djw))
+                .no_secondary_compression(),
+        );
+        assert!(djw.len() < plain.len());
+        assert_eq!(validate_delta(&djw).unwrap().secondary_compressor, Some(1));
+        assert!(matches!(disassemble(&djw), Err(Xd3Error::Unsupported(_))));
+        // the header still names DJW, but no section is compressed with it
+        assert_eq!(overridden.len(), plain.len() + 1);
+        assert_eq!(
+            validate_delta(&overridden).unwrap().secondary_compressor,
+            Some(1)
+        );
+        assert!(disassemble(&overridden).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn chain_friendly_deltas() {
+        use xdelta3::vcdiff::VCD_SOURCE;
+
+        // every version inserts new data twice within one window, which the default
+        // matcher copies from earlier in the target the second time
+        let v1 = pseudo_random(100_000, 72);
+        let mut v2 = v1.clone();
+        let fresh = pseudo_random(1_000, 73);
+        v2[20_000..21_000].copy_from_slice(&fresh);
+        v2[22_000..23_000].copy_from_slice(&fresh);
+        let mut v3 = v2.clone();
+        let fresh = pseudo_random(1_000, 74);
+        v3[70_000..71_000].copy_from_slice(&fresh);
+        v3[72_000..73_000].copy_from_slice(&fresh);
+
+        let encode_with = |cfg, old: &[u8], new: &[u8]| {
+            let mut patch = Vec::new();
+            process(cfg, ProcessMode::Encode, new, old, &mut patch).expect("failed to encode");
+            patch
+        };
+        for (old, new) in [(&v1, &v2), (&v2, &v3)].iter() {
+            let default = encode_with(Xd3Config::new().window_size(1 << 14), old, new);
+            assert!(target_copies(&default) > 0);
+
+            let cfg = Xd3Config::new().window_size(1 << 14).chain_friendly();
+            let patch = encode_with(cfg, old, new);
+            assert_eq!(target_copies(&patch), 0);
+
+            // one window per 16 KiB of target, each built on the source
+            let info = validate_delta(&patch).expect("valid delta rejected");
+            assert_eq!(info.window_count, 7);
+            assert!(info.window_indicators.iter().all(|i| i & VCD_SOURCE != 0));
+            assert_eq!(&new[..], &check_decode(&patch, old)[..]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn process_verify_checks_round_trip() {
+        use std::io::{Cursor, Read, Seek, SeekFrom};
+        use xdelta3::stream::process_verify;
+
+        let source = pseudo_random(100_000, 110);
+        let mut input = source.clone();
+        input[70_000..70_100].copy_from_slice(&pseudo_random(100, 111));
+
+        let mut patch = Vec::new();
+        let stats = process_verify(
+            Xd3Config::new(),
+            ProcessMode::Encode,
+            Cursor::new(&input),
+            Cursor::new(&source),
+            &mut patch,
+        )
+        .expect("verification failed");
+        assert_eq!(stats.output_bytes, patch.len() as u64);
+        assert_eq!(input, check_decode(&patch, &source));
+
+        // an input that changes after being rewound, so the second read differs
+        struct Shifting {
+            data: Cursor<Vec<u8>>,
+            rewound: bool,
+        }
+
+        impl Read for Shifting {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.data.read(buf)
+            }
+        }
+
+        impl Seek for Shifting {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                if let SeekFrom::Start(_) = pos {
+                    if !self.rewound {
+                        self.rewound = true;
+                        self.data.get_mut()[12_345] ^= 0xff;
+                    }
+                }
+                self.data.seek(pos)
+            }
+        }
+
+        let shifting = Shifting {
+            data: Cursor::new(input.clone()),
+            rewound: false,
+        };
+        let mut patch = Vec::new();
+        let err = process_verify(
+            Xd3Config::new(),
+            ProcessMode::Encode,
+            shifting,
+            Cursor::new(&source),
+            &mut patch,
+        )
+        .expect_err("changed input verified");
+        assert!(matches!(
+            err.xd3_error(),
+            Some(Xd3Error::VerificationFailed { at_byte: 12_345 })
+        ));
+        assert!(patch.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn deadline_aborts_processing() {
+        use std::time::{Duration, Instant};
+        use xdelta3::error::Xd3IoErrorExt;
+
+        let source = pseudo_random(100_000, 108);
+        let mut input = source.clone();
+        input[5_000..5_100].copy_from_slice(&pseudo_random(100, 109));
+
+        let mut patch = Vec::new();
+        let cfg = Xd3Config::new().deadline(Instant::now() - Duration::from_secs(1));
+        let err = process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect_err("encoded past the deadline");
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(matches!(err.xd3_error(), Some(Xd3Error::DeadlineExceeded)));
+        assert!(err.is_retryable());
+        assert!(patch.is_empty());
+
+        let cfg = Xd3Config::new().deadline(Instant::now() + Duration::from_secs(600));
+        process(
+            cfg,
+            ProcessMode::Encode,
+            &input[..],
+            &source[..],
+            &mut patch,
+        )
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn firmware_update_preset() {
+        use xdelta3::vcdiff::VCD_ADLER32;
+
+        let old = pseudo_random(64 << 10, 105);
+        let mut new = old.clone();
+        new[1_000..1_016].copy_from_slice(&pseudo_random(16, 106));
+        new[40_000..40_016].copy_from_slice(&pseudo_random(16, 107));
+
+        let encode = |cfg| {
+            let mut patch = Vec::new();
+            process(cfg, ProcessMode::Encode, &new[..], &old[..], &mut patch)
+                .expect("failed to encode");
+            patch
+        };
+        let patch = encode(Xd3Config::for_firmware_update());
+        assert_eq!(encode(Xd3Config::for_firmware_update()), patch);
+        assert!(patch.len() < new.len() / 10);
+
+        // 4 KiB windows with checksums, where the defaults give one unchecked window
+        let info = validate_delta(&patch).expect("valid delta rejected");
+        assert!(info.window_count >= (new.len() >> 12) as u64);
+        assert!(info.window_indicators.iter().all(|i| i & VCD_ADLER32 != 0));
+        assert_eq!(info.secondary_compressor, None);
+        assert_eq!(new, check_decode(&patch, &old));
+        let default = validate_delta(&encode(Xd3Config::new())).unwrap();
+        assert_eq!(default.window_count, 1);
+        assert!(default
+            .window_indicators
+            .iter()
+            .all(|i| i & VCD_ADLER32 == 0));
+
+        // a secondary compressor chosen on top of the preset stays unused
+        let patch = encode(
+            Xd3Config::for_firmware_update().secondary_compression(Some(SecondaryCompressor::Djw)),
+        );
+        assert!(disassemble(&patch).is_ok());
+
+        #[cfg(feature = "toml")]
+        {
+            let toml = Xd3Config::for_firmware_update().to_toml_string();
+            assert!(toml.contains("window_size = \"4K\""), "{}", toml);
+            assert!(toml.contains("level = 9"), "{}", toml);
+            let toml = Xd3Config::new().to_toml_string();
+            assert!(toml.contains("window_size = \"8M\""), "{}", toml);
+            assert!(toml.contains("level = 0"), "{}", toml);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn adler32_sets_window_indicator() {
+        use xdelta3::vcdiff::{VCD_ADLER32, VCD_SOURCE};
+
+        let source = pseudo_random(100_000, 65);
+        let mut input = source.clone();
+        input[10_000..10_100].copy_from_slice(&pseudo_random(100, 66));
+
+        for &adler32 in &[false, true] {
+            let cfg = Xd3Config::new().window_size(1 << 15).adler32(adler32);
+            let mut patch = Vec::new();
+            process(
+                cfg,
+                ProcessMode::Encode,
+                &input[..],
+                &source[..],
+                &mut patch,
+            )
+            .expect("failed to encode");
+
+            let info = validate_delta(&patch).expect("valid delta rejected");
+            assert_eq!(info.window_indicators.len() as u64, info.window_count);
+            for &indicator in &info.window_indicators {
+                assert_eq!(indicator & VCD_ADLER32 != 0, adler32);
+                assert_ne!(indicator & VCD_SOURCE, 0);
+            }
+            assert_eq!(input, check_decode(&patch, &source));
+        }
+    }
+
+    #[test]
+    fn malformed_deltas_name_window() {
+        let malformed = |delta: &[u8]| match decode(delta, b"") {
+            Err(Xd3Error::MalformedDelta(detail)) => detail,
+            res => panic!("expected MalformedDelta, got {:?}", res),
+        };
+
+        // COPY 4 from target address 2 before anything was produced
+        #[rustfmt::skip]
+        let forward_copy = [
+            0xD6, 0xC3, 0xC4, 0x00, 0x00,
+            0x00, 7, 4, 0, 0, 1, 1,
+            20, // COPY 4 mode 0
+            2,
+        ];
+        assert!(malformed(&forward_copy).starts_with("window 0: "));
+
+        // ADD 3 in a window of 2 bytes
+        #[rustfmt::skip]
+        let overrun = [
+            0xD6, 0xC3, 0xC4, 0x00, 0x00,
+            0x00, 9, 2, 0, 3, 1, 0,
+            b'a', b'b', b'c',
+            4, // ADD 3
+        ];
+        assert!(malformed(&overrun).starts_with("window 0: "));
+
+        // second window's VCD_TARGET segment covers 4 bytes when only 3 exist
+        #[rustfmt::skip]
+        let target_segment = [
+            0xD6, 0xC3, 0xC4, 0x00, 0x00,
+            0x00, 9, 3, 0, 3, 1, 0,
+            b'a', b'b', b'c',
+            4, // ADD 3
+            0x02, 4, 0, // VCD_TARGET, segment of 4 bytes at 0
+            7, 4, 0, 0, 1, 1,
+            20, // COPY 4 mode 0
+            0,
+        ];
+        assert!(malformed(&target_segment).starts_with("window 1: "));
+        assert!(validate_delta(&target_segment).is_err());
+
+        #[cfg(feature = "stream")]
+        {
+            let mut out = Vec::new();
+            let err = process(
+                Xd3Config::new(),
+                ProcessMode::Decode,
+                &forward_copy[..],
+                &b""[..],
+                &mut out,
+            )
+            .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(err.to_string().starts_with("xdelta3 decode failed"));
+            match err.xd3_error() {
+                Some(Xd3Error::MalformedDelta(detail)) => assert!(detail.starts_with("window 0: ")),
+                other => panic!("expected MalformedDelta, got {:?}", other),
+            }
+
+            // a well-formed delta applied to the wrong source fails its checksum instead
+            let source: Vec<u8> = (0..8192u32).map(|i| (i * 7919 % 251) as u8).collect();
+            let mut input = source.clone();
+            input[100] ^= 0xFF;
+            let mut patch = Vec::new();
+            process(
+                Xd3Config::new().adler32(true),
+                ProcessMode::Encode,
+                &input[..],
+                &source[..],
+                &mut patch,
+            )
+            .unwrap();
+            let mut wrong_source = source.clone();
+            wrong_source[4000] ^= 0xFF;
+            let err = process(
+                Xd3Config::new(),
+                ProcessMode::Decode,
+                &patch[..],
+                &wrong_source[..],
+                Vec::new(),
+            )
+            .unwrap_err();
+            match err.xd3_error() {
+                Some(Xd3Error::Stream { operation, .. }) => assert_eq!(*operation, "decode"),
+                other => panic!("expected a stream error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn retryable_error_classification() {
+        use std::io::{Error, ErrorKind};
+        use xdelta3::error::Xd3IoErrorExt;
+
+        assert!(Error::from(ErrorKind::TimedOut).is_retryable());
+        assert!(Error::from(ErrorKind::Interrupted).is_retryable());
+        assert!(Error::from(ErrorKind::UnexpectedEof).is_retryable());
+        assert!(!Error::new(ErrorKind::UnexpectedEof, Xd3Error::NotAVcdiff).is_retryable());
+        let expired = Error::new(ErrorKind::Other, Xd3Error::DeadlineExceeded);
+        assert!(expired.with_xd3_context("encoding").is_retryable());
+        assert!(!Error::from(ErrorKind::PermissionDenied).is_retryable());
+        assert!(!Error::new(ErrorKind::InvalidData, Xd3Error::NotAVcdiff).is_retryable());
+        assert!(!Xd3Error::MalformedDelta("bad".to_owned()).is_retryable());
+
+        // context keeps the kind and the error underneath
+        let err = Error::new(ErrorKind::InvalidData, Xd3Error::NotAVcdiff)
+            .with_xd3_context("reading input")
+            .with_xd3_context("xdelta3 decode failed");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "xdelta3 decode failed: reading input: input is not a VCDIFF delta"
+        );
+        assert!(matches!(err.xd3_error(), Some(Xd3Error::NotAVcdiff)));
+        let source = std::error::Error::source(err.get_ref().unwrap()).unwrap();
+        assert_eq!(
+            source.to_string(),
+            "reading input: input is not a VCDIFF delta"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn retryable_decode_errors() {
+        use xdelta3::error::Xd3IoErrorExt;
+
+        let source = pseudo_random(100_000, 68);
+        let mut input = source.clone();
+        input[30_000..30_100].copy_from_slice(&pseudo_random(100, 69));
+        let patch = encode(&input, &source).unwrap();
+
+        // the delta itself ends early, which reading it again won't fix
+        let mut out = Vec::new();
+        let cfg = Xd3Config::new();
+        let truncated = &patch[..patch.len() - 1];
+        let err = process(cfg, ProcessMode::Decode, truncated, &source[..], &mut out)
+            .expect_err("truncated delta decoded");
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(matches!(err.xd3_error(), Some(Xd3Error::MalformedDelta(_))));
+        assert!(!err.is_retryable(), "{:?}", err);
+
+        // the data is bad
+        let mut corrupt = patch.clone();
+        corrupt[4] = 0xff;
+        let mut out = Vec::new();
+        let err = process(
+            Xd3Config::new(),
+            ProcessMode::Decode,
+            &corrupt[..],
+            &source[..],
+            &mut out,
+        )
+        .expect_err("corrupt delta decoded");
+        assert!(!err.is_retryable(), "{:?}", err);
+    }
+
+    #[test]
+    fn disassemble_instructions() {
+        use xdelta3::vcdiff::CopyFrom;
+
+        // "cdefXYZZZZcdef" from source "abcdefgh": COPY 4 @2 from the source, ADD "XY",
+        // RUN 4 "Z", COPY 4 from the start of the target
+        #[rustfmt::skip]
+        let delta = [
+            0xD6, 0xC3, 0xC4, 0x00, 0x00,
+            0x01, 8, 0, // VCD_SOURCE, segment of 8 bytes at 0
+            15, 14, 0, 3, 5, 2,
+            b'X', b'Y', b'Z', // data
+            20, 3, 0, 4, 20, // COPY 4 mode 0, ADD 2, RUN (size 4), COPY 4 mode 0
+            2, 8, // addresses
+        ];
+        let source = b"abcdefgh";
+        assert_eq!(&check_decode(&delta, source)[..], b"cdefXYZZZZcdef");
+
+        let expected = vec![
+            Instruction::Copy {
+                target_offset: 0,
+                len: 4,
+                from: CopyFrom::Source(2),
+            },
+            Instruction::Add {
+                target_offset: 4,
+                data: b"XY".to_vec(),
+            },
+            Instruction::Run {
+                target_offset: 6,
+                len: 4,
+                byte: b'Z',
+            },
+            Instruction::Copy {
+                target_offset: 10,
+                len: 4,
+                from: CopyFrom::Target(0),
+            },
+        ];
+        assert_eq!(disassemble(&delta).unwrap(), expected);
+
+        // replaying a real delta's instructions rebuilds its target
+        let source = pseudo_random(50_000, 101);
+        let mut input = source.clone();
+        input[10_000..10_100].copy_from_slice(&pseudo_random(100, 102));
+        let patch = encode(&input, &source).unwrap();
+        let mut target = Vec::new();
+        for inst in disassemble(&patch).unwrap() {
+            match inst {
+                Instruction::Add { data, .. } => target.extend_from_slice(&data),
+                Instruction::Run { len, byte, .. } => {
+                    target.extend(std::iter::repeat(byte).take(len as usize))
+                }
+                Instruction::Copy { len, from, .. } => {
+                    for i in 0..len as usize {
+                        let b = match from {
+                            CopyFrom::Source(at) => source[at as usize + i],
+                            CopyFrom::Target(at) => target[at as usize + i],
+                        };
+                        target.push(b);
+                    }
+                }
+            }
+        }
+        assert_eq!(target, input);
+    }
+
     fn read_file(filename: &str) -> Vec<u8> {
         let mut file = File::open(filename).expect("Failed to open file");
         let mut data = Vec::new();
@@ -0,0 +1,46 @@
+#[cfg(test)]
+mod tests {
+    use xdelta3::prelude::*;
+
+    #[test]
+    fn prelude_covers_basic_use() {
+        let source = b"the quick brown fox jumps over the lazy dog".repeat(10);
+        let mut input = source.clone();
+        input[100..105].copy_from_slice(b"CHEAP");
+
+        let patch = encode(&input, &source).unwrap();
+        assert_eq!(decode(&patch, &source).unwrap(), input);
+        assert!(matches!(decode(&input, &source), Err(Xd3Error::NotAVcdiff)));
+
+        #[cfg(feature = "stream")]
+        {
+            let mut patch = Vec::new();
+            process(
+                Xd3Config::new(),
+                ProcessMode::Encode,
+                &input[..],
+                &source[..],
+                &mut patch,
+            )
+            .unwrap();
+
+            let mut out = Vec::new();
+            futures::executor::block_on(decode_async(&patch[..], &source[..], &mut out)).unwrap();
+            assert_eq!(out, input);
+
+            let mut patch = Vec::new();
+            futures::executor::block_on(encode_async(&input[..], &source[..], &mut patch)).unwrap();
+            let mut out = Vec::new();
+            futures::executor::block_on(process_async(
+                Xd3Config::new(),
+                ProcessMode::Decode,
+                &patch[..],
+                &source[..],
+                &mut out,
+            ))
+            .unwrap();
+            assert_eq!(out, input);
+            assert_eq!(EncodeStats::default().windows_processed, 0);
+        }
+    }
+}